@@ -0,0 +1,894 @@
+use crate::config::{self, Environment, PeerFile, ServerConfig};
+use crate::crypto;
+use crate::error::{Error, Result};
+use crate::migrate::{self, Kind, SERVER_VERSION};
+use crate::overrides::Overrides;
+use crate::types::{self, Endpoint, ListenAddr, PublicKey};
+use ipnet::IpNet;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use toml_edit::{ImDocument, Item};
+
+/// 诊断级别
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// 配置检查发现的问题
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// 所在文件（主配置文件或 `peers_dir` 中的文件）
+    pub path: PathBuf,
+    /// 行号（从 1 开始）
+    pub line: usize,
+    /// 列号（从 1 开始，按字符计）
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(
+            f,
+            "{}:{}:{}: {}: {}",
+            self.path.display(),
+            self.line,
+            self.column,
+            severity,
+            self.message
+        )
+    }
+}
+
+/// 是否包含错误级别的诊断
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics.iter().any(|d| d.severity == Severity::Error)
+}
+
+/// 静态检查服务器配置及 `peers_dir` 中的文件：语法、密钥、地址范围和对等体之间的冲突
+///
+/// 检查的是应用 `overrides` 之后的配置，与服务器实际加载的一致。
+/// 主配置文件无法读取时返回错误，其余问题都作为诊断返回。
+pub fn validate(path: &Path, overrides: &Overrides) -> Result<Vec<Diagnostic>> {
+    let mut checker = Checker::default();
+    if let Some(config) = checker.load(path, overrides)? {
+        checker.check_config(&config);
+    }
+    Ok(checker.diagnostics)
+}
+
+/// 在静态检查之外检查运行环境：端点能否解析、监听端口是否已被占用
+pub async fn check(path: &Path, overrides: &Overrides) -> Result<Vec<Diagnostic>> {
+    let mut checker = Checker::default();
+    if let Some(config) = checker.load(path, overrides)? {
+        checker.check_config(&config);
+        checker.check_environment(&config).await;
+    }
+    Ok(checker.diagnostics)
+}
+
+/// 诊断指向的配置项，找不到时依次退回到所在的表和文件开头
+#[derive(Debug, Clone, Copy)]
+enum Location {
+    /// 主配置文件的顶层表或键，如 `[interface]`
+    Table(&'static str),
+    /// 主配置文件顶层表中的键，如 `interface.address`
+    Field(&'static str, &'static str),
+    /// 合并后的第 i 个对等体
+    Peer(usize),
+    /// 合并后第 i 个对等体中的键
+    PeerField(usize, &'static str),
+}
+
+/// 参与检查的文件，第一个是主配置文件
+struct Source {
+    path: PathBuf,
+    document: ImDocument<String>,
+}
+
+#[derive(Default)]
+struct Checker {
+    sources: Vec<Source>,
+    /// 合并后每个对等体所在的文件和在该文件中的序号
+    peers: Vec<(usize, usize)>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Checker {
+    /// 解析主配置和 `peers_dir` 中的文件
+    ///
+    /// 语法错误、无效的值或类型错误记录为指向出错位置的诊断，此时返回 None。
+    /// 旧版本的配置先按加载时的方式迁移再检查，迁移不改变行号。
+    /// 覆盖的值没有对应的位置，应用覆盖后的错误指向主配置文件开头。
+    fn load(&mut self, path: &Path, overrides: &Overrides) -> Result<Option<ServerConfig>> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| Error::ConfigError(format!("Failed to read config file: {}", e)))?;
+        let (content, from) = match migrate::upgrade(&content, Kind::Server, false) {
+            Ok(Some(upgraded)) => (upgraded.content, Ok(upgraded.from)),
+            Ok(None) => (content, Ok(SERVER_VERSION)),
+            Err(e) => (content, Err(e)),
+        };
+        // 语法错误由 add_source 报告
+        if !self.add_source(path.to_path_buf(), content) {
+            return Ok(None);
+        }
+        let from = match from {
+            Ok(from) => from,
+            Err(e) => {
+                self.error(Location::Table("version"), e.detail());
+                return Ok(None);
+            }
+        };
+        if from < SERVER_VERSION {
+            self.warning(
+                Location::Table("version"),
+                format!(
+                    "Configuration version {} is outdated; it is upgraded to {} on load, \
+                     run `migrate` to update the file",
+                    from, SERVER_VERSION
+                ),
+            );
+        }
+
+        let peers_dir = self.sources[0]
+            .document
+            .get("peers_dir")
+            .and_then(Item::as_str)
+            .map(|dir| path.parent().unwrap_or(Path::new("")).join(dir));
+        if let Some(dir) = peers_dir {
+            match config::peer_files(&dir) {
+                Ok(files) => {
+                    for file in files {
+                        match fs::read_to_string(&file) {
+                            Ok(content) => {
+                                let content = match migrate::upgrade_peers(&content, from) {
+                                    Ok(Some(upgraded)) => upgraded,
+                                    _ => content,
+                                };
+                                self.add_source(file, content);
+                            }
+                            Err(e) => self.error(
+                                Location::Table("peers_dir"),
+                                format!("Failed to read {:?}: {}", file, e),
+                            ),
+                        }
+                    }
+                }
+                Err(e) => self.error(Location::Table("peers_dir"), e.detail()),
+            }
+        }
+        // 反序列化只能报告第一个无效值，先逐个检查以便一次列出全部
+        self.check_values();
+        if has_errors(&self.diagnostics) {
+            return Ok(None);
+        }
+
+        let raw = self.sources[0].document.raw();
+        let parsed = if overrides.is_empty() {
+            toml::from_str::<ServerConfig>(raw).map_err(|e| (e.span(), e.message().to_string()))
+        } else {
+            ServerConfig::parse(raw, overrides).map_err(|e| (None, e.detail()))
+        };
+        let mut config = match parsed {
+            Ok(config) => config,
+            Err((span, message)) => {
+                self.push(0, span, Severity::Error, &message);
+                return Ok(None);
+            }
+        };
+        for index in 1..self.sources.len() {
+            let source = &self.sources[index];
+            match toml::from_str::<PeerFile>(source.document.raw()) {
+                Ok(file) => {
+                    let path = source.path.clone();
+                    config.peers.extend(file.peers.into_iter().map(|mut peer| {
+                        peer.source = Some(path.clone());
+                        peer
+                    }));
+                }
+                Err(e) => self.push(index, e.span(), Severity::Error, e.message()),
+            }
+        }
+        Ok((!has_errors(&self.diagnostics)).then_some(config))
+    }
+
+    /// 解析文件并记录其中的对等体，语法错误时记录诊断并返回 false
+    fn add_source(&mut self, path: PathBuf, content: String) -> bool {
+        let document = match ImDocument::parse(content.clone()) {
+            Ok(document) => document,
+            Err(e) => {
+                let offset = e.span().map_or(0, |s| s.start);
+                let diagnostic =
+                    diagnostic_at(&path, &content, offset, Severity::Error, e.message());
+                self.diagnostics.push(diagnostic);
+                return false;
+            }
+        };
+        let index = self.sources.len();
+        let count = document.get("peers").map_or(0, |peers| {
+            peers
+                .as_array_of_tables()
+                .map(|tables| tables.len())
+                .or_else(|| peers.as_array().map(|array| array.len()))
+                .unwrap_or(0)
+        });
+        self.sources.push(Source { path, document });
+        self.peers.extend((0..count).map(|local| (index, local)));
+        true
+    }
+
+    fn root(&self, source: usize) -> &Item {
+        self.sources[source].document.as_item()
+    }
+
+    /// 诊断位置所在的文件、表和键
+    fn lookup(&self, location: Location) -> (usize, Option<&Item>, Option<&'static str>) {
+        let peer = |index: usize| {
+            let (source, local) = self.peers.get(index).copied().unwrap_or_default();
+            let table = self.root(source).get("peers").and_then(|p| p.get(local));
+            (source, table)
+        };
+        match location {
+            Location::Table(table) => (0, self.root(0).get(table), None),
+            Location::Field(table, field) => (0, self.root(0).get(table), Some(field)),
+            Location::Peer(index) => {
+                let (source, table) = peer(index);
+                (source, table, None)
+            }
+            Location::PeerField(index, field) => {
+                let (source, table) = peer(index);
+                (source, table, Some(field))
+            }
+        }
+    }
+
+    fn item(&self, location: Location) -> Option<&Item> {
+        let (_, table, field) = self.lookup(location);
+        table?.get(field?)
+    }
+
+    fn span(&self, location: Location) -> (usize, Option<Range<usize>>) {
+        let (source, table, field) = self.lookup(location);
+        let span = table.and_then(|table| {
+            field
+                .and_then(|field| table.get(field))
+                .and_then(Item::span)
+                .or_else(|| table.span())
+        });
+        (source, span)
+    }
+
+    fn push(
+        &mut self,
+        source: usize,
+        span: Option<Range<usize>>,
+        severity: Severity,
+        message: &str,
+    ) {
+        let source = &self.sources[source];
+        let diagnostic = diagnostic_at(
+            &source.path,
+            source.document.raw(),
+            span.map_or(0, |s| s.start),
+            severity,
+            message,
+        );
+        self.diagnostics.push(diagnostic);
+    }
+
+    fn report(&mut self, severity: Severity, location: Location, message: impl Into<String>) {
+        let (source, span) = self.span(location);
+        self.push(source, span, severity, &message.into());
+    }
+
+    /// 报告逗号分隔列表中的某一项，能定位时指向该项本身
+    fn report_entry(
+        &mut self,
+        severity: Severity,
+        location: Location,
+        entry: &str,
+        message: impl Into<String>,
+    ) {
+        let (source, Some(span)) = self.span(location) else {
+            return self.report(severity, location, message);
+        };
+        // 只在字符串没有转义时才能把值中的偏移换算为文件中的偏移
+        let raw = &self.sources[source].document.raw()[span.clone()];
+        let offset = raw
+            .get(1..raw.len().saturating_sub(1))
+            .filter(|inner| !inner.contains('\\'))
+            .and_then(|inner| inner.find(entry))
+            .map_or(span.start, |position| span.start + 1 + position);
+        self.push(source, Some(offset..offset), severity, &message.into());
+    }
+
+    /// 诊断中对等体的称呼，`peers_dir` 中未命名的对等体附带文件名
+    fn peer_name(&self, config: &ServerConfig, index: usize) -> String {
+        if let Some(name) = &config.peers[index].name {
+            return format!("peer {}", name);
+        }
+        match self.peers.get(index) {
+            Some(&(source, local)) if source > 0 => {
+                let path = &self.sources[source].path;
+                let file = path.file_name().unwrap_or(path.as_os_str());
+                format!("peers[{}] in {}", local, file.to_string_lossy())
+            }
+            _ => format!("peers[{}]", index),
+        }
+    }
+
+    fn error(&mut self, location: Location, message: impl Into<String>) {
+        self.report(Severity::Error, location, message);
+    }
+
+    fn warning(&mut self, location: Location, message: impl Into<String>) {
+        self.report(Severity::Warning, location, message);
+    }
+
+    /// 逐个检查字段的值能否解析，错误信息与加载时一致
+    fn check_values(&mut self) {
+        for field in ["private_key", "next_private_key", "previous_private_key"] {
+            self.check_value(Location::Field("interface", field), |v| {
+                crypto::decode_private_key(v).map_err(|e| format!("{}: {}", field, e.detail()))
+            });
+        }
+        self.check_value(Location::Field("interface", "address"), |v| {
+            v.parse::<IpNet>()
+                .map_err(|e| format!("Invalid interface address {:?}: {}", v, e))
+        });
+        self.check_entries(Location::Field("interface", "listen"), |v| {
+            v.parse::<ListenAddr>().map_err(|e| e.to_string())
+        });
+        self.check_entries(Location::Field("nat", "masquerade"), parse_network);
+        for field in ["pools", "reserved"] {
+            self.check_entries(Location::Field("ipam", field), parse_network);
+        }
+        for table in ["stream", "pq"] {
+            self.check_value(Location::Field(table, "listen"), |v| {
+                v.parse::<SocketAddr>()
+                    .map_err(|e| format!("Invalid {}.listen {:?}: {}", table, v, e))
+            });
+        }
+
+        for index in 0..self.peers.len() {
+            self.check_value(Location::PeerField(index, "public_key"), |v| {
+                crypto::decode_public_key(v).map_err(|e| e.detail())
+            });
+            self.check_value(Location::PeerField(index, "psk"), |v| {
+                crypto::decode_psk(v).map_err(|e| e.detail())
+            });
+            for field in ["endpoint", "pq_endpoint"] {
+                self.check_value(Location::PeerField(index, field), |v| {
+                    v.parse::<Endpoint>().map_err(|e| e.to_string())
+                });
+            }
+            self.check_entries(Location::PeerField(index, "allowed_ips"), parse_network);
+        }
+    }
+
+    /// 检查字符串字段，字段不存在或不是字符串时交给反序列化报告
+    fn check_value<T>(
+        &mut self,
+        location: Location,
+        parse: impl Fn(&str) -> std::result::Result<T, String>,
+    ) {
+        let message = self
+            .item(location)
+            .and_then(Item::as_str)
+            .and_then(|value| parse(value).err());
+        if let Some(message) = message {
+            self.error(location, message);
+        }
+    }
+
+    /// 检查字符串数组或逗号分隔列表中的每一项
+    fn check_entries<T>(
+        &mut self,
+        location: Location,
+        parse: impl Fn(&str) -> std::result::Result<T, String>,
+    ) {
+        let entries: Vec<String> = match self.item(location) {
+            Some(item) => match item.as_array() {
+                Some(array) => array
+                    .iter()
+                    .filter_map(|v| v.as_str())
+                    .map(str::to_string)
+                    .collect(),
+                None => item
+                    .as_str()
+                    .into_iter()
+                    .flat_map(|v| v.split(','))
+                    .map(|entry| entry.trim().to_string())
+                    .filter(|entry| !entry.is_empty())
+                    .collect(),
+            },
+            None => return,
+        };
+        for entry in entries {
+            if let Err(message) = parse(&entry) {
+                self.report_entry(Severity::Error, location, &entry, message);
+            }
+        }
+    }
+
+    fn check_config(&mut self, config: &ServerConfig) {
+        let mut config = config.clone();
+        if let Err(e) = config.interface.load_secrets(&Environment::Process) {
+            self.error(Location::Table("interface"), e.detail());
+        }
+        for (index, peer) in config.peers.iter_mut().enumerate() {
+            if let Err(e) = peer.load_secrets(&Environment::Process) {
+                self.error(Location::Peer(index), e.detail());
+            }
+        }
+
+        let subnet = config.interface.address;
+        let pools: Vec<IpNet> = std::iter::once(subnet)
+            .chain(
+                config
+                    .ipam
+                    .iter()
+                    .flat_map(|ipam| ipam.pools.iter().copied()),
+            )
+            .collect();
+        let mut public_keys: HashMap<PublicKey, usize> = HashMap::new();
+        let mut routes: Vec<(usize, IpNet)> = Vec::new();
+        for (index, peer) in config.peers.iter().enumerate() {
+            if let Some(first) = public_keys.get(&peer.public_key) {
+                self.error(
+                    Location::PeerField(index, "public_key"),
+                    format!(
+                        "Duplicate public key, also used by {}",
+                        self.peer_name(&config, *first)
+                    ),
+                );
+            } else {
+                public_keys.insert(peer.public_key, index);
+            }
+
+            let location = Location::PeerField(index, "allowed_ips");
+            for &network in &peer.allowed_ips {
+                let entry = network.to_string();
+                let host = network.prefix_len() == network.max_prefix_len();
+                if host && network.addr() == subnet.addr() {
+                    let message = format!(
+                        "{} overlaps the interface address {}",
+                        network,
+                        subnet.addr()
+                    );
+                    self.report_entry(Severity::Error, location, &entry, message);
+                } else if host && !pools.iter().any(|pool| pool.contains(&network)) {
+                    let message = format!(
+                        "Peer address {} is outside the interface subnet {}",
+                        network,
+                        subnet.trunc()
+                    );
+                    self.report_entry(Severity::Warning, location, &entry, message);
+                }
+                self.check_overlap(&config, subnet, &routes, index, network, &entry);
+                routes.push((index, network));
+            }
+        }
+    }
+
+    /// 检查与之前对等体的地址范围冲突
+    ///
+    /// 相同的范围只会路由到最后一个对等体，属于错误；接口子网内的重叠同样是错误，
+    /// 子网外的嵌套范围按最长前缀匹配，只给出警告。
+    fn check_overlap(
+        &mut self,
+        config: &ServerConfig,
+        subnet: IpNet,
+        routes: &[(usize, IpNet)],
+        index: usize,
+        network: IpNet,
+        entry: &str,
+    ) {
+        let name = self.peer_name(config, index);
+        let location = Location::PeerField(index, "allowed_ips");
+        let in_subnet = |net: &IpNet| subnet.contains(net) && !net.contains(&subnet.addr());
+        for (other, existing) in routes.iter().filter(|(other, _)| *other != index) {
+            if !(existing.contains(&network) || network.contains(existing)) {
+                continue;
+            }
+            let other = self.peer_name(config, *other);
+            if *existing == network || (in_subnet(existing) && in_subnet(&network)) {
+                let message = format!("{} of {} overlaps {} of {}", network, name, existing, other);
+                self.report_entry(Severity::Error, location, entry, message);
+            } else {
+                let message = format!(
+                    "{} of {} overlaps {} of {}; the more specific route wins",
+                    network, name, existing, other
+                );
+                self.report_entry(Severity::Warning, location, entry, message);
+            }
+            break;
+        }
+    }
+
+    async fn check_environment(&mut self, config: &ServerConfig) {
+        for (index, peer) in config.peers.iter().enumerate() {
+            let endpoints = [
+                ("endpoint", &peer.endpoint),
+                ("pq_endpoint", &peer.pq_endpoint),
+            ];
+            for (field, endpoint) in endpoints {
+                if let Some(endpoint @ Endpoint::Host(..)) = endpoint {
+                    if let Err(e) = endpoint.resolve().await {
+                        self.error(Location::PeerField(index, field), e.detail());
+                    }
+                }
+            }
+        }
+
+        let location = if config.interface.listen.is_empty() {
+            Location::Field("interface", "listen_port")
+        } else {
+            Location::Field("interface", "listen")
+        };
+        for addr in config.interface.listen_addrs() {
+            self.check_bind(location, addr, false);
+        }
+        if let Some(stream) = &config.stream {
+            self.check_bind(Location::Field("stream", "listen"), stream.listen, true);
+        }
+        if let Some(pq) = &config.pq {
+            self.check_bind(Location::Field("pq", "listen"), pq.listen, false);
+        }
+    }
+
+    /// 尝试绑定监听地址，确认端口未被占用
+    ///
+    /// 端口被占用只给出警告：对运行中的服务器做重载前检查时，端口正由该服务器自身占用。
+    fn check_bind(&mut self, location: Location, addr: SocketAddr, tcp: bool) {
+        let result = if tcp {
+            TcpListener::bind(addr).map(drop)
+        } else {
+            UdpSocket::bind(addr).map(drop)
+        };
+        let protocol = if tcp { "TCP" } else { "UDP" };
+        match result {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::AddrInUse => self.warning(
+                location,
+                format!(
+                    "{} port {} is already in use (fine if this server is already running)",
+                    protocol, addr
+                ),
+            ),
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => self.warning(
+                location,
+                format!(
+                    "Not allowed to bind {} {}; the server needs to run as root",
+                    protocol, addr
+                ),
+            ),
+            Err(e) => self.error(
+                location,
+                format!("Cannot bind {} {}: {}", protocol, addr, e),
+            ),
+        }
+    }
+}
+
+/// 按字节偏移计算行列号
+fn diagnostic_at(
+    path: &Path,
+    content: &str,
+    offset: usize,
+    severity: Severity,
+    message: &str,
+) -> Diagnostic {
+    let offset = offset.min(content.len());
+    let before = &content[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    Diagnostic {
+        severity,
+        path: path.to_path_buf(),
+        line: before.matches('\n').count() + 1,
+        column: before[line_start..].chars().count() + 1,
+        message: message.trim().to_string(),
+    }
+}
+
+/// 解析列表中的单个 CIDR
+fn parse_network(entry: &str) -> std::result::Result<Vec<IpNet>, String> {
+    types::parse_networks(entry).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "rusty-tunnel-check-{}-{}",
+            name,
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// 写入主配置文件后检查
+    fn validate_content(dir: &Path, content: &str) -> Vec<Diagnostic> {
+        let path = dir.join("server.toml");
+        fs::write(&path, content).unwrap();
+        validate(&path, &Overrides::default()).unwrap()
+    }
+
+    fn find<'a>(diagnostics: &'a [Diagnostic], text: &str) -> &'a Diagnostic {
+        diagnostics
+            .iter()
+            .find(|d| d.message.contains(text))
+            .unwrap_or_else(|| panic!("no diagnostic containing {:?} in {:#?}", text, diagnostics))
+    }
+
+    #[test]
+    fn test_validate_reports_positions() {
+        let dir = temp_dir("positions");
+        let (private_key, public_key) = crypto::generate_keypair().unwrap();
+        let content = format!(
+            r#"version = 2
+
+[interface]
+name = "wg0"
+private_key = "{}"
+address = "10.8.0.1/24"
+listen_port = 51820
+
+[[peers]]
+name = "alice"
+public_key = "{}"
+allowed_ips = "10.8.0.2/32, 10.8.0.300/32"
+psk = "c2hvcnQ="
+
+[[peers]]
+name = "bob"
+public_key = "{}"
+allowed_ips = "192.168.7.0/24, 10.8.0.2/32"
+endpoint = "vpn.example.com"
+
+[[peers]]
+public_key = "not base64!"
+allowed_ips = "10.9.0.5/32, 10.8.0.1/32"
+"#,
+            private_key.to_base64().expose(),
+            public_key,
+            public_key
+        );
+
+        // 无效的值全部报告，此时不做语义检查
+        let diagnostics = validate_content(&dir, &content);
+        let at = |diagnostics: &[Diagnostic], text: &str| {
+            let d = find(diagnostics, text);
+            (d.severity, d.line, d.column)
+        };
+        assert_eq!(
+            at(&diagnostics, "Invalid CIDR \"10.8.0.300/32\""),
+            (Severity::Error, 12, 29)
+        );
+        assert_eq!(
+            at(&diagnostics, "Preshared key must be 32 bytes"),
+            (Severity::Error, 13, 7)
+        );
+        assert_eq!(
+            at(
+                &diagnostics,
+                "Invalid endpoint \"vpn.example.com\": expected host:port"
+            ),
+            (Severity::Error, 19, 12)
+        );
+        assert_eq!(
+            at(&diagnostics, "Failed to decode public key"),
+            (Severity::Error, 22, 14)
+        );
+        assert_eq!(diagnostics.len(), 4);
+
+        let (_, other_key) = crypto::generate_keypair().unwrap();
+        let content = content
+            .replace(", 10.8.0.300/32", "")
+            .replace("psk = \"c2hvcnQ=\"\n", "")
+            .replace("vpn.example.com", "203.0.113.7:51820")
+            .replace("not base64!", &other_key.to_string());
+        let diagnostics = validate_content(&dir, &content);
+        assert_eq!(
+            at(
+                &diagnostics,
+                "Duplicate public key, also used by peer alice"
+            ),
+            (Severity::Error, 16, 14)
+        );
+        assert_eq!(
+            at(
+                &diagnostics,
+                "10.8.0.2/32 of peer bob overlaps 10.8.0.2/32 of peer alice"
+            ),
+            (Severity::Error, 17, 32)
+        );
+        assert_eq!(
+            at(&diagnostics, "Peer address 10.9.0.5/32 is outside"),
+            (Severity::Warning, 22, 16)
+        );
+        assert_eq!(
+            at(&diagnostics, "overlaps the interface address 10.8.0.1"),
+            (Severity::Error, 22, 29)
+        );
+        assert_eq!(diagnostics.len(), 4);
+        assert!(has_errors(&diagnostics));
+
+        let syntax = validate_content(&dir, "[interface]\nname = \"wg0\nprivate_key = 1\n");
+        assert_eq!((syntax[0].line, syntax[0].column), (2, 12));
+        let missing = validate_content(
+            &dir,
+            "version = 2\n[interface]\nname = \"wg0\"\n\npeers = []\n",
+        );
+        assert!(missing[0].message.contains("missing field"));
+        assert!(validate(&dir.join("missing.toml"), &Overrides::default()).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate_peers_dir() {
+        let dir = temp_dir("peers-dir");
+        let peers = dir.join("peers.d");
+        fs::create_dir_all(&peers).unwrap();
+        let (private_key, _) = crypto::generate_keypair().unwrap();
+        let (_, alice) = crypto::generate_keypair().unwrap();
+        let (_, carol) = crypto::generate_keypair().unwrap();
+        let content = format!(
+            "version = 2\npeers_dir = \"peers.d\"\n[interface]\nname = \"wg0\"\n\
+             private_key = \"{}\"\naddress = \"10.8.0.1/24\"\nlisten_port = 51820\n\n\
+             [[peers]]\nname = \"alice\"\npublic_key = \"{}\"\nallowed_ips = \"10.8.0.2/32\"\n",
+            private_key.to_base64().expose(),
+            alice
+        );
+        let peer = |public_key: &PublicKey, allowed_ips: &str| {
+            format!(
+                "[[peers]]\npublic_key = \"{}\"\nallowed_ips = \"{}\"\n",
+                public_key, allowed_ips
+            )
+        };
+        fs::write(peers.join("bob.toml"), peer(&alice, "10.8.0.3/32")).unwrap();
+        fs::write(peers.join("carol.toml"), peer(&carol, "10.8.0.3/32")).unwrap();
+        fs::write(peers.join("notes.txt"), "not toml").unwrap();
+
+        // 诊断指向对等体所在的文件
+        let diagnostics = validate_content(&dir, &content);
+        let d = find(
+            &diagnostics,
+            "Duplicate public key, also used by peer alice",
+        );
+        assert_eq!(d.path, peers.join("bob.toml"));
+        assert_eq!((d.line, d.column), (2, 14));
+        let d = find(
+            &diagnostics,
+            "10.8.0.3/32 of peers[0] in carol.toml overlaps 10.8.0.3/32 of peers[0] in bob.toml",
+        );
+        assert_eq!(d.path, peers.join("carol.toml"));
+        assert_eq!((d.line, d.column), (3, 16));
+        assert_eq!(diagnostics.len(), 2);
+
+        fs::write(peers.join("bob.toml"), peer(&alice, "10.8.0.4/33")).unwrap();
+        let diagnostics = validate_content(&dir, &content);
+        let d = find(&diagnostics, "Invalid CIDR \"10.8.0.4/33\"");
+        let expected = format!("{}:3:16: error: ", peers.join("bob.toml").display());
+        assert!(d.to_string().starts_with(&expected));
+        assert_eq!(diagnostics.len(), 1);
+
+        fs::write(peers.join("bob.toml"), "[interface]\nname = \"wg1\"\n").unwrap();
+        let diagnostics = validate_content(&dir, &content);
+        assert!(find(&diagnostics, "unknown field `interface`")
+            .path
+            .ends_with("bob.toml"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate_legacy_version() {
+        let dir = temp_dir("version");
+        let peers = dir.join("peers.d");
+        fs::create_dir_all(&peers).unwrap();
+        let (private_key, _) = crypto::generate_keypair().unwrap();
+        let (_, alice) = crypto::generate_keypair().unwrap();
+        let (_, bob) = crypto::generate_keypair().unwrap();
+        let content = format!(
+            "peers_dir = \"peers.d\"\n\n[interface]\nname = \"wg0\"\nprivate_key = \"{}\"\n\
+             address = \"10.8.0.1/24\"\nlisten_port = 51820\n\n\
+             [[peers]]\npublic_key = \"{}\"\nallowed_ips = \"10.8.0.2\"\n",
+            private_key.to_base64().expose(),
+            alice
+        );
+        let peer = format!(
+            "[[peers]]\npublic_key = \"{}\"\nallowed_ips = \"10.8.0.2\"\n",
+            bob
+        );
+        fs::write(peers.join("bob.toml"), peer).unwrap();
+
+        // 版本 1 省略前缀的地址按迁移后的值检查，另外提示更新文件
+        let diagnostics = validate_content(&dir, &content);
+        let d = find(&diagnostics, "Configuration version 1 is outdated");
+        assert_eq!((d.severity, d.line, d.column), (Severity::Warning, 1, 1));
+        let d = find(&diagnostics, "10.8.0.2/32 of peers[0] in bob.toml overlaps");
+        assert_eq!((d.line, d.column), (3, 16));
+        assert_eq!(diagnostics.len(), 2);
+
+        let diagnostics = validate_content(&dir, &format!("version = 3\n{}", content));
+        let d = find(&diagnostics, "newer than the supported version 2");
+        assert_eq!((d.severity, d.line, d.column), (Severity::Error, 1, 11));
+        assert_eq!(diagnostics.len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate_applies_overrides() {
+        let dir = temp_dir("overrides");
+        let (private_key, _) = crypto::generate_keypair().unwrap();
+        let content = "version = 2\npeers = []\n[interface]\nname = \"wg0\"\n\
+                       address = \"10.8.0.1/24\"\nlisten_port = 51820\n";
+        let diagnostics = validate_content(&dir, content);
+        assert_eq!(find(&diagnostics, "is required").severity, Severity::Error);
+
+        // 覆盖提供的必填项同样参与检查
+        let path = dir.join("server.toml");
+        let set = |item: String| {
+            let mut overrides = Overrides::default();
+            overrides.extend([item.parse().unwrap()]);
+            overrides
+        };
+        let key = set(format!(
+            "interface.private_key={}",
+            private_key.to_base64().expose()
+        ));
+        assert!(validate(&path, &key).unwrap().is_empty());
+
+        let port = set("interface.listen_port=x".to_string());
+        let diagnostics = validate(&path, &port).unwrap();
+        let d = find(&diagnostics, "Invalid configuration after overrides");
+        assert_eq!((d.severity, d.line, d.column), (Severity::Error, 1, 1));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_check_reports_port_in_use() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let (private_key, _) = crypto::generate_keypair().unwrap();
+        let content = format!(
+            "version = 2\npeers = []\n[interface]\nname = \"wg0\"\nprivate_key = \"{}\"\n\
+             address = \"10.8.0.1/24\"\nlisten_port = 51820\nlisten = [\"127.0.0.1:{}\"]\n",
+            private_key.to_base64().expose(),
+            port
+        );
+
+        let dir = temp_dir("port");
+        assert!(validate_content(&dir, &content).is_empty());
+        let path = dir.join("server.toml");
+        let diagnostics = check(&path, &Overrides::default()).await.unwrap();
+        let d = find(&diagnostics, "is already in use");
+        assert_eq!((d.severity, d.line, d.column), (Severity::Warning, 8, 10));
+        assert!(!has_errors(&diagnostics));
+        drop(socket);
+        assert!(check(&path, &Overrides::default())
+            .await
+            .unwrap()
+            .is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,95 +1,1016 @@
+use crate::crypto;
 use crate::error::{Error, Result};
+use crate::migrate::{self, Kind, CLIENT_VERSION, LEGACY_VERSION, SERVER_VERSION};
+use crate::overrides::Overrides;
+use crate::secret::{SecretKey, REDACTED};
+use crate::types::{Endpoint, ListenAddr, PublicKey};
+use ipnet::IpNet;
+use log::warn;
+use nix::fcntl::{Flock, FlockArg};
+use nix::unistd::Uid;
 use serde::{Deserialize, Serialize};
-use std::fs;
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use zeroize::{Zeroize, Zeroizing};
 
 /// 对等体配置
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PeerConfig {
+    /// 对等体名称（可选，用于 `add-peer` 和 `remove-peer`）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
     /// 对等体公钥
-    pub public_key: String,
-    /// 允许的 IP 地址范围
-    pub allowed_ips: String,
-    /// 对等体端点（可选，用于客户端连接）
-    pub endpoint: Option<String>,
+    pub public_key: PublicKey,
+    /// 允许的 IP 地址范围（配置中逗号分隔）
+    #[serde(with = "ip_list")]
+    pub allowed_ips: Vec<IpNet>,
+    /// 对等体端点（可选，`ip:port` 或 `host:port`，用于客户端连接）
+    pub endpoint: Option<Endpoint>,
+    /// 保活报文间隔（秒，可选，用于穿越 NAT）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub persistent_keepalive: Option<u16>,
     /// 预共享密钥（可选）
-    pub psk: Option<String>,
+    pub psk: Option<SecretKey>,
+    /// 从文件读取预共享密钥（可选，文件不能被组或其他用户读取）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub psk_file: Option<String>,
+    /// 从环境变量读取预共享密钥（可选）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub psk_env: Option<String>,
+    /// 从 systemd 凭据读取预共享密钥（可选，对应 `LoadCredential=` 的名称）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub psk_credential: Option<String>,
+    /// 发往该对等体的流量混淆（可选，对端需配置相同密钥）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub obfuscation: Option<ObfuscationConfig>,
+    /// 对端的后量子密钥交换地址（可选，`ip:port` 或 `host:port`，配置后由本端定期发起交换）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pq_endpoint: Option<Endpoint>,
+    /// 对等体所在的 `peers_dir` 文件，为空时在主配置文件中
+    #[serde(skip)]
+    pub source: Option<PathBuf>,
+    /// 加载配置时解析的主机名端点地址
+    #[serde(skip)]
+    pub resolved_endpoint: Option<SocketAddr>,
+    /// 加载配置时解析的主机名后量子密钥交换地址
+    #[serde(skip)]
+    pub resolved_pq_endpoint: Option<SocketAddr>,
+}
+
+/// 流量混淆配置
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObfuscationConfig {
+    /// 共享密钥（Base64 编码的 32 字节）
+    pub key: SecretKey,
+    /// 每次发起握手前发送的垃圾报文数
+    #[serde(default)]
+    pub junk_packets: u8,
+}
+
+/// WireGuard 后端
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// 内核 wireguard 模块
+    Kernel,
+    /// 基于 boringtun 的用户空间实现
+    #[default]
+    Userspace,
 }
 
 /// 接口配置
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct InterfaceConfig {
     /// 接口名称
     pub name: String,
-    /// 私钥
-    pub private_key: String,
+    /// 私钥（使用外部来源时留空）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_key: Option<SecretKey>,
+    /// 从文件读取私钥（可选，文件不能被组或其他用户读取）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_key_file: Option<String>,
+    /// 从环境变量读取私钥（可选）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_key_env: Option<String>,
+    /// 从 systemd 凭据读取私钥（可选，对应 `LoadCredential=` 的名称）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_key_credential: Option<String>,
+    /// 下一个私钥（可选，同时接受发往该密钥的握手，用 `promote` 提升为当前私钥）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_private_key: Option<SecretKey>,
+    /// 从文件读取下一个私钥（可选，要求同 `private_key_file`）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_private_key_file: Option<String>,
+    /// 从环境变量读取下一个私钥（可选）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_private_key_env: Option<String>,
+    /// 从 systemd 凭据读取下一个私钥（可选）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_private_key_credential: Option<String>,
+    /// 被替换的私钥（由 `promote` 写入，失效前仍接受发往该密钥的握手）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_private_key: Option<SecretKey>,
+    /// 被替换的私钥失效的 Unix 时间戳（秒）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_key_expires: Option<u64>,
+    /// 提升后新旧私钥同时有效的时间（秒，默认 7 天）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_overlap: Option<u64>,
     /// 接口地址
-    pub address: String,
+    pub address: IpNet,
     /// 监听端口
     pub listen_port: u16,
+    /// 监听地址列表（可选，如 "203.0.113.5:443"；只写 IP 时使用 `listen_port`，为空时监听所有地址）
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub listen: Vec<ListenAddr>,
+    /// WireGuard 后端
+    #[serde(default)]
+    pub backend: Backend,
+    /// 接口所在的网络命名空间（可选，UDP 套接字保留在宿主命名空间）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub netns: Option<String>,
+    /// 接受使用该密钥混淆的流量（可选，未混淆的对等体不受影响）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub obfuscation: Option<ObfuscationConfig>,
+    /// 接口 MTU（可选，默认由系统决定）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<u16>,
+    /// wg-quick 的 PostUp 命令，只在导入和导出时保留，服务器不执行
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub post_up: Vec<String>,
+    /// wg-quick 的 PreDown 命令，同 `post_up`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pre_down: Vec<String>,
+}
+
+/// NAT 伪装配置
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NatConfig {
+    /// 出口网卡名称
+    pub egress_interface: String,
+    /// 需要伪装的隧道子网
+    pub masquerade: Vec<IpNet>,
+}
+
+/// TCP/TLS 流式传输配置
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StreamConfig {
+    /// 监听地址，如 "0.0.0.0:443"
+    pub listen: SocketAddr,
+    /// TLS 证书链（PEM，可选）
+    pub tls_cert: Option<String>,
+    /// TLS 私钥（PEM，可选）
+    pub tls_key: Option<String>,
+}
+
+/// 权限配置
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SecurityConfig {
+    /// 设备初始化完成后切换到的用户（可选）
+    pub user: Option<String>,
+    /// 切换到的组（可选，默认为用户的主组）
+    pub group: Option<String>,
+    /// 初始化完成后启用 seccomp 和 Landlock 沙箱
+    #[serde(default)]
+    pub sandbox: bool,
+    /// 沙箱中可读写的状态目录（可选）
+    pub state_dir: Option<String>,
+}
+
+/// 地址池配置
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IpamConfig {
+    /// 接口子网之外的其他地址池（逗号分隔，如 `fd00::1/64`），主机位不为零时该地址保留给服务器
+    #[serde(default, skip_serializing_if = "Vec::is_empty", with = "ip_list")]
+    pub pools: Vec<IpNet>,
+    /// 不分配给对等体的范围（逗号分隔）
+    #[serde(default, skip_serializing_if = "Vec::is_empty", with = "ip_list")]
+    pub reserved: Vec<IpNet>,
+}
+
+/// 后量子预共享密钥轮换配置
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PqConfig {
+    /// 密钥交换监听地址，如 "0.0.0.0:51821"
+    pub listen: SocketAddr,
+    /// 轮换间隔（秒）
+    #[serde(default = "default_rotation_interval")]
+    pub rotation_interval: u64,
+}
+
+fn default_rotation_interval() -> u64 {
+    120
+}
+
+fn legacy_version() -> u32 {
+    LEGACY_VERSION
 }
 
 /// 服务器配置
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ServerConfig {
+    /// 配置格式版本，旧版本在加载时自动迁移
+    #[serde(default = "legacy_version")]
+    pub version: u32,
     /// 接口配置
     pub interface: InterfaceConfig,
+    /// NAT 配置（可选）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nat: Option<NatConfig>,
+    /// TCP/TLS 回退传输（可选）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream: Option<StreamConfig>,
+    /// 权限配置（可选）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub security: Option<SecurityConfig>,
+    /// 后量子预共享密钥轮换（可选，需启用 `pq` 特性）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pq: Option<PqConfig>,
+    /// 地址池（可选，默认只使用接口子网）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipam: Option<IpamConfig>,
+    /// 从该目录的 `*.toml` 文件加载更多对等体（可选，相对路径以主配置文件所在目录为基准）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peers_dir: Option<String>,
     /// 对等体列表
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub peers: Vec<PeerConfig>,
 }
 
+/// `peers_dir` 中的文件，只包含 `[[peers]]`
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PeerFile {
+    #[serde(default)]
+    pub peers: Vec<PeerConfig>,
+}
+
+/// 两份配置之间对等体的差异，按公钥匹配
+#[derive(Debug, Default)]
+pub struct PeerChanges<'a> {
+    /// 新增的对等体
+    pub added: Vec<&'a PeerConfig>,
+    /// 设置发生变化的对等体（新配置）
+    pub changed: Vec<&'a PeerConfig>,
+    /// 删除的对等体
+    pub removed: Vec<&'a PeerConfig>,
+}
+
 /// 客户端配置
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClientConfig {
-    /// 私钥
-    pub private_key: String,
-    /// 本地地址
-    pub address: String,
+    /// 配置格式版本，旧版本在加载时自动迁移
+    #[serde(default = "legacy_version")]
+    pub version: u32,
+    /// 私钥（使用外部来源时留空）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_key: Option<SecretKey>,
+    /// 从文件读取私钥（可选）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_key_file: Option<String>,
+    /// 从环境变量读取私钥（可选）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_key_env: Option<String>,
+    /// 从 systemd 凭据读取私钥（可选）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_key_credential: Option<String>,
+    /// 本地地址（逗号分隔）
+    #[serde(with = "ip_list")]
+    pub address: Vec<IpNet>,
     /// 服务器公钥
-    pub server_public_key: String,
+    pub server_public_key: PublicKey,
     /// 服务器端点
-    pub server_endpoint: String,
+    pub server_endpoint: Endpoint,
+    /// 经隧道路由的地址范围（可选，逗号分隔，为空时路由全部流量）
+    #[serde(default, skip_serializing_if = "Vec::is_empty", with = "ip_list")]
+    pub allowed_ips: Vec<IpNet>,
+    /// 保活报文间隔（秒，可选）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub persistent_keepalive: Option<u16>,
     /// 预共享密钥（可选）
-    pub psk: Option<String>,
+    pub psk: Option<SecretKey>,
+    /// 从文件读取预共享密钥（可选）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub psk_file: Option<String>,
+    /// 从环境变量读取预共享密钥（可选）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub psk_env: Option<String>,
+    /// 从 systemd 凭据读取预共享密钥（可选）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub psk_credential: Option<String>,
     /// DNS 服务器（可选）
     pub dns: Option<Vec<String>>,
+    /// 接口 MTU（可选）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<u16>,
+    /// wg-quick 的 PostUp 命令，只在导入和导出时保留
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub post_up: Vec<String>,
+    /// wg-quick 的 PreDown 命令，只在导入和导出时保留
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pre_down: Vec<String>,
+}
+
+/// 读取外部密钥时使用的环境变量（`*_env` 和 `CREDENTIALS_DIRECTORY`）
+///
+/// 默认读取进程环境；测试显式传入变量，避免修改全局环境而与并行的测试互相干扰。
+#[derive(Debug, Clone, Default)]
+pub enum Environment {
+    /// 当前进程的环境变量
+    #[default]
+    Process,
+    /// 给定的变量
+    Vars(HashMap<String, String>),
+}
+
+impl Environment {
+    fn var(&self, name: &str) -> Option<String> {
+        match self {
+            Environment::Process => env::var(name).ok(),
+            Environment::Vars(vars) => vars.get(name).cloned(),
+        }
+    }
+}
+
+impl FromIterator<(String, String)> for Environment {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(vars: I) -> Self {
+        Environment::Vars(vars.into_iter().collect())
+    }
+}
+
+/// 密钥的外部来源：文件、环境变量或 systemd 凭据
+struct SecretSource<'a> {
+    /// 配置项名称前缀，用于错误信息
+    field: &'a str,
+    file: Option<&'a str>,
+    env: Option<&'a str>,
+    credential: Option<&'a str>,
+}
+
+impl SecretSource<'_> {
+    fn is_set(&self) -> bool {
+        self.file.is_some() || self.env.is_some() || self.credential.is_some()
+    }
+
+    /// 读取并解码密钥，未配置外部来源时返回 `None`
+    fn load(&self, inline: bool, environment: &Environment) -> Result<Option<SecretKey>> {
+        let value = Zeroizing::new(match (self.file, self.env, self.credential) {
+            (None, None, None) => return Ok(None),
+            _ if inline => {
+                return Err(Error::ConfigError(format!(
+                    "{} is set both inline and from an external source",
+                    self.field
+                )))
+            }
+            (Some(path), None, None) => read_key_file(Path::new(path))?,
+            (None, Some(var), None) => environment.var(var).ok_or_else(|| {
+                Error::ConfigError(format!(
+                    "Environment variable {} for {} is not set",
+                    var, self.field
+                ))
+            })?,
+            (None, None, Some(name)) => {
+                let dir = environment.var("CREDENTIALS_DIRECTORY").ok_or_else(|| {
+                    Error::ConfigError(format!(
+                        "{}_credential requires CREDENTIALS_DIRECTORY; load it with LoadCredential=",
+                        self.field
+                    ))
+                })?;
+                // 凭据名是目录中的文件名，不能指向目录之外
+                if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+                    return Err(Error::ConfigError(format!(
+                        "Invalid {}_credential {:?}: expected a plain credential name",
+                        self.field, name
+                    )));
+                }
+                fs::read_to_string(Path::new(&dir).join(name)).map_err(|e| {
+                    Error::ConfigError(format!("Failed to read credential {}: {}", name, e))
+                })?
+            }
+            _ => {
+                return Err(Error::ConfigError(format!(
+                    "Only one of {0}_file, {0}_env and {0}_credential may be set",
+                    self.field
+                )))
+            }
+        });
+
+        let value = value.trim();
+        if value.is_empty() {
+            return Err(Error::ConfigError(format!(
+                "{} from an external source is empty",
+                self.field
+            )));
+        }
+        let key = crypto::decode_secret(value).map_err(|e| {
+            Error::ConfigError(format!(
+                "Invalid {} from an external source: {}",
+                self.field,
+                e.detail()
+            ))
+        })?;
+        Ok(Some(key))
+    }
+}
+
+/// 以逗号分隔的字符串保存地址范围列表，与 wg-quick 的写法一致
+mod ip_list {
+    use crate::types;
+    use ipnet::IpNet;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(networks: &[IpNet], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&types::join_networks(networks))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<IpNet>, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        types::parse_networks(&value).map_err(de::Error::custom)
+    }
+}
+
+/// 读取密钥文件，拒绝组或其他用户可读、或不属于 root 和当前用户的文件
+///
+/// 权限检查和读取使用同一个打开的文件，检查之后替换路径不会绕过检查。
+fn read_key_file(path: &Path) -> Result<String> {
+    let read_error = |e: std::io::Error| {
+        Error::ConfigError(format!("Failed to read key file {:?}: {}", path, e))
+    };
+    let mut file = File::open(path).map_err(read_error)?;
+    let metadata = file.metadata().map_err(read_error)?;
+    if metadata.permissions().mode() & 0o044 != 0 {
+        return Err(Error::SecurityError(format!(
+            "Key file {:?} is readable by group or others; run chmod 600",
+            path
+        )));
+    }
+    let owner = metadata.uid();
+    if owner != 0 && owner != Uid::effective().as_raw() {
+        return Err(Error::SecurityError(format!(
+            "Key file {:?} is owned by uid {}; it must belong to root or the server user",
+            path, owner
+        )));
+    }
+    let mut content = String::new();
+    file.read_to_string(&mut content).map_err(read_error)?;
+    Ok(content)
+}
+
+/// 列出 `peers_dir` 中的 `*.toml` 文件，按文件名排序，忽略隐藏文件
+pub fn peer_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = fs::read_dir(dir)
+        .map_err(|e| Error::ConfigError(format!("Failed to read peers_dir {:?}: {}", dir, e)))?;
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let hidden = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        if !hidden && path.extension().is_some_and(|ext| ext == "toml") && path.is_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// 在内存中把旧版本的配置迁移到当前版本，返回迁移后的内容和原版本
+///
+/// 不写入版本号，解析错误中的行号与文件一致。
+fn upgrade(path: &Path, content: String, kind: Kind) -> Result<(String, u32)> {
+    match migrate::upgrade(&content, kind, false)? {
+        Some(upgraded) => {
+            warn!(
+                "{:?} uses configuration version {}, upgraded in memory to {}; \
+                 run `migrate` to update the file",
+                path,
+                upgraded.from,
+                kind.current()
+            );
+            Ok((upgraded.content, upgraded.from))
+        }
+        None => Ok((content, kind.current())),
+    }
+}
+
+/// 错误信息中对等体所在的文件
+fn origin(peer: &PeerConfig) -> String {
+    match &peer.source {
+        Some(source) => format!("{:?}", source),
+        None => "the main configuration".to_string(),
+    }
+}
+
+/// 配置中保存密钥的键
+const SECRET_FIELDS: &[&str] = &[
+    "private_key",
+    "next_private_key",
+    "previous_private_key",
+    "psk",
+    "key",
+];
+
+/// 将 TOML 值中的密钥替换为 `[redacted]`，并清零原文
+fn redact(value: &mut toml::Value) {
+    match value {
+        toml::Value::Table(table) => {
+            for (key, item) in table.iter_mut() {
+                match item {
+                    toml::Value::String(secret) if SECRET_FIELDS.contains(&key.as_str()) => {
+                        secret.zeroize();
+                        *secret = REDACTED.to_string();
+                    }
+                    item => redact(item),
+                }
+            }
+        }
+        toml::Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+fn save_toml<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let content = toml::to_string_pretty(value)
+        .map_err(|e| Error::ConfigError(format!("Failed to serialize config: {}", e)))?;
+    write_atomic(path, &content)
+        .map_err(|e| Error::ConfigError(format!("Failed to write config file {:?}: {}", path, e)))
+}
+
+/// 先写入同目录的临时文件再重命名，读取方不会看到写了一半的配置
+///
+/// 保留原文件的权限，新文件仅所有者可读写。临时文件名包含进程号和随机后缀，并发写入不会互相覆盖。
+pub fn write_atomic(path: &Path, content: &str) -> std::io::Result<()> {
+    let mode = fs::metadata(path)
+        .map(|m| m.permissions().mode())
+        .unwrap_or(0o600);
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(
+        ".{}.{:08x}.tmp",
+        std::process::id(),
+        rand::random::<u32>()
+    ));
+    let temp = path.with_file_name(name);
+
+    let result = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&temp)
+        .and_then(|mut file| {
+            file.write_all(content.as_bytes())?;
+            file.set_permissions(fs::Permissions::from_mode(mode))?;
+            file.sync_all()
+        })
+        .and_then(|()| fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// 为修改配置文件的命令加排他锁，返回值释放前其他命令等待
+///
+/// 配置文件写入时被重命名替换，因此锁加在同目录的 `<name>.lock` 上。
+pub fn lock_config(path: &Path) -> Result<Flock<File>> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".lock");
+    let lock_path = path.with_file_name(name);
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(&lock_path)
+        .map_err(|e| Error::ConfigError(format!("Failed to open {:?}: {}", lock_path, e)))?;
+    Flock::lock(file, FlockArg::LockExclusive)
+        .map_err(|(_, e)| Error::ConfigError(format!("Failed to lock {:?}: {}", lock_path, e)))
+}
+
+impl PeerConfig {
+    fn psk_source(&self) -> SecretSource<'_> {
+        SecretSource {
+            field: "psk",
+            file: self.psk_file.as_deref(),
+            env: self.psk_env.as_deref(),
+            credential: self.psk_credential.as_deref(),
+        }
+    }
+
+    /// 预共享密钥是否来自外部来源
+    pub fn has_external_psk(&self) -> bool {
+        self.psk_source().is_set()
+    }
+
+    /// 从外部来源读取预共享密钥
+    pub fn load_secrets(&mut self, environment: &Environment) -> Result<()> {
+        if let Some(psk) = self.psk_source().load(self.psk.is_some(), environment)? {
+            self.psk = Some(psk);
+        }
+        Ok(())
+    }
+
+    /// 端点地址，主机名使用 [`PeerConfig::resolve_endpoints`] 解析的结果，不查询 DNS
+    pub fn endpoint_addr(&self) -> Option<SocketAddr> {
+        match self.endpoint.as_ref()? {
+            Endpoint::Addr(addr) => Some(*addr),
+            Endpoint::Host(..) => self.resolved_endpoint,
+        }
+    }
+
+    /// 后量子密钥交换地址，处理方式与 [`PeerConfig::endpoint_addr`] 相同
+    pub fn pq_endpoint_addr(&self) -> Option<SocketAddr> {
+        match self.pq_endpoint.as_ref()? {
+            Endpoint::Addr(addr) => Some(*addr),
+            Endpoint::Host(..) => self.resolved_pq_endpoint,
+        }
+    }
+
+    /// 查询主机名端点的 DNS；无法解析时记录警告，等待对端主动连接
+    pub async fn resolve_endpoints(&mut self) {
+        self.resolved_endpoint = self.lookup(self.endpoint.as_ref()).await;
+        self.resolved_pq_endpoint = self.lookup(self.pq_endpoint.as_ref()).await;
+    }
+
+    async fn lookup(&self, endpoint: Option<&Endpoint>) -> Option<SocketAddr> {
+        let endpoint @ Endpoint::Host(..) = endpoint? else {
+            return None;
+        };
+        endpoint
+            .resolve()
+            .await
+            .map_err(|e| warn!("Peer {}: {}", self.public_key.short(), e.detail()))
+            .ok()
+    }
+}
+
+/// 默认的私钥重叠期：7 天
+const DEFAULT_KEY_OVERLAP: u64 = 7 * 24 * 3600;
+
+impl InterfaceConfig {
+    fn private_key_source(&self) -> SecretSource<'_> {
+        SecretSource {
+            field: "private_key",
+            file: self.private_key_file.as_deref(),
+            env: self.private_key_env.as_deref(),
+            credential: self.private_key_credential.as_deref(),
+        }
+    }
+
+    fn next_private_key_source(&self) -> SecretSource<'_> {
+        SecretSource {
+            field: "next_private_key",
+            file: self.next_private_key_file.as_deref(),
+            env: self.next_private_key_env.as_deref(),
+            credential: self.next_private_key_credential.as_deref(),
+        }
+    }
+
+    /// 私钥是否来自外部来源
+    pub fn has_external_private_key(&self) -> bool {
+        self.private_key_source().is_set()
+    }
+
+    /// 下一个私钥是否来自外部来源
+    pub fn has_external_next_key(&self) -> bool {
+        self.next_private_key_source().is_set()
+    }
+
+    /// 从外部来源读取私钥和下一个私钥
+    pub fn load_secrets(&mut self, environment: &Environment) -> Result<()> {
+        if let Some(key) = self
+            .private_key_source()
+            .load(self.private_key.is_some(), environment)?
+        {
+            self.private_key = Some(key);
+        }
+        if let Some(key) = self
+            .next_private_key_source()
+            .load(self.next_private_key.is_some(), environment)?
+        {
+            self.next_private_key = Some(key);
+        }
+        self.private_key()?;
+        Ok(())
+    }
+
+    /// 当前私钥，未配置时返回错误
+    pub fn private_key(&self) -> Result<&SecretKey> {
+        self.private_key.as_ref().ok_or_else(|| {
+            Error::ConfigError(
+                "interface.private_key, private_key_file, private_key_env or private_key_credential is required"
+                    .to_string(),
+            )
+        })
+    }
+
+    /// 接受握手的私钥及其失效时间（Unix 秒），第一个为当前私钥
+    ///
+    /// 已过期的旧私钥不包含在内。
+    pub fn accepted_private_keys(&self, now: u64) -> Result<Vec<(&SecretKey, Option<u64>)>> {
+        let mut keys = vec![(self.private_key()?, None)];
+        keys.extend(self.next_private_key.as_ref().map(|key| (key, None)));
+        if let Some(previous) = &self.previous_private_key {
+            match self.previous_key_expires {
+                Some(expires) if expires <= now => {}
+                expires => keys.push((previous, expires)),
+            }
+        }
+        Ok(keys)
+    }
+
+    /// 将 `next_private_key` 提升为当前私钥，返回新的公钥
+    ///
+    /// 原私钥移入 `previous_private_key`，在 `key_overlap` 秒内仍被接受，供客户端逐步更新服务器公钥。
+    pub fn promote_next_key(&mut self, now: u64) -> Result<PublicKey> {
+        if self.has_external_private_key() || self.has_external_next_key() {
+            return Err(Error::ConfigError(
+                "private_key or next_private_key comes from an external source; replace it there instead of using promote"
+                    .to_string(),
+            ));
+        }
+        let next = self.next_private_key.take().ok_or_else(|| {
+            Error::ConfigError("interface.next_private_key is not set".to_string())
+        })?;
+        let public_key = crypto::public_key(&next);
+
+        self.previous_private_key = self.private_key.replace(next);
+        self.previous_key_expires = Some(now + self.key_overlap.unwrap_or(DEFAULT_KEY_OVERLAP));
+        Ok(public_key)
+    }
+
+    /// 监听的套接字地址，只写 IP 的地址使用 `listen_port`
+    pub fn listen_addrs(&self) -> Vec<SocketAddr> {
+        if self.listen.is_empty() {
+            return vec![SocketAddr::from((Ipv4Addr::UNSPECIFIED, self.listen_port))];
+        }
+
+        self.listen
+            .iter()
+            .map(|addr| addr.with_default_port(self.listen_port))
+            .collect()
+    }
 }
 
 impl ServerConfig {
-    /// 从文件加载服务器配置
+    /// 从文件加载服务器配置，应用 `RT_` 环境变量覆盖，合并 `peers_dir` 中的对等体，并读取外部来源的密钥
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::load(path.as_ref(), &Overrides::from_env()?)
+    }
+
+    /// 从文件加载服务器配置并应用给定的覆盖，不读取 `RT_` 环境变量
+    ///
+    /// 覆盖只作用于主配置文件，之后再合并 `peers_dir` 中的对等体。
+    /// 会把配置写回文件的命令应传入空的覆盖，避免覆盖值被持久化。
+    pub fn load(path: &Path, overrides: &Overrides) -> Result<Self> {
+        Self::load_with_env(path, overrides, &Environment::Process)
+    }
+
+    /// 同 [`ServerConfig::load`]，外部密钥来源从 `environment` 读取
+    pub fn load_with_env(
+        path: &Path,
+        overrides: &Overrides,
+        environment: &Environment,
+    ) -> Result<Self> {
         let content = fs::read_to_string(path)
             .map_err(|e| Error::ConfigError(format!("Failed to read config file: {}", e)))?;
-        toml::from_str(&content)
-            .map_err(|e| Error::ConfigError(format!("Failed to parse config: {}", e)))
+        let (content, from) = upgrade(path, content, Kind::Server)?;
+        let mut config = Self::parse(&content, overrides)?;
+        config.version = SERVER_VERSION;
+        config.load_peer_files(path, from)?;
+        config.load_secrets(environment)?;
+        Ok(config)
     }
 
-    /// 保存配置到文件
-    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
-        let content = toml::to_string_pretty(self)
+    /// 解析主配置文件的内容并应用覆盖，不合并 `peers_dir`，也不读取外部来源的密钥
+    pub fn parse(content: &str, overrides: &Overrides) -> Result<Self> {
+        let parse_error =
+            |e: toml::de::Error| Error::ConfigError(format!("Failed to parse config: {}", e));
+        if overrides.is_empty() {
+            return toml::from_str(content).map_err(parse_error);
+        }
+        let mut table: toml::Table = toml::from_str(content).map_err(parse_error)?;
+        overrides.apply(&mut table)?;
+        let config: ServerConfig = toml::Value::Table(table).try_into().map_err(|e| {
+            Error::ConfigError(format!("Invalid configuration after overrides: {}", e))
+        })?;
+        for item in overrides.unmatched(&config.redacted()?) {
+            warn!(
+                "Override {} ({}) does not match any setting",
+                item.origin, item
+            );
+        }
+        Ok(config)
+    }
+
+    /// 解析全部对等体的主机名端点，每次加载配置后调用一次，运行中不再查询 DNS
+    pub async fn resolve_endpoints(&mut self) {
+        for peer in &mut self.peers {
+            peer.resolve_endpoints().await;
+        }
+    }
+
+    /// 序列化为 TOML 值，密钥替换为 `[redacted]`，来自外部来源的密钥只保留来源
+    pub fn redacted(&self) -> Result<toml::Value> {
+        let mut value = toml::Value::try_from(self.without_external_secrets())
             .map_err(|e| Error::ConfigError(format!("Failed to serialize config: {}", e)))?;
-        fs::write(path, content)
-            .map_err(|e| Error::ConfigError(format!("Failed to write config file: {}", e)))?;
+        redact(&mut value);
+        Ok(value)
+    }
+
+    /// `peers_dir` 的实际路径
+    pub fn peers_dir(&self, config_path: &Path) -> Option<PathBuf> {
+        let dir = self.peers_dir.as_ref()?;
+        Some(config_path.parent().unwrap_or(Path::new("")).join(dir))
+    }
+
+    /// 读取 `peers_dir` 中的对等体，按主配置文件的原版本 `from` 迁移，公钥重复时返回错误
+    fn load_peer_files(&mut self, config_path: &Path, from: u32) -> Result<()> {
+        if let Some(dir) = self.peers_dir(config_path) {
+            for path in peer_files(&dir)? {
+                let mut content = fs::read_to_string(&path)
+                    .map_err(|e| Error::ConfigError(format!("Failed to read {:?}: {}", path, e)))?;
+                if let Some(upgraded) = migrate::upgrade_peers(&content, from)
+                    .map_err(|e| Error::ConfigError(format!("{:?}: {}", path, e.detail())))?
+                {
+                    content = upgraded;
+                }
+                let file: PeerFile = toml::from_str(&content).map_err(|e| {
+                    Error::ConfigError(format!("Failed to parse {:?}: {}", path, e))
+                })?;
+                self.peers.extend(file.peers.into_iter().map(|mut peer| {
+                    peer.source = Some(path.clone());
+                    peer
+                }));
+            }
+        }
+
+        let mut seen: HashMap<PublicKey, &PeerConfig> = HashMap::new();
+        for peer in &self.peers {
+            if let Some(first) = seen.insert(peer.public_key, peer) {
+                return Err(Error::ConfigError(format!(
+                    "Duplicate public key {} in {} and {}",
+                    peer.public_key,
+                    origin(first),
+                    origin(peer)
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// 读取外部来源的私钥和预共享密钥
+    pub fn load_secrets(&mut self, environment: &Environment) -> Result<()> {
+        self.interface.load_secrets(environment)?;
+        for peer in &mut self.peers {
+            peer.load_secrets(environment)?;
+        }
         Ok(())
     }
+
+    /// 密钥文件和 systemd 凭据目录，沙箱需要允许读取以便重载
+    pub fn secret_paths(&self) -> Vec<PathBuf> {
+        let mut sources = vec![
+            self.interface.private_key_source(),
+            self.interface.next_private_key_source(),
+        ];
+        sources.extend(self.peers.iter().map(PeerConfig::psk_source));
+
+        let mut paths: Vec<PathBuf> = sources
+            .iter()
+            .filter_map(|source| source.file)
+            .map(PathBuf::from)
+            .collect();
+        if sources.iter().any(|source| source.credential.is_some()) {
+            paths.extend(env::var_os("CREDENTIALS_DIRECTORY").map(PathBuf::from));
+        }
+        paths
+    }
+
+    /// 去掉从外部来源读取的密钥，只保留来源，使配置可以重新加载
+    fn without_external_secrets(&self) -> Self {
+        let mut config = self.clone();
+        if config.interface.has_external_private_key() {
+            config.interface.private_key = None;
+        }
+        if config.interface.has_external_next_key() {
+            config.interface.next_private_key = None;
+        }
+        for peer in &mut config.peers {
+            if peer.psk_source().is_set() {
+                peer.psk = None;
+            }
+        }
+        config
+    }
+
+    /// 原子地保存配置到文件，`peers_dir` 中的对等体写回各自的文件，来自外部来源的密钥不会写入
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut config = self.without_external_secrets();
+        let mut files: BTreeMap<PathBuf, Vec<PeerConfig>> = BTreeMap::new();
+        for peer in std::mem::take(&mut config.peers) {
+            match peer.source.clone() {
+                Some(source) => files.entry(source).or_default().push(peer),
+                None => config.peers.push(peer),
+            }
+        }
+
+        save_toml(path.as_ref(), &config)?;
+        for (source, peers) in files {
+            save_toml(&source, &PeerFile { peers })?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> PeerChanges<'a> {
+    /// 比较旧配置和新配置中的对等体
+    pub fn between(old: &'a [PeerConfig], new: &'a [PeerConfig]) -> Self {
+        let mut changes = PeerChanges::default();
+        for peer in new {
+            match old.iter().find(|p| p.public_key == peer.public_key) {
+                Some(previous) if previous != peer => changes.changed.push(peer),
+                Some(_) => {}
+                None => changes.added.push(peer),
+            }
+        }
+        changes.removed = old
+            .iter()
+            .filter(|peer| !new.iter().any(|p| p.public_key == peer.public_key))
+            .collect();
+        changes
+    }
+
+    /// 是否没有任何变化
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
 }
 
 impl ClientConfig {
-    /// 从文件加载客户端配置
+    fn private_key_source(&self) -> SecretSource<'_> {
+        SecretSource {
+            field: "private_key",
+            file: self.private_key_file.as_deref(),
+            env: self.private_key_env.as_deref(),
+            credential: self.private_key_credential.as_deref(),
+        }
+    }
+
+    fn psk_source(&self) -> SecretSource<'_> {
+        SecretSource {
+            field: "psk",
+            file: self.psk_file.as_deref(),
+            env: self.psk_env.as_deref(),
+            credential: self.psk_credential.as_deref(),
+        }
+    }
+
+    /// 从文件加载客户端配置，并读取外部来源的密钥
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
         let content = fs::read_to_string(path)
             .map_err(|e| Error::ConfigError(format!("Failed to read config file: {}", e)))?;
-        toml::from_str(&content)
-            .map_err(|e| Error::ConfigError(format!("Failed to parse config: {}", e)))
+        let (content, _) = upgrade(path, content, Kind::Client)?;
+        let mut config: ClientConfig = toml::from_str(&content)
+            .map_err(|e| Error::ConfigError(format!("Failed to parse config: {}", e)))?;
+        config.version = CLIENT_VERSION;
+
+        if let Some(key) = config
+            .private_key_source()
+            .load(config.private_key.is_some(), &Environment::Process)?
+        {
+            config.private_key = Some(key);
+        }
+        if config.private_key.is_none() {
+            return Err(Error::ConfigError("private_key is required".to_string()));
+        }
+        if let Some(psk) = config
+            .psk_source()
+            .load(config.psk.is_some(), &Environment::Process)?
+        {
+            config.psk = Some(psk);
+        }
+        Ok(config)
     }
 
-    /// 保存配置到文件
+    /// 原子地保存配置到文件，来自外部来源的密钥不会写入
     pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
-        let content = toml::to_string_pretty(self)
+        let mut config = self.clone();
+        if config.private_key_source().is_set() {
+            config.private_key = None;
+        }
+        if config.psk_source().is_set() {
+            config.psk = None;
+        }
+        let content = toml::to_string_pretty(&config)
             .map_err(|e| Error::ConfigError(format!("Failed to serialize config: {}", e)))?;
-        fs::write(path, content)
-            .map_err(|e| Error::ConfigError(format!("Failed to write config file: {}", e)))?;
-        Ok(())
+        write_atomic(path.as_ref(), &content)
+            .map_err(|e| Error::ConfigError(format!("Failed to write config file: {}", e)))
     }
 }
 
@@ -97,25 +1018,498 @@ impl ClientConfig {
 mod tests {
     use super::*;
 
+    const PRIVATE_KEY: &str = "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=";
+    const PEER_KEY: &str = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=";
+
     #[test]
     fn test_server_config_serialization() {
         let config = ServerConfig {
+            version: SERVER_VERSION,
             interface: InterfaceConfig {
                 name: "wg0".to_string(),
-                private_key: "test_key".to_string(),
-                address: "10.8.0.1/24".to_string(),
+                private_key: Some(SecretKey::from([1u8; 32])),
+                address: "10.8.0.1/24".parse().unwrap(),
                 listen_port: 51820,
+                ..Default::default()
             },
+            nat: None,
+            stream: None,
+            security: None,
+            pq: None,
+            ipam: None,
+            peers_dir: None,
             peers: vec![PeerConfig {
-                public_key: "peer_key".to_string(),
-                allowed_ips: "10.8.0.2/32".to_string(),
-                endpoint: None,
-                psk: None,
+                public_key: PublicKey::from([2u8; 32]),
+                allowed_ips: vec!["10.8.0.2/32".parse().unwrap()],
+                ..Default::default()
             }],
         };
 
         let toml_str = toml::to_string(&config).unwrap();
         assert!(toml_str.contains("wg0"));
         assert!(toml_str.contains("51820"));
+        assert!(toml_str.contains(r#"allowed_ips = "10.8.0.2/32""#));
+        assert!(!toml_str.contains("[nat]"));
+    }
+
+    #[test]
+    fn test_nat_section_parsing() {
+        let config: ServerConfig = toml::from_str(&format!(
+            r#"
+[interface]
+name = "wg0"
+private_key = "{PRIVATE_KEY}"
+address = "10.8.0.1/24"
+listen_port = 51820
+
+[nat]
+egress_interface = "eth0"
+masquerade = ["10.8.0.0/24"]
+
+[stream]
+listen = "0.0.0.0:443"
+
+[security]
+user = "rustytunnel"
+sandbox = true
+
+[[peers]]
+public_key = "{PEER_KEY}"
+allowed_ips = "10.8.0.2/32"
+"#
+        ))
+        .unwrap();
+
+        assert_eq!(config.interface.backend, Backend::Userspace);
+        let nat = config.nat.unwrap();
+        assert_eq!(nat.egress_interface, "eth0");
+        assert_eq!(
+            nat.masquerade,
+            vec!["10.8.0.0/24".parse::<IpNet>().unwrap()]
+        );
+        assert_eq!(
+            config.stream.unwrap().listen,
+            "0.0.0.0:443".parse().unwrap()
+        );
+        let security = config.security.unwrap();
+        assert_eq!(security.user.as_deref(), Some("rustytunnel"));
+        assert_eq!(security.group, None);
+        assert!(security.sandbox);
+    }
+
+    #[test]
+    fn test_backend_parsing() {
+        let interface: InterfaceConfig = toml::from_str(&format!(
+            r#"
+name = "wg0"
+private_key = "{PRIVATE_KEY}"
+address = "10.8.0.1/24"
+listen_port = 51820
+backend = "kernel"
+netns = "tenant-a"
+"#
+        ))
+        .unwrap();
+        assert_eq!(interface.backend, Backend::Kernel);
+        assert_eq!(interface.netns.as_deref(), Some("tenant-a"));
+        assert_eq!(
+            interface.listen_addrs(),
+            vec!["0.0.0.0:51820".parse::<SocketAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_listen_addresses() {
+        let content = format!(
+            r#"
+name = "wg0"
+private_key = "{PRIVATE_KEY}"
+address = "10.8.0.1/24"
+listen_port = 51820
+listen = ["203.0.113.5", "203.0.113.5:443", "[2001:db8::1]:51820"]
+"#
+        );
+        let interface: InterfaceConfig = toml::from_str(&content).unwrap();
+        let addrs: Vec<String> = interface
+            .listen_addrs()
+            .iter()
+            .map(SocketAddr::to_string)
+            .collect();
+        assert_eq!(
+            addrs,
+            vec![
+                "203.0.113.5:51820",
+                "203.0.113.5:443",
+                "[2001:db8::1]:51820"
+            ]
+        );
+
+        let invalid = content.replace("\"203.0.113.5\",", "\"not-an-address\",");
+        let err = toml::from_str::<InterfaceConfig>(&invalid).unwrap_err();
+        assert!(err
+            .message()
+            .contains("Invalid listen address \"not-an-address\""));
+    }
+
+    #[test]
+    fn test_invalid_values_fail_at_load() {
+        let dir = env::temp_dir().join(format!("rusty-tunnel-values-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.toml");
+        let valid = format!(
+            r#"[interface]
+name = "wg0"
+private_key = "{PRIVATE_KEY}"
+address = "10.8.0.1/24"
+listen_port = 51820
+
+[[peers]]
+public_key = "{PEER_KEY}"
+allowed_ips = "10.8.0.2/32, fd00:8::2/128"
+endpoint = "vpn.example.com:51820"
+"#
+        );
+        let load = |content: &str| {
+            fs::write(&path, content).unwrap();
+            ServerConfig::load(&path, &Overrides::default()).map_err(|e| e.to_string())
+        };
+
+        let config = load(&valid).unwrap();
+        assert_eq!(config.peers[0].allowed_ips.len(), 2);
+        assert_eq!(config.version, SERVER_VERSION);
+        // 没有版本号的旧配置可以省略前缀，加载时迁移
+        let config = load(&valid.replace("10.8.0.2/32", "10.8.0.2")).unwrap();
+        assert_eq!(
+            config.peers[0].allowed_ips[0],
+            "10.8.0.2/32".parse::<IpNet>().unwrap()
+        );
+        let err = load(&format!(
+            "version = 2\n{}",
+            valid.replace("10.8.0.2/32", "10.8.0.2")
+        ));
+        assert!(err.unwrap_err().contains("Invalid CIDR \"10.8.0.2\""));
+        assert_eq!(
+            config.peers[0].endpoint,
+            Some(Endpoint::Host("vpn.example.com".to_string(), 51820))
+        );
+
+        let err = load(&valid.replace("fd00:8::2/128", "10.8.0.300/32")).unwrap_err();
+        assert!(err.contains("line 9, column 15"), "{}", err);
+        assert!(err.contains("Invalid CIDR \"10.8.0.300/32\""), "{}", err);
+        let err = load(&valid.replace(":51820\"", "\"")).unwrap_err();
+        assert!(err.contains("line 10"), "{}", err);
+        assert!(err.contains("expected host:port"), "{}", err);
+        let err = load(&valid.replace(PEER_KEY, "c2hvcnQ=")).unwrap_err();
+        assert!(err.contains("line 8"), "{}", err);
+        assert!(
+            err.contains("Public key must be 32 bytes, got 5"),
+            "{}",
+            err
+        );
+        let err = load(&valid.replace("10.8.0.1/24", "10.8.0.1")).unwrap_err();
+        assert!(err.contains("line 4"), "{}", err);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_overrides_and_redaction() {
+        let dir = env::temp_dir().join(format!("rusty-tunnel-overrides-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.toml");
+        fs::write(
+            &path,
+            format!(
+                "[interface]\nname = \"wg0\"\nprivate_key = \"{PRIVATE_KEY}\"\n\
+                 address = \"10.8.0.1/24\"\nlisten_port = 51820\n\n\
+                 [[peers]]\npublic_key = \"{PEER_KEY}\"\nallowed_ips = \"10.8.0.2/32\"\n"
+            ),
+        )
+        .unwrap();
+        let overrides = |items: &[&str]| {
+            let mut overrides = Overrides::default();
+            overrides.extend(items.iter().map(|item| item.parse().unwrap()));
+            overrides
+        };
+
+        let config = ServerConfig::load(
+            &path,
+            &overrides(&[
+                "interface.listen_port=443",
+                &format!("peers.0.psk={PRIVATE_KEY}"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.interface.listen_port, 443);
+        assert!(config.peers[0].psk.is_some());
+
+        let redacted = toml::to_string(&config.redacted().unwrap()).unwrap();
+        assert!(!redacted.contains(PRIVATE_KEY), "{}", redacted);
+        assert!(redacted.contains(PEER_KEY));
+        assert!(redacted.contains(r#"psk = "[redacted]""#), "{}", redacted);
+
+        // 覆盖的外部来源取代内联私钥，输出只保留来源
+        let environment = Environment::from_iter([("RT_TEST_KEY".into(), PRIVATE_KEY.into())]);
+        let config = ServerConfig::load_with_env(
+            &path,
+            &overrides(&["interface.private_key_env=RT_TEST_KEY"]),
+            &environment,
+        )
+        .unwrap();
+        assert!(config.interface.private_key.is_some());
+        let redacted = toml::to_string(&config.redacted().unwrap()).unwrap();
+        assert!(redacted.contains("private_key_env"), "{}", redacted);
+        assert!(!redacted.contains("private_key ="), "{}", redacted);
+
+        let err = ServerConfig::load(&path, &overrides(&["interface.listen_port=high"]))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("interface.listen_port"), "{}", err);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_external_key_sources() {
+        let dir = env::temp_dir().join(format!("rusty-tunnel-keys-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let key_file = dir.join("server.key");
+        let (file_key, _) = crypto::generate_keypair().unwrap();
+        let env_psk = crypto::generate_psk().unwrap();
+        let credential_psk = crypto::generate_psk().unwrap();
+        let (file_key_b64, env_psk_b64, credential_psk_b64) = (
+            file_key.to_base64(),
+            env_psk.to_base64(),
+            credential_psk.to_base64(),
+        );
+        fs::write(&key_file, format!("{}\n", file_key_b64.expose())).unwrap();
+        fs::set_permissions(&key_file, fs::Permissions::from_mode(0o600)).unwrap();
+        fs::write(dir.join("peer-psk"), credential_psk_b64.expose()).unwrap();
+        let (next_key, _) = crypto::generate_keypair().unwrap();
+        let next_key_b64 = next_key.to_base64();
+        fs::write(dir.join("next-key"), next_key_b64.expose()).unwrap();
+        let environment = |psk: &str| -> Environment {
+            [
+                ("CREDENTIALS_DIRECTORY", dir.display().to_string()),
+                ("RUSTY_TUNNEL_TEST_PSK", psk.to_string()),
+            ]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect()
+        };
+        let load = |path: &Path, environment: &Environment| {
+            ServerConfig::load_with_env(path, &Overrides::default(), environment)
+        };
+        let vars = environment(env_psk_b64.expose());
+
+        let config_path = dir.join("server.toml");
+        fs::write(
+            &config_path,
+            format!(
+                r#"
+[interface]
+name = "wg0"
+private_key_file = "{}"
+next_private_key_credential = "next-key"
+address = "10.8.0.1/24"
+listen_port = 51820
+
+[[peers]]
+public_key = "{}"
+allowed_ips = "10.8.0.2/32"
+psk_env = "RUSTY_TUNNEL_TEST_PSK"
+
+[[peers]]
+public_key = "{}"
+allowed_ips = "10.8.0.3/32"
+psk_credential = "peer-psk"
+"#,
+                key_file.display(),
+                PEER_KEY,
+                PublicKey::from([3u8; 32])
+            ),
+        )
+        .unwrap();
+
+        let config = load(&config_path, &vars).unwrap();
+        assert_eq!(config.interface.private_key, Some(file_key.clone()));
+        assert_eq!(config.interface.next_private_key, Some(next_key));
+        assert!(config.interface.clone().promote_next_key(0).is_err());
+        assert_eq!(config.peers[0].psk, Some(env_psk));
+        assert_eq!(config.peers[1].psk, Some(credential_psk));
+
+        // 调试输出不包含密钥
+        let debug = format!("{:?}", config);
+        assert!(!debug.contains(file_key_b64.expose()));
+        assert!(!debug.contains(env_psk_b64.expose()));
+        assert!(config.secret_paths().contains(&key_file));
+
+        // 外部来源的密钥不会写回配置文件
+        let saved = dir.join("saved.toml");
+        config.save(&saved).unwrap();
+        let content = fs::read_to_string(&saved).unwrap();
+        assert!(!content.contains(file_key_b64.expose()));
+        assert!(!content.contains(env_psk_b64.expose()));
+        assert!(!content.contains(credential_psk_b64.expose()));
+        assert!(!content.contains(next_key_b64.expose()));
+        assert_eq!(
+            load(&saved, &vars).unwrap().interface.private_key,
+            Some(file_key)
+        );
+
+        // 外部来源的密钥同样需要是有效的 32 字节 Base64
+        let err = load(&config_path, &environment("env_psk")).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Invalid psk from an external source"));
+
+        // 只读取传入的环境，不回落到进程环境
+        let err = load(&config_path, &Environment::from_iter([])).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("next_private_key_credential requires CREDENTIALS_DIRECTORY"));
+
+        // 组或其他用户可读的密钥文件被拒绝
+        fs::set_permissions(&key_file, fs::Permissions::from_mode(0o640)).unwrap();
+        let err = load(&config_path, &vars).unwrap_err();
+        assert!(err.to_string().contains("readable by group or others"));
+
+        // 其他用户拥有的密钥文件被拒绝
+        fs::set_permissions(&key_file, fs::Permissions::from_mode(0o600)).unwrap();
+        if Uid::effective().is_root() {
+            nix::unistd::chown(&key_file, Some(Uid::from_raw(65534)), None).unwrap();
+            let err = load(&config_path, &vars).unwrap_err();
+            assert!(err.to_string().contains("is owned by uid 65534"), "{}", err);
+            nix::unistd::chown(&key_file, Some(Uid::from_raw(0)), None).unwrap();
+        }
+
+        // 凭据名不能指向凭据目录之外
+        let content = fs::read_to_string(&config_path).unwrap();
+        fs::write(
+            &config_path,
+            content.replace("\"peer-psk\"", "\"../server.key\""),
+        )
+        .unwrap();
+        let err = load(&config_path, &vars).unwrap_err();
+        assert!(
+            err.to_string().contains("Invalid psk_credential"),
+            "{}",
+            err
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resolve_endpoints() {
+        let mut peer = PeerConfig {
+            endpoint: Some(Endpoint::Host("localhost".to_string(), 51820)),
+            pq_endpoint: Some("192.0.2.1:51821".parse().unwrap()),
+            ..Default::default()
+        };
+        // 未解析前不查询 DNS
+        assert_eq!(peer.endpoint_addr(), None);
+        assert_eq!(
+            peer.pq_endpoint_addr(),
+            Some("192.0.2.1:51821".parse().unwrap())
+        );
+
+        peer.resolve_endpoints().await;
+        let addr = peer.endpoint_addr().unwrap();
+        assert!(addr.ip().is_loopback());
+        assert_eq!(addr.port(), 51820);
+        assert_eq!(peer.resolved_pq_endpoint, None);
+    }
+
+    #[test]
+    fn test_peer_changes() {
+        let base = toml::from_str::<PeerFile>(&format!(
+            "[[peers]]\npublic_key = \"{PEER_KEY}\"\nallowed_ips = \"10.8.0.2/32\"\n"
+        ))
+        .unwrap()
+        .peers
+        .remove(0);
+        let peer = |key: u8, ip: &str| PeerConfig {
+            public_key: PublicKey::from([key; 32]),
+            allowed_ips: vec![ip.parse().unwrap()],
+            ..base.clone()
+        };
+
+        let old = vec![
+            peer(1, "10.8.0.2/32"),
+            peer(2, "10.8.0.3/32"),
+            peer(3, "10.8.0.4/32"),
+        ];
+        let new = vec![
+            peer(4, "10.8.0.5/32"),
+            peer(2, "10.8.0.6/32"),
+            peer(1, "10.8.0.2/32"),
+        ];
+        let changes = PeerChanges::between(&old, &new);
+        assert_eq!(changes.added, vec![&new[0]]);
+        assert_eq!(changes.changed, vec![&new[1]]);
+        assert_eq!(changes.removed, vec![&old[2]]);
+        assert!(PeerChanges::between(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn test_peers_dir() {
+        let dir = env::temp_dir().join(format!("rusty-tunnel-peers-dir-{}", std::process::id()));
+        let peers = dir.join("peers.d");
+        fs::create_dir_all(&peers).unwrap();
+        let path = dir.join("server.toml");
+        fs::write(
+            &path,
+            format!(
+                r#"peers_dir = "peers.d"
+
+[interface]
+name = "wg0"
+private_key = "{PRIVATE_KEY}"
+address = "10.8.0.1/24"
+listen_port = 51820
+"#
+            ),
+        )
+        .unwrap();
+        let alice = format!(
+            "[[peers]]\nname = \"alice\"\npublic_key = \"{PEER_KEY}\"\n\
+             allowed_ips = \"10.8.0.2/32\"\n"
+        );
+        fs::write(peers.join("alice.toml"), &alice).unwrap();
+        fs::write(peers.join(".alice.toml.swp"), "ignored").unwrap();
+        fs::write(peers.join("README"), "ignored").unwrap();
+
+        let mut config = ServerConfig::load(&path, &Overrides::default()).unwrap();
+        assert_eq!(config.peers.len(), 1);
+        assert_eq!(config.peers[0].source, Some(peers.join("alice.toml")));
+
+        // 对等体写回各自的文件，主配置文件中不出现
+        config.peers[0].persistent_keepalive = Some(25);
+        config.save(&path).unwrap();
+        assert!(!fs::read_to_string(&path).unwrap().contains("[[peers]]"));
+        assert!(fs::read_to_string(peers.join("alice.toml"))
+            .unwrap()
+            .contains("persistent_keepalive = 25"));
+        let config = ServerConfig::load(&path, &Overrides::default()).unwrap();
+        assert_eq!(config.peers[0].persistent_keepalive, Some(25));
+
+        fs::write(peers.join("bob.toml"), alice.replace("alice", "bob")).unwrap();
+        let err = ServerConfig::load(&path, &Overrides::default())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Duplicate public key"), "{}", err);
+        assert!(
+            err.contains("alice.toml") && err.contains("bob.toml"),
+            "{}",
+            err
+        );
+        fs::write(peers.join("bob.toml"), "[interface]\nname = \"wg1\"\n").unwrap();
+        let err = ServerConfig::load(&path, &Overrides::default())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("unknown field `interface`"), "{}", err);
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -1,52 +1,74 @@
 use crate::error::{Error, Result};
+use crate::secret::SecretKey;
+use crate::types::PublicKey;
 use base64::{engine::general_purpose::STANDARD, Engine};
 use rand::rngs::OsRng;
-use x25519_dalek::{PublicKey, StaticSecret};
+use rand::RngCore;
+use x25519_dalek::StaticSecret;
+use zeroize::{Zeroize, Zeroizing};
 
 /// 生成 WireGuard 密钥对
-pub fn generate_keypair() -> Result<(String, String)> {
-    let mut rng = OsRng;
-    let private_key = StaticSecret::random_from_rng(&mut rng);
-    let public_key = PublicKey::from(&private_key);
+pub fn generate_keypair() -> Result<(SecretKey, PublicKey)> {
+    let private_key = SecretKey::from(StaticSecret::random_from_rng(OsRng).to_bytes());
+    let public_key = public_key(&private_key);
+    Ok((private_key, public_key))
+}
 
-    let private_key_b64 = STANDARD.encode(private_key.as_bytes());
-    let public_key_b64 = STANDARD.encode(public_key.as_bytes());
+/// 由私钥计算公钥
+pub fn public_key(private_key: &SecretKey) -> PublicKey {
+    let private_key = StaticSecret::from(*private_key.as_bytes());
+    PublicKey::from(x25519_dalek::PublicKey::from(&private_key).to_bytes())
+}
 
-    Ok((private_key_b64, public_key_b64))
+/// 生成预共享密钥
+pub fn generate_psk() -> Result<SecretKey> {
+    let mut psk = Zeroizing::new([0u8; 32]);
+    OsRng
+        .try_fill_bytes(&mut psk[..])
+        .map_err(|e| Error::CryptoError(format!("Failed to generate preshared key: {}", e)))?;
+    Ok(SecretKey::from(*psk))
 }
 
 /// 从 Base64 编码的字符串解码私钥
-pub fn decode_private_key(encoded: &str) -> Result<[u8; 32]> {
-    let decoded = STANDARD
-        .decode(encoded)
-        .map_err(|e| Error::CryptoError(format!("Failed to decode private key: {}", e)))?;
-
-    if decoded.len() != 32 {
-        return Err(Error::CryptoError(
-            "Private key must be 32 bytes".to_string(),
-        ));
-    }
-
-    let mut key = [0u8; 32];
-    key.copy_from_slice(&decoded);
-    Ok(key)
+pub fn decode_private_key(encoded: &str) -> Result<SecretKey> {
+    decode_key(encoded, "private key", "Private key")
 }
 
 /// 从 Base64 编码的字符串解码公钥
 pub fn decode_public_key(encoded: &str) -> Result<[u8; 32]> {
+    decode_key(encoded, "public key", "Public key").map(|key| *key.as_bytes())
+}
+
+/// 从 Base64 编码的字符串解码预共享密钥
+pub fn decode_psk(encoded: &str) -> Result<SecretKey> {
+    decode_key(encoded, "preshared key", "Preshared key")
+}
+
+/// 从 Base64 编码的字符串解码用途不限的 32 字节密钥
+pub fn decode_secret(encoded: &str) -> Result<SecretKey> {
+    decode_key(encoded, "key", "Key")
+}
+
+/// 解码 32 字节的 Base64 密钥，中间缓冲区在返回前清零
+fn decode_key(encoded: &str, name: &str, title: &str) -> Result<SecretKey> {
     let decoded = STANDARD
         .decode(encoded)
-        .map_err(|e| Error::CryptoError(format!("Failed to decode public key: {}", e)))?;
+        .map(Zeroizing::new)
+        .map_err(|e| Error::CryptoError(format!("Failed to decode {}: {}", name, e)))?;
 
     if decoded.len() != 32 {
-        return Err(Error::CryptoError(
-            "Public key must be 32 bytes".to_string(),
-        ));
+        return Err(Error::CryptoError(format!(
+            "{} must be 32 bytes, got {}",
+            title,
+            decoded.len()
+        )));
     }
 
     let mut key = [0u8; 32];
     key.copy_from_slice(&decoded);
-    Ok(key)
+    let secret = SecretKey::from(key);
+    key.zeroize();
+    Ok(secret)
 }
 
 #[cfg(test)]
@@ -56,18 +78,40 @@ mod tests {
     #[test]
     fn test_generate_keypair() {
         let (priv_key, pub_key) = generate_keypair().unwrap();
-        assert!(!priv_key.is_empty());
-        assert!(!pub_key.is_empty());
-        assert_ne!(priv_key, pub_key);
+        assert_ne!(priv_key.as_bytes(), pub_key.as_bytes());
+        assert_eq!(public_key(&priv_key), pub_key);
     }
 
     #[test]
     fn test_decode_keys() {
         let (priv_key, pub_key) = generate_keypair().unwrap();
-        let priv_decoded = decode_private_key(&priv_key).unwrap();
-        let pub_decoded = decode_public_key(&pub_key).unwrap();
+        let priv_decoded = decode_private_key(priv_key.to_base64().expose()).unwrap();
+        let pub_decoded = decode_public_key(&pub_key.to_string()).unwrap();
+
+        assert_eq!(priv_decoded, priv_key);
+        assert_eq!(&pub_decoded, pub_key.as_bytes());
 
-        assert_eq!(priv_decoded.len(), 32);
-        assert_eq!(pub_decoded.len(), 32);
+        let psk = generate_psk().unwrap();
+        assert_eq!(decode_psk(psk.to_base64().expose()).unwrap(), psk);
+        let short = STANDARD.encode([0u8; 16]);
+        let err = decode_psk(&short).unwrap_err().to_string();
+        assert!(err.contains("Preshared key must be 32 bytes, got 16"));
+    }
+
+    #[test]
+    fn test_public_key_derivation() {
+        // RFC 7748 第 6.1 节的测试向量
+        let private_key = SecretKey::from([
+            0x77, 0x07, 0x6d, 0x0a, 0x73, 0x18, 0xa5, 0x7d, 0x3c, 0x16, 0xc1, 0x72, 0x51, 0xb2,
+            0x66, 0x45, 0xdf, 0x4c, 0x2f, 0x87, 0xeb, 0xc0, 0x99, 0x2a, 0xb1, 0x77, 0xfb, 0xa5,
+            0x1d, 0xb9, 0x2c, 0x2a,
+        ]);
+        let public_key = PublicKey::from([
+            0x85, 0x20, 0xf0, 0x09, 0x89, 0x30, 0xa7, 0x54, 0x74, 0x8b, 0x7d, 0xdc, 0xb4, 0x3e,
+            0xf7, 0x5a, 0x0d, 0xbf, 0x3a, 0x0d, 0x26, 0x38, 0x1a, 0xf4, 0xeb, 0xa4, 0xa9, 0x8e,
+            0xaa, 0x9b, 0x4e, 0x6a,
+        ]);
+        assert_eq!(self::public_key(&private_key), public_key);
+        assert!(decode_private_key("not a key").is_err());
     }
 }
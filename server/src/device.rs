@@ -1,62 +1,171 @@
 use crate::error::{Error, Result};
+use crate::netns;
+use async_trait::async_trait;
+use ipnet::IpNet;
+use log::info;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{FromRawFd, IntoRawFd};
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex as StdMutex, OnceLock};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::{mpsc, Mutex};
+
+/// 数据包设备：承载明文 IP 数据包的接口
+///
+/// 生命周期方法对应 `ip` 命令的操作，数据面通过 `recv`/`send` 读写单个数据包。
+#[async_trait]
+pub trait Device: Send + Sync {
+    /// 设备名称
+    fn name(&self) -> &str;
+    /// 创建设备
+    fn create(&self) -> Result<()>;
+    /// 将设备移入配置的网络命名空间
+    fn move_to_namespace(&self) -> Result<()>;
+    /// 删除设备，并清理为其创建的网络命名空间
+    fn destroy(&self) -> Result<()>;
+    /// 启用设备
+    fn up(&self) -> Result<()>;
+    /// 禁用设备
+    fn down(&self) -> Result<()>;
+    /// 配置 IP 地址
+    fn set_address(&self) -> Result<()>;
+    /// 删除 IP 地址
+    fn remove_address(&self) -> Result<()>;
+    /// 添加路由
+    fn add_route(&self, route: &IpNet) -> Result<()>;
+    /// 删除路由
+    fn remove_route(&self, route: &IpNet) -> Result<()>;
+    /// 启用 IP 转发，`ipv6` 为真时同时启用 IPv6 转发
+    fn enable_forwarding(&self, ipv6: bool) -> Result<()>;
+    /// 读取一个数据包
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
+    /// 写入一个数据包
+    async fn send(&self, packet: &[u8]) -> io::Result<usize>;
+}
 
 /// TUN 设备管理器
 pub struct TunDevice {
     /// 设备名称
     pub name: String,
     /// 设备地址
-    pub address: String,
+    pub address: IpNet,
+    /// 设备所在的网络命名空间（可选）
+    pub netns: Option<String>,
+    /// 设备 MTU（可选）
+    pub mtu: Option<u16>,
+    /// 命名空间是否由本程序创建
+    created_netns: AtomicBool,
+    /// TUN 文件描述符，创建设备后可用
+    tun: OnceLock<AsyncFd<File>>,
 }
 
 impl TunDevice {
     /// 创建新的 TUN 设备
-    pub fn new(name: &str, address: &str) -> Self {
+    pub fn new(name: &str, address: IpNet) -> Self {
         TunDevice {
             name: name.to_string(),
-            address: address.to_string(),
+            address,
+            netns: None,
+            mtu: None,
+            created_netns: AtomicBool::new(false),
+            tun: OnceLock::new(),
+        }
+    }
+
+    /// 创建位于指定网络命名空间中的 TUN 设备
+    pub fn with_netns(name: &str, address: IpNet, netns: Option<String>) -> Self {
+        TunDevice {
+            netns,
+            ..Self::new(name, address)
+        }
+    }
+
+    /// 启用设备时设置 MTU
+    pub fn with_mtu(mut self, mtu: Option<u16>) -> Self {
+        self.mtu = mtu;
+        self
+    }
+
+    /// 打开 TUN 接口
+    pub fn open(&self) -> Result<()> {
+        if self.tun.get().is_some() {
+            return Ok(());
         }
+
+        let mut config = tun::Configuration::default();
+        config.name(&self.name);
+        config.platform(|config| {
+            config.packet_information(false);
+        });
+
+        let device = tun::create(&config)
+            .map_err(|e| Error::DeviceError(format!("Failed to create TUN device: {}", e)))?;
+        device
+            .set_nonblock()
+            .map_err(|e| Error::DeviceError(format!("Failed to set nonblocking: {}", e)))?;
+
+        // 文件描述符关闭时接口随之删除。
+        // SAFETY: 描述符来自刚创建的 TUN 设备，所有权转移给 File，由 AsyncFd 独占。
+        let file = unsafe { File::from_raw_fd(device.into_raw_fd()) };
+        let fd = unsafe { AsyncFd::register(file) }
+            .map_err(|e| Error::DeviceError(format!("Failed to register TUN device: {}", e)))?;
+        let _ = self.tun.set(fd);
+        Ok(())
+    }
+
+    fn fd(&self) -> io::Result<&AsyncFd<File>> {
+        self.tun
+            .get()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "TUN device not open"))
     }
 
     /// 启用设备
     pub fn up(&self) -> Result<()> {
-        self.run_command(&["ip", "link", "set", "dev", &self.name, "up"])?;
+        if let Some(mtu) = self.mtu {
+            self.ip(&["link", "set", "dev", &self.name, "mtu", &mtu.to_string()])?;
+        }
+        self.ip(&["link", "set", "dev", &self.name, "up"])?;
         Ok(())
     }
 
     /// 禁用设备
     pub fn down(&self) -> Result<()> {
-        self.run_command(&["ip", "link", "set", "dev", &self.name, "down"])?;
+        self.ip(&["link", "set", "dev", &self.name, "down"])?;
         Ok(())
     }
 
     /// 配置 IP 地址
     pub fn set_address(&self) -> Result<()> {
-        self.run_command(&["ip", "addr", "add", &self.address, "dev", &self.name])?;
+        self.ip(&["addr", "add", &self.address.to_string(), "dev", &self.name])?;
         Ok(())
     }
 
     /// 删除 IP 地址
     pub fn remove_address(&self) -> Result<()> {
-        self.run_command(&["ip", "addr", "del", &self.address, "dev", &self.name])?;
+        self.ip(&["addr", "del", &self.address.to_string(), "dev", &self.name])?;
         Ok(())
     }
 
     /// 添加路由
-    pub fn add_route(&self, route: &str) -> Result<()> {
-        self.run_command(&["ip", "route", "add", route, "dev", &self.name])?;
+    pub fn add_route(&self, route: &IpNet) -> Result<()> {
+        self.ip(&["route", "add", &route.to_string(), "dev", &self.name])?;
         Ok(())
     }
 
     /// 删除路由
-    pub fn remove_route(&self, route: &str) -> Result<()> {
-        self.run_command(&["ip", "route", "del", route, "dev", &self.name])?;
+    pub fn remove_route(&self, route: &IpNet) -> Result<()> {
+        self.ip(&["route", "del", &route.to_string(), "dev", &self.name])?;
         Ok(())
     }
 
-    /// 启用 IP 转发
-    pub fn enable_forwarding() -> Result<()> {
+    /// 启用 IP 转发，`ipv6` 为真时同时启用 IPv6 转发
+    pub fn enable_forwarding(ipv6: bool) -> Result<()> {
         Self::run_sysctl("net.ipv4.ip_forward", "1")?;
+        if ipv6 {
+            Self::run_sysctl("net.ipv6.conf.all.forwarding", "1")?;
+        }
         Ok(())
     }
 
@@ -66,6 +175,48 @@ impl TunDevice {
         Ok(())
     }
 
+    /// 将设备移入网络命名空间，命名空间不存在时创建
+    pub fn move_to_namespace(&self) -> Result<()> {
+        let Some(ns) = &self.netns else {
+            return Ok(());
+        };
+
+        netns::validate_name(ns)?;
+        if !netns::exists(ns) {
+            netns::create(ns)?;
+            self.created_netns.store(true, Ordering::SeqCst);
+        }
+
+        info!("Moving {} into network namespace {}", self.name, ns);
+        self.run_command(&["ip", "link", "set", "dev", &self.name, "netns", ns])?;
+        Ok(())
+    }
+
+    /// 删除命名空间中的设备（连同其地址和路由），并删除本程序创建的命名空间
+    pub fn destroy(&self) -> Result<()> {
+        let Some(ns) = &self.netns else {
+            return Ok(());
+        };
+
+        if self.ip(&["link", "show", "dev", &self.name]).is_ok() {
+            self.ip(&["link", "del", "dev", &self.name])?;
+        }
+        if self.created_netns.swap(false, Ordering::SeqCst) {
+            netns::delete(ns)?;
+        }
+        Ok(())
+    }
+
+    /// 在设备所在的命名空间中运行 ip 命令
+    fn ip(&self, args: &[&str]) -> Result<()> {
+        let mut command = vec!["ip"];
+        if let Some(ns) = &self.netns {
+            command.extend(["-n", ns.as_str()]);
+        }
+        command.extend_from_slice(args);
+        self.run_command(&command)
+    }
+
     /// 运行系统命令
     fn run_command(&self, args: &[&str]) -> Result<()> {
         let output = Command::new(args[0])
@@ -75,10 +226,7 @@ impl TunDevice {
 
         if !output.status.success() {
             let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(Error::DeviceError(format!(
-                "Command failed: {}",
-                stderr
-            )));
+            return Err(Error::DeviceError(format!("Command failed: {}", stderr)));
         }
 
         Ok(())
@@ -87,30 +235,249 @@ impl TunDevice {
     /// 运行 sysctl 命令
     fn run_sysctl(key: &str, value: &str) -> Result<()> {
         let output = Command::new("sysctl")
-            .args(&["-w", &format!("{}={}", key, value)])
+            .args(["-w", &format!("{}={}", key, value)])
             .output()
             .map_err(|e| Error::DeviceError(format!("Failed to run sysctl: {}", e)))?;
 
         if !output.status.success() {
             let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(Error::DeviceError(format!(
-                "sysctl failed: {}",
-                stderr
-            )));
+            return Err(Error::DeviceError(format!("sysctl failed: {}", stderr)));
         }
 
         Ok(())
     }
 }
 
+#[async_trait]
+impl Device for TunDevice {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn create(&self) -> Result<()> {
+        self.open()
+    }
+
+    fn move_to_namespace(&self) -> Result<()> {
+        TunDevice::move_to_namespace(self)
+    }
+
+    fn destroy(&self) -> Result<()> {
+        TunDevice::destroy(self)
+    }
+
+    fn up(&self) -> Result<()> {
+        TunDevice::up(self)
+    }
+
+    fn down(&self) -> Result<()> {
+        TunDevice::down(self)
+    }
+
+    fn set_address(&self) -> Result<()> {
+        TunDevice::set_address(self)
+    }
+
+    fn remove_address(&self) -> Result<()> {
+        TunDevice::remove_address(self)
+    }
+
+    fn add_route(&self, route: &IpNet) -> Result<()> {
+        TunDevice::add_route(self, route)
+    }
+
+    fn remove_route(&self, route: &IpNet) -> Result<()> {
+        TunDevice::remove_route(self, route)
+    }
+
+    fn enable_forwarding(&self, ipv6: bool) -> Result<()> {
+        let Some(ns) = &self.netns else {
+            return TunDevice::enable_forwarding(ipv6);
+        };
+        let mut command = vec!["ip", "netns", "exec", ns, "sysctl", "-w"];
+        command.push("net.ipv4.ip_forward=1");
+        if ipv6 {
+            command.push("net.ipv6.conf.all.forwarding=1");
+        }
+        self.run_command(&command)
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let fd = self.fd()?;
+        loop {
+            let mut guard = fd.readable().await?;
+            match guard.try_io(|inner| inner.get_ref().read(buf)) {
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    async fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        let fd = self.fd()?;
+        loop {
+            let mut guard = fd.writable().await?;
+            match guard.try_io(|inner| inner.get_ref().write(packet)) {
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+/// 内存设备：通过通道收发数据包，用于测试
+///
+/// 生命周期方法均为空操作，不需要 root 权限；路由只记录在内存中。
+pub struct MemoryDevice {
+    /// 设备名称
+    name: String,
+    /// 注入到设备的数据包（相当于本机发往隧道的流量）
+    inbound: Mutex<mpsc::UnboundedReceiver<Vec<u8>>>,
+    /// 服务器写入设备的数据包
+    outbound: mpsc::UnboundedSender<Vec<u8>>,
+    /// 设备上的路由
+    routes: Arc<StdMutex<MemoryRoutes>>,
+}
+
+/// 内存设备的路由表
+#[derive(Debug, Default)]
+pub struct MemoryRoutes {
+    /// 已添加的路由
+    pub installed: Vec<IpNet>,
+    /// 添加时返回错误的路由，用于模拟失败
+    pub rejected: Vec<IpNet>,
+}
+
+/// 内存设备的另一端
+pub struct MemoryDeviceHandle {
+    /// 向设备注入数据包
+    pub inject: mpsc::UnboundedSender<Vec<u8>>,
+    /// 接收服务器写入设备的数据包
+    pub received: mpsc::UnboundedReceiver<Vec<u8>>,
+    /// 设备上的路由
+    pub routes: Arc<StdMutex<MemoryRoutes>>,
+}
+
+impl MemoryDevice {
+    /// 创建内存设备及其控制端
+    pub fn new(name: &str) -> (Self, MemoryDeviceHandle) {
+        let (inject, inbound) = mpsc::unbounded_channel();
+        let (outbound, received) = mpsc::unbounded_channel();
+        let routes = Arc::new(StdMutex::new(MemoryRoutes::default()));
+        let device = MemoryDevice {
+            name: name.to_string(),
+            inbound: Mutex::new(inbound),
+            outbound,
+            routes: routes.clone(),
+        };
+        let handle = MemoryDeviceHandle {
+            inject,
+            received,
+            routes,
+        };
+        (device, handle)
+    }
+}
+
+#[async_trait]
+impl Device for MemoryDevice {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn create(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn move_to_namespace(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn destroy(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn up(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn down(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_address(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn remove_address(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn add_route(&self, route: &IpNet) -> Result<()> {
+        let mut routes = self.routes.lock().unwrap();
+        if routes.rejected.contains(route) {
+            return Err(Error::DeviceError(format!("Failed to add route {}", route)));
+        }
+        routes.installed.push(*route);
+        Ok(())
+    }
+
+    fn remove_route(&self, route: &IpNet) -> Result<()> {
+        self.routes.lock().unwrap().installed.retain(|r| r != route);
+        Ok(())
+    }
+
+    fn enable_forwarding(&self, _ipv6: bool) -> Result<()> {
+        Ok(())
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let packet = self
+            .inbound
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "device closed"))?;
+        let len = packet.len().min(buf.len());
+        buf[..len].copy_from_slice(&packet[..len]);
+        Ok(len)
+    }
+
+    async fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        self.outbound
+            .send(packet.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "device closed"))?;
+        Ok(packet.len())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_tun_device_creation() {
-        let device = TunDevice::new("wg0", "10.8.0.1/24");
+        let address: IpNet = "10.8.0.1/24".parse().unwrap();
+        let device = TunDevice::new("wg0", address);
         assert_eq!(device.name, "wg0");
-        assert_eq!(device.address, "10.8.0.1/24");
+        assert_eq!(device.address, address);
+        assert_eq!(device.netns, None);
+
+        let device = TunDevice::with_netns("wg0", address, Some("tenant-a".to_string()));
+        assert_eq!(device.netns.as_deref(), Some("tenant-a"));
+    }
+
+    #[tokio::test]
+    async fn test_memory_device() {
+        let (device, mut handle) = MemoryDevice::new("mem0");
+        handle.inject.send(vec![1, 2, 3]).unwrap();
+
+        let mut buf = [0u8; 16];
+        let len = device.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], &[1, 2, 3]);
+
+        device.send(&[4, 5]).await.unwrap();
+        assert_eq!(handle.received.recv().await.unwrap(), vec![4, 5]);
     }
 }
@@ -7,6 +7,8 @@ pub enum Error {
     CryptoError(String),
     DeviceError(String),
     NetworkError(String),
+    FirewallError(String),
+    SecurityError(String),
     Other(String),
 }
 
@@ -18,6 +20,8 @@ impl fmt::Display for Error {
             Error::CryptoError(e) => write!(f, "Crypto Error: {}", e),
             Error::DeviceError(e) => write!(f, "Device Error: {}", e),
             Error::NetworkError(e) => write!(f, "Network Error: {}", e),
+            Error::FirewallError(e) => write!(f, "Firewall Error: {}", e),
+            Error::SecurityError(e) => write!(f, "Security Error: {}", e),
             Error::Other(e) => write!(f, "Error: {}", e),
         }
     }
@@ -25,6 +29,22 @@ impl fmt::Display for Error {
 
 impl std::error::Error for Error {}
 
+impl Error {
+    /// 不带错误类别前缀的错误信息
+    pub fn detail(&self) -> String {
+        match self {
+            Error::ConfigError(message)
+            | Error::CryptoError(message)
+            | Error::DeviceError(message)
+            | Error::NetworkError(message)
+            | Error::FirewallError(message)
+            | Error::SecurityError(message)
+            | Error::Other(message) => message.clone(),
+            Error::IoError(e) => e.to_string(),
+        }
+    }
+}
+
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Self {
         Error::IoError(err)
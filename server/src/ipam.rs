@@ -0,0 +1,476 @@
+use crate::config::{self, PeerConfig, ServerConfig};
+use crate::error::{Error, Result};
+use crate::types::PublicKey;
+use ipnet::IpNet;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+/// 状态目录中保存地址池的文件名
+pub const STATE_FILE: &str = "ipam.json";
+
+/// 分配给对等体的地址范围
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Allocation {
+    /// 地址范围，自动分配的为 /32 或 /128
+    pub network: IpNet,
+    /// 所属对等体的公钥
+    pub owner: PublicKey,
+}
+
+/// 持久化的地址池状态
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PoolState {
+    #[serde(default)]
+    reserved: Vec<IpNet>,
+    #[serde(default)]
+    allocations: Vec<Allocation>,
+}
+
+/// 隧道地址池：跟踪接口子网中已分配和保留的地址
+///
+/// 对等体 `allowed_ips` 中落在子网内的范围视为已分配；子网外的路由（如 `0.0.0.0/0`）
+/// 以及包含接口地址的路由（如客户端经服务器访问整个隧道子网）不占用地址。
+#[derive(Debug, Clone)]
+pub struct AddressPool {
+    /// 地址池的子网（IPv4 和/或 IPv6）
+    networks: Vec<IpNet>,
+    /// 接口自身的地址，始终保留
+    interface: Vec<IpAddr>,
+    /// 手动保留的范围
+    reserved: Vec<IpNet>,
+    /// 配置 `ipam.reserved` 中保留的范围，每次加载时从配置读取，不写入状态文件
+    configured: Vec<IpNet>,
+    /// 已分配给对等体的范围
+    allocations: Vec<Allocation>,
+    /// 状态文件路径（可选）
+    path: Option<PathBuf>,
+}
+
+impl AddressPool {
+    /// 以接口地址（如 `10.8.0.1/24`、`fd00::1/64`）创建空地址池，接口地址本身保留
+    pub fn new(addresses: &[IpNet]) -> Self {
+        AddressPool {
+            networks: addresses.iter().map(IpNet::trunc).collect(),
+            interface: addresses.iter().map(IpNet::addr).collect(),
+            reserved: Vec::new(),
+            configured: Vec::new(),
+            allocations: Vec::new(),
+            path: None,
+        }
+    }
+
+    /// 根据服务器配置创建地址池，子网为接口地址和 `ipam.pools`
+    ///
+    /// 配置了 `security.state_dir` 时读取其中保存的状态，然后与对等体配置同步，
+    /// 最后保留 `ipam.reserved` 中的范围。
+    pub fn from_config(config: &ServerConfig) -> Result<Self> {
+        let ipam = config.ipam.clone().unwrap_or_default();
+        let mut addresses = vec![config.interface.address];
+        addresses.extend(ipam.pools);
+        let mut pool = AddressPool::new(&addresses);
+        if let Some(dir) = config.security.as_ref().and_then(|s| s.state_dir.as_ref()) {
+            pool.path = Some(Path::new(dir).join(STATE_FILE));
+            pool.load()?;
+        }
+        pool.sync_peers(&config.peers)?;
+        for network in ipam.reserved {
+            pool.check_available(&network, None).map_err(|e| {
+                Error::ConfigError(format!("Invalid ipam.reserved: {}", e.detail()))
+            })?;
+            pool.configured.push(network);
+        }
+        Ok(pool)
+    }
+
+    /// 读取状态文件，文件不存在时保持为空
+    fn load(&mut self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => {
+                return Err(Error::ConfigError(format!(
+                    "Failed to read {:?}: {}",
+                    path, e
+                )))
+            }
+        };
+        let state: PoolState = serde_json::from_str(&content).map_err(|e| {
+            Error::ConfigError(format!("Invalid address pool state {:?}: {}", path, e))
+        })?;
+
+        // 接口子网变更后，旧子网中的记录不再有效
+        self.reserved = state
+            .reserved
+            .into_iter()
+            .filter(|network| self.contains(network))
+            .collect();
+        self.allocations = state
+            .allocations
+            .into_iter()
+            .filter(|allocation| self.contains(&allocation.network))
+            .collect();
+        Ok(())
+    }
+
+    /// 将状态写入状态目录，未配置状态目录时不做任何事
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let state = PoolState {
+            reserved: self.reserved.clone(),
+            allocations: self.allocations.clone(),
+        };
+        let content = serde_json::to_string_pretty(&state)
+            .map_err(|e| Error::Other(format!("Failed to serialize address pool: {}", e)))?;
+        config::write_atomic(path, &content)
+            .map_err(|e| Error::ConfigError(format!("Failed to write {:?}: {}", path, e)))
+    }
+
+    /// 与对等体配置同步：记录对等体占用的范围，释放已删除对等体的地址
+    ///
+    /// 仍在配置中的对等体保留原有的分配，即使该范围已不在其 `allowed_ips` 中；
+    /// 与配置中其他对等体冲突的旧分配让位于配置。
+    /// 两个对等体的范围重叠，或与接口地址、保留范围重叠时返回错误，地址池保持不变。
+    pub fn sync_peers(&mut self, peers: &[PeerConfig]) -> Result<()> {
+        let previous = std::mem::take(&mut self.allocations);
+        if let Err(e) = self.claim_peers(peers) {
+            self.allocations = previous;
+            return Err(e);
+        }
+
+        for allocation in previous {
+            if !peers.iter().any(|p| p.public_key == allocation.owner) {
+                info!(
+                    "Released {} held by removed peer {}",
+                    allocation.network, allocation.owner
+                );
+            } else if self.allocations.contains(&allocation) {
+                continue;
+            } else if let Err(e) =
+                self.check_available(&allocation.network, Some(&allocation.owner))
+            {
+                info!(
+                    "Released the previous allocation of peer {}: {}",
+                    allocation.owner,
+                    e.detail()
+                );
+            } else {
+                self.allocations.push(allocation);
+            }
+        }
+        Ok(())
+    }
+
+    fn claim_peers(&mut self, peers: &[PeerConfig]) -> Result<()> {
+        for peer in peers {
+            for network in &peer.allowed_ips {
+                if self.contains(network) && !self.is_route(network) {
+                    self.claim(&peer.public_key, *network)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 为对等体记录指定的地址范围
+    pub fn claim(&mut self, owner: &PublicKey, network: IpNet) -> Result<()> {
+        self.check_available(&network, Some(owner))?;
+        let allocation = Allocation {
+            network,
+            owner: *owner,
+        };
+        if !self.allocations.contains(&allocation) {
+            self.allocations.push(allocation);
+        }
+        Ok(())
+    }
+
+    /// 为对等体在每个子网中分配下一个空闲的 /32 或 /128 地址，已有分配时直接返回
+    pub fn allocate(&mut self, owner: &PublicKey) -> Result<Vec<IpNet>> {
+        let mut allocated = Vec::new();
+        let mut new = Vec::new();
+        for network in &self.networks {
+            let existing = self
+                .allocations
+                .iter()
+                .find(|a| &a.owner == owner && network.contains(&a.network));
+            if let Some(existing) = existing {
+                allocated.push(existing.network);
+                continue;
+            }
+
+            let address = network
+                .hosts()
+                .find(|ip| *ip != network.network() && self.is_free(ip))
+                .ok_or_else(|| {
+                    Error::ConfigError(format!("No free address left in {}", network))
+                })?;
+            allocated.push(IpNet::from(address));
+            new.push(Allocation {
+                network: IpNet::from(address),
+                owner: *owner,
+            });
+        }
+        self.allocations.extend(new);
+        Ok(allocated)
+    }
+
+    /// 释放对等体的全部地址，返回被释放的范围
+    pub fn release(&mut self, owner: &PublicKey) -> Vec<IpNet> {
+        let (released, kept) = std::mem::take(&mut self.allocations)
+            .into_iter()
+            .partition::<Vec<_>, _>(|a| &a.owner == owner);
+        self.allocations = kept;
+        released.into_iter().map(|a| a.network).collect()
+    }
+
+    /// 保留地址范围，不再分配给对等体
+    pub fn reserve(&mut self, network: IpNet) -> Result<()> {
+        self.check_available(&network, None)?;
+        if !self.reserved.contains(&network) {
+            self.reserved.push(network);
+        }
+        Ok(())
+    }
+
+    /// 取消保留，范围未被保留时返回 false
+    pub fn unreserve(&mut self, network: &IpNet) -> bool {
+        let before = self.reserved.len();
+        self.reserved.retain(|r| r != network);
+        self.reserved.len() != before
+    }
+
+    /// 地址池的子网
+    pub fn networks(&self) -> &[IpNet] {
+        &self.networks
+    }
+
+    /// 已分配的范围
+    pub fn allocations(&self) -> &[Allocation] {
+        &self.allocations
+    }
+
+    /// 手动保留的范围
+    pub fn reserved(&self) -> &[IpNet] {
+        &self.reserved
+    }
+
+    /// 手动和配置中保留的全部范围
+    fn all_reserved(&self) -> impl Iterator<Item = &IpNet> {
+        self.reserved.iter().chain(&self.configured)
+    }
+
+    /// 范围是否落在地址池的某个子网内
+    fn contains(&self, network: &IpNet) -> bool {
+        self.networks.iter().any(|n| n.contains(network))
+    }
+
+    /// 是否为经对等体转发的路由：包含接口地址且不是单个地址
+    fn is_route(&self, network: &IpNet) -> bool {
+        network.prefix_len() < network.max_prefix_len()
+            && self.interface.iter().any(|ip| network.contains(ip))
+    }
+
+    /// 地址是否未被接口、保留范围或对等体占用
+    fn is_free(&self, ip: &IpAddr) -> bool {
+        !self.interface.contains(ip)
+            && !self.all_reserved().any(|r| r.contains(ip))
+            && !self.allocations.iter().any(|a| a.network.contains(ip))
+    }
+
+    /// 检查范围能否分配给 `owner`（为 None 时表示保留）
+    fn check_available(&self, network: &IpNet, owner: Option<&PublicKey>) -> Result<()> {
+        if !self.contains(network) {
+            return Err(Error::ConfigError(format!(
+                "{} is outside the address pool",
+                network
+            )));
+        }
+        if let Some(address) = self.interface.iter().find(|ip| network.contains(*ip)) {
+            return Err(Error::ConfigError(format!(
+                "{} overlaps the interface address {}",
+                network, address
+            )));
+        }
+        if let Some(reserved) = self.all_reserved().find(|r| overlaps(r, network)) {
+            if owner.is_some() {
+                return Err(Error::ConfigError(format!(
+                    "{} overlaps the reserved range {}",
+                    network, reserved
+                )));
+            }
+        }
+        let conflict = self
+            .allocations
+            .iter()
+            .find(|a| overlaps(&a.network, network) && Some(&a.owner) != owner);
+        if let Some(allocation) = conflict {
+            return Err(Error::ConfigError(format!(
+                "{} overlaps {} already allocated to peer {}",
+                network, allocation.network, allocation.owner
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// CIDR 范围要么互不相交，要么一个包含另一个
+fn overlaps(a: &IpNet, b: &IpNet) -> bool {
+    a.contains(b) || b.contains(a)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn net(s: &str) -> IpNet {
+        s.parse().unwrap()
+    }
+
+    fn key(n: u8) -> PublicKey {
+        PublicKey::from([n; 32])
+    }
+
+    fn server_config(state_dir: &Path, peers: &[(PublicKey, &str)]) -> ServerConfig {
+        let mut content = format!(
+            r#"
+[interface]
+name = "wg0"
+address = "10.8.0.1/29"
+listen_port = 51820
+
+[security]
+state_dir = "{}"
+"#,
+            state_dir.display()
+        );
+        for (public_key, allowed_ips) in peers {
+            content.push_str(&format!(
+                "\n[[peers]]\npublic_key = \"{}\"\nallowed_ips = \"{}\"\n",
+                public_key, allowed_ips
+            ));
+        }
+        toml::from_str(&content).unwrap()
+    }
+
+    #[test]
+    fn test_allocate_and_release_dual_stack() {
+        let [alice, bob, carol, dave, erin, frank] = [1, 2, 3, 4, 5, 6].map(key);
+        let mut pool = AddressPool::new(&[net("10.8.0.1/29"), net("fd00::1/125")]);
+        pool.reserve(net("10.8.0.2/32")).unwrap();
+
+        let addresses = pool.allocate(&alice).unwrap();
+        assert_eq!(addresses, vec![net("10.8.0.3/32"), net("fd00::2/128")]);
+        assert_eq!(pool.allocate(&alice).unwrap(), addresses);
+        assert_eq!(
+            pool.allocate(&bob).unwrap(),
+            vec![net("10.8.0.4/32"), net("fd00::3/128")]
+        );
+        assert!(pool.reserve(net("10.8.0.4/31")).is_err());
+        assert!(pool.claim(&carol, net("10.8.0.4/30")).is_err());
+        assert!(pool.claim(&carol, net("10.9.0.1/32")).is_err());
+
+        assert_eq!(pool.release(&alice), addresses);
+        assert_eq!(
+            pool.allocate(&carol).unwrap(),
+            vec![net("10.8.0.3/32"), net("fd00::2/128")]
+        );
+
+        // .5 和 .6 用完后 IPv4 子网耗尽，IPv6 地址不会被占用
+        pool.allocate(&dave).unwrap();
+        pool.allocate(&erin).unwrap();
+        let err = pool.allocate(&frank).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("No free address left in 10.8.0.0/29"));
+        assert!(pool.allocations().iter().all(|a| a.owner != frank));
+    }
+
+    #[test]
+    fn test_state_persistence_and_conflicts() {
+        let dir = std::env::temp_dir().join(format!("rusty-tunnel-ipam-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let [alice, bob, carol, gw, hub] = [1, 2, 3, 4, 5].map(key);
+
+        let peers = [(alice, "10.8.0.2/32"), (gw, "10.8.0.4/31, 0.0.0.0/0")];
+        let config = server_config(&dir, &peers);
+        let mut pool = AddressPool::from_config(&config).unwrap();
+        pool.reserve(net("10.8.0.3/32")).unwrap();
+        assert_eq!(pool.allocate(&bob).unwrap(), vec![net("10.8.0.6/32")]);
+        pool.save().unwrap();
+
+        // 重新加载后保留范围仍然有效，bob 不在配置中因而被释放
+        let mut pool = AddressPool::from_config(&config).unwrap();
+        assert_eq!(pool.reserved(), &[net("10.8.0.3/32")]);
+        assert_eq!(pool.allocate(&carol).unwrap(), vec![net("10.8.0.6/32")]);
+        pool.save().unwrap();
+
+        // 仍在配置中的 alice 保留原有的地址，已删除的 gw 和 carol 的地址被释放
+        let moved = server_config(&dir, &[(alice, "192.168.1.0/24")]);
+        let mut pool = AddressPool::from_config(&moved).unwrap();
+        assert_eq!(pool.allocate(&alice).unwrap(), vec![net("10.8.0.2/32")]);
+        assert_eq!(pool.allocate(&bob).unwrap(), vec![net("10.8.0.4/32")]);
+
+        let duplicate = server_config(&dir, &[(alice, "10.8.0.2/32"), (bob, "10.8.0.2/32")]);
+        let err = AddressPool::from_config(&duplicate).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains(&format!("already allocated to peer {}", alice)));
+        let interface = server_config(&dir, &[(alice, "10.8.0.1/32")]);
+        assert!(AddressPool::from_config(&interface).is_err());
+        let hub = server_config(&dir, &[(hub, "10.8.0.0/24")]);
+        assert!(AddressPool::from_config(&hub)
+            .unwrap()
+            .allocations()
+            .is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_dual_stack_from_config() {
+        let [alice, bob] = [1, 2].map(key);
+        let content = |reserved: &str| {
+            format!(
+                r#"
+[interface]
+name = "wg0"
+address = "10.8.0.1/29"
+listen_port = 51820
+
+[ipam]
+pools = "fd00::1/125"
+reserved = "{}"
+
+[[peers]]
+public_key = "{}"
+allowed_ips = "10.8.0.3/32, fd00::4/128"
+"#,
+                reserved, alice
+            )
+        };
+
+        let config: ServerConfig = toml::from_str(&content("10.8.0.2/32, fd00::2/127")).unwrap();
+        let mut pool = AddressPool::from_config(&config).unwrap();
+        assert_eq!(pool.networks(), &[net("10.8.0.0/29"), net("fd00::/125")]);
+        assert_eq!(pool.allocations().len(), 2);
+        assert_eq!(
+            pool.allocate(&bob).unwrap(),
+            vec![net("10.8.0.4/32"), net("fd00::5/128")]
+        );
+        assert!(pool.claim(&bob, net("fd00::3/128")).is_err());
+
+        // 保留范围与已配置的对等体冲突时拒绝加载
+        let config: ServerConfig = toml::from_str(&content("fd00::4/126")).unwrap();
+        let err = AddressPool::from_config(&config).unwrap_err();
+        assert!(err.to_string().contains("Invalid ipam.reserved"));
+    }
+}
@@ -0,0 +1,397 @@
+use crate::config::{PeerChanges, PeerConfig, ServerConfig};
+use crate::error::{Error, Result};
+use crate::netns;
+use crate::peer::{Peer, PeerStatus};
+use crate::types::PublicKey;
+use ipnet::IpNet;
+use log::info;
+use netlink_packet_core::{
+    NetlinkMessage, NetlinkPayload, NLM_F_ACK, NLM_F_CREATE, NLM_F_DUMP, NLM_F_EXCL, NLM_F_REQUEST,
+};
+use netlink_packet_generic::GenlMessage;
+use netlink_packet_route::link::{InfoKind, LinkAttribute, LinkInfo, LinkMessage};
+use netlink_packet_route::RouteNetlinkMessage;
+use netlink_packet_wireguard::constants::{
+    AF_INET, AF_INET6, WGDEVICE_F_REPLACE_PEERS, WGPEER_F_REMOVE_ME, WGPEER_F_REPLACE_ALLOWEDIPS,
+};
+use netlink_packet_wireguard::nlas::{
+    WgAllowedIp, WgAllowedIpAttrs, WgDeviceAttrs, WgPeer, WgPeerAttrs,
+};
+use netlink_packet_wireguard::{Wireguard, WireguardCmd};
+use netlink_request::{netlink_request_genl, netlink_request_rtnl};
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// 超过该时间未握手的对等体视为已断开（与 WireGuard 的 REJECT_AFTER_TIME 一致）
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// 单条 netlink 消息携带的对等体数量上限，避免超出缓冲区
+const PEERS_PER_MESSAGE: usize = 32;
+
+/// 内核报告的对等体计数器
+#[derive(Debug, Clone)]
+pub struct KernelPeerStats {
+    /// 公钥
+    pub public_key: PublicKey,
+    /// 最近一次通信的端点
+    pub endpoint: Option<SocketAddr>,
+    /// 最后握手时间
+    pub last_handshake: Option<SystemTime>,
+    /// 接收字节数
+    pub rx_bytes: u64,
+    /// 发送字节数
+    pub tx_bytes: u64,
+}
+
+/// 内核 WireGuard 设备
+///
+/// 通过 rtnetlink 创建 `wireguard` 类型的链路，通过 generic netlink 下发密钥、对等体和允许的 IP。
+pub struct KernelDevice {
+    /// 接口名称
+    name: String,
+    /// 链路所在的网络命名空间（可选）
+    netns: Option<String>,
+}
+
+impl KernelDevice {
+    /// 创建内核设备句柄（不会立即创建链路）
+    pub fn new(name: &str, netns: Option<String>) -> Self {
+        KernelDevice {
+            name: name.to_string(),
+            netns,
+        }
+    }
+
+    /// 检查内核是否加载了 wireguard 模块
+    pub fn is_available() -> bool {
+        Path::new("/sys/module/wireguard").exists()
+    }
+
+    /// 下发完整配置，替换已有的对等体
+    pub fn configure(&self, config: &ServerConfig) -> Result<()> {
+        let private_key = config.interface.private_key()?;
+        let peers: Vec<WgPeer> = config.peers.iter().map(peer_attrs).collect();
+
+        info!(
+            "Configuring kernel WireGuard device {} with {} peer(s)",
+            self.name,
+            peers.len()
+        );
+
+        let mut first = vec![
+            WgDeviceAttrs::IfName(self.name.clone()),
+            WgDeviceAttrs::PrivateKey(*private_key.as_bytes()),
+            WgDeviceAttrs::ListenPort(config.interface.listen_port),
+            WgDeviceAttrs::Flags(WGDEVICE_F_REPLACE_PEERS),
+        ];
+
+        let mut chunks = peers.chunks(PEERS_PER_MESSAGE);
+        if let Some(chunk) = chunks.next() {
+            first.push(WgDeviceAttrs::Peers(chunk.to_vec()));
+        }
+        self.set_device(first)?;
+
+        // 后续消息只追加对等体，不再替换
+        for chunk in chunks {
+            self.set_device(vec![
+                WgDeviceAttrs::IfName(self.name.clone()),
+                WgDeviceAttrs::Peers(chunk.to_vec()),
+            ])?;
+        }
+
+        Ok(())
+    }
+
+    /// 只下发变化的对等体，其余对等体的会话和计数器保持不变
+    pub fn apply_changes(&self, changes: &PeerChanges) -> Result<()> {
+        let mut peers: Vec<WgPeer> = changes
+            .removed
+            .iter()
+            .map(|peer| {
+                WgPeer(vec![
+                    WgPeerAttrs::PublicKey(*peer.public_key.as_bytes()),
+                    WgPeerAttrs::Flags(WGPEER_F_REMOVE_ME),
+                ])
+            })
+            .collect();
+        peers.extend(
+            changes
+                .added
+                .iter()
+                .chain(&changes.changed)
+                .map(|p| peer_attrs(p)),
+        );
+
+        info!(
+            "Updating kernel WireGuard device {}: {} added, {} changed, {} removed",
+            self.name,
+            changes.added.len(),
+            changes.changed.len(),
+            changes.removed.len()
+        );
+
+        for chunk in peers.chunks(PEERS_PER_MESSAGE) {
+            self.set_device(vec![
+                WgDeviceAttrs::IfName(self.name.clone()),
+                WgDeviceAttrs::Peers(chunk.to_vec()),
+            ])?;
+        }
+        Ok(())
+    }
+
+    /// 从内核读取对等体计数器
+    pub fn peer_stats(&self) -> Result<Vec<KernelPeerStats>> {
+        let message = GenlMessage::from_payload(Wireguard {
+            cmd: WireguardCmd::GetDevice,
+            nlas: vec![WgDeviceAttrs::IfName(self.name.clone())],
+        });
+        let responses = self.netlink(|| {
+            netlink_request_genl(message, Some(NLM_F_REQUEST | NLM_F_DUMP | NLM_F_ACK))
+                .map_err(|e| netlink_error("Failed to query kernel device", e))
+        })?;
+
+        let mut stats = Vec::new();
+        for response in responses {
+            let NetlinkMessage {
+                payload: NetlinkPayload::InnerMessage(message),
+                ..
+            } = response
+            else {
+                continue;
+            };
+            for nla in message.payload.nlas {
+                if let WgDeviceAttrs::Peers(peers) = nla {
+                    stats.extend(peers.iter().filter_map(parse_peer_stats));
+                }
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// 用内核计数器更新对等体状态
+    pub fn update_peers(&self, peers: &mut [Peer]) -> Result<()> {
+        let stats = self.peer_stats()?;
+        let now = SystemTime::now();
+
+        for peer in peers.iter_mut() {
+            let Some(entry) = stats.iter().find(|s| s.public_key == peer.public_key) else {
+                continue;
+            };
+
+            peer.bytes_received = entry.rx_bytes;
+            peer.bytes_sent = entry.tx_bytes;
+            if entry.endpoint.is_some() {
+                peer.endpoint = entry.endpoint;
+            }
+            peer.last_handshake = entry
+                .last_handshake
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            peer.status = match entry
+                .last_handshake
+                .and_then(|t| now.duration_since(t).ok())
+            {
+                Some(age) if peer.last_handshake > 0 && age < HANDSHAKE_TIMEOUT => {
+                    PeerStatus::Connected
+                }
+                _ => PeerStatus::Disconnected,
+            };
+        }
+
+        Ok(())
+    }
+
+    /// 删除链路
+    pub fn delete(&self) -> Result<()> {
+        info!("Deleting kernel WireGuard device {}", self.name);
+        let message = self.link_message();
+        self.netlink(|| {
+            netlink_request_rtnl(
+                RouteNetlinkMessage::DelLink(message),
+                Some(NLM_F_REQUEST | NLM_F_ACK),
+            )
+            .map_err(|e| netlink_error("Failed to delete kernel device", e))
+        })?;
+        Ok(())
+    }
+
+    /// 在当前（宿主）命名空间创建 wireguard 链路，已存在时忽略
+    ///
+    /// 内核 WireGuard 的 UDP 套接字始终位于创建链路的命名空间，因此之后即使把链路移入其他命名空间，
+    /// 端口仍在宿主命名空间监听。
+    pub fn create_link(&self) -> Result<()> {
+        match netlink_request_rtnl(
+            RouteNetlinkMessage::NewLink(self.link_message()),
+            Some(NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL),
+        ) {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => {
+                Err(netlink_error("Failed to create kernel device", e))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// 发送一条 SetDevice 消息
+    fn set_device(&self, nlas: Vec<WgDeviceAttrs>) -> Result<()> {
+        let message = GenlMessage::from_payload(Wireguard {
+            cmd: WireguardCmd::SetDevice,
+            nlas,
+        });
+        self.netlink(|| {
+            netlink_request_genl(message, Some(NLM_F_REQUEST | NLM_F_ACK))
+                .map_err(|e| netlink_error("Failed to configure kernel device", e))
+        })?;
+        Ok(())
+    }
+
+    /// 在链路所在的命名空间中执行 netlink 请求
+    fn netlink<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send,
+        F: FnOnce() -> Result<T> + Send,
+    {
+        match &self.netns {
+            Some(ns) => netns::run_in(ns, f),
+            None => f(),
+        }
+    }
+
+    fn link_message(&self) -> LinkMessage {
+        let mut message = LinkMessage::default();
+        message
+            .attributes
+            .push(LinkAttribute::IfName(self.name.clone()));
+        message
+            .attributes
+            .push(LinkAttribute::LinkInfo(vec![LinkInfo::Kind(
+                InfoKind::Wireguard,
+            )]));
+        message
+    }
+}
+
+fn netlink_error(context: &str, e: io::Error) -> Error {
+    Error::DeviceError(format!("{}: {}", context, e))
+}
+
+/// 将对等体配置转换为 netlink 属性
+fn peer_attrs(config: &PeerConfig) -> WgPeer {
+    let mut attrs = vec![WgPeerAttrs::PublicKey(*config.public_key.as_bytes())];
+
+    // 全零表示没有预共享密钥，更新已有对等体时也会清除旧的密钥
+    let psk = config.psk.as_ref().map_or([0u8; 32], |psk| *psk.as_bytes());
+    attrs.push(WgPeerAttrs::PresharedKey(psk));
+
+    if let Some(addr) = config.endpoint_addr() {
+        attrs.push(WgPeerAttrs::Endpoint(addr));
+    }
+
+    // 0 关闭保活，重载时删除该配置项也会生效
+    attrs.push(WgPeerAttrs::PersistentKeepalive(
+        config.persistent_keepalive.unwrap_or(0),
+    ));
+
+    let mut allowed_ips = Vec::new();
+    for net in &config.allowed_ips {
+        let family = match net {
+            IpNet::V4(_) => AF_INET,
+            IpNet::V6(_) => AF_INET6,
+        };
+        allowed_ips.push(WgAllowedIp(vec![
+            WgAllowedIpAttrs::Family(family),
+            WgAllowedIpAttrs::IpAddr(net.network()),
+            WgAllowedIpAttrs::Cidr(net.prefix_len()),
+        ]));
+    }
+    attrs.push(WgPeerAttrs::AllowedIps(allowed_ips));
+    attrs.push(WgPeerAttrs::Flags(WGPEER_F_REPLACE_ALLOWEDIPS));
+
+    WgPeer(attrs)
+}
+
+/// 解析内核返回的对等体属性
+fn parse_peer_stats(peer: &WgPeer) -> Option<KernelPeerStats> {
+    let public_key = peer.iter().find_map(|attr| match attr {
+        WgPeerAttrs::PublicKey(key) => Some(PublicKey::from(*key)),
+        _ => None,
+    })?;
+    let mut stats = KernelPeerStats {
+        public_key,
+        endpoint: None,
+        last_handshake: None,
+        rx_bytes: 0,
+        tx_bytes: 0,
+    };
+
+    for attr in peer.iter() {
+        match attr {
+            WgPeerAttrs::Endpoint(addr) => stats.endpoint = Some(*addr),
+            WgPeerAttrs::LastHandshake(time) if *time > UNIX_EPOCH => {
+                stats.last_handshake = Some(*time)
+            }
+            WgPeerAttrs::RxBytes(bytes) => stats.rx_bytes = *bytes,
+            WgPeerAttrs::TxBytes(bytes) => stats.tx_bytes = *bytes,
+            _ => {}
+        }
+    }
+
+    Some(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto;
+
+    #[test]
+    fn test_peer_attrs() {
+        let (_, pub_key) = crypto::generate_keypair().unwrap();
+        let config = PeerConfig {
+            public_key: pub_key,
+            allowed_ips: vec![
+                "10.8.0.2/32".parse().unwrap(),
+                "fd00:8::2/128".parse().unwrap(),
+            ],
+            endpoint: Some("192.0.2.1:51820".parse().unwrap()),
+            persistent_keepalive: Some(25),
+            ..Default::default()
+        };
+
+        let peer = peer_attrs(&config);
+        let allowed_ips = peer
+            .iter()
+            .find_map(|attr| match attr {
+                WgPeerAttrs::AllowedIps(ips) => Some(ips.len()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(allowed_ips, 2);
+        assert!(peer
+            .iter()
+            .any(|attr| matches!(attr, WgPeerAttrs::PersistentKeepalive(25))));
+
+        let stats = parse_peer_stats(&peer).unwrap();
+        assert_eq!(stats.public_key, pub_key);
+        assert_eq!(stats.endpoint, Some("192.0.2.1:51820".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_peer_attrs_skips_unresolved_endpoint() {
+        let (_, pub_key) = crypto::generate_keypair().unwrap();
+        let config = PeerConfig {
+            public_key: pub_key,
+            allowed_ips: vec!["10.8.0.2/32".parse().unwrap()],
+            endpoint: Some("peer.invalid:51820".parse().unwrap()),
+            ..Default::default()
+        };
+        let peer = peer_attrs(&config);
+        assert!(!peer
+            .iter()
+            .any(|attr| matches!(attr, WgPeerAttrs::Endpoint(_))));
+        assert_eq!(parse_peer_stats(&peer).unwrap().public_key, pub_key);
+    }
+}
@@ -1,8 +1,29 @@
+pub mod check;
 pub mod config;
 pub mod crypto;
 pub mod device;
+pub mod error;
+pub mod ipam;
+pub mod kernel;
+pub mod migrate;
+pub mod nat;
+pub mod netns;
+pub mod obfuscation;
+pub mod overrides;
 pub mod peer;
+#[cfg(feature = "pq")]
+pub mod pq;
+pub mod privilege;
+pub mod provision;
+pub mod qr;
+pub mod sandbox;
+pub mod secret;
 pub mod server;
-pub mod error;
+pub mod stream;
+pub mod transport;
+pub mod tunnel;
+pub mod types;
+pub mod watch;
+pub mod wgquick;
 
 pub use error::{Error, Result};
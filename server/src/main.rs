@@ -1,7 +1,33 @@
+use caps::CapsHashSet;
 use clap::{Parser, Subcommand};
-use log::info;
-use rusty_tunnel_server::{config::ServerConfig, crypto, error::Result, server::VpnServer};
-use std::path::PathBuf;
+use ipnet::IpNet;
+use log::{error, info, warn};
+use rusty_tunnel_server::{
+    check::{self, Severity},
+    config::{self, ClientConfig, ServerConfig},
+    crypto,
+    error::{Error, Result},
+    ipam::AddressPool,
+    migrate::{self, Kind},
+    overrides::{Override, Overrides},
+    privilege,
+    provision::{self, NewPeer},
+    qr,
+    sandbox::Sandbox,
+    secret::{SecretKey, SecretString},
+    server::VpnServer,
+    types::{self, PublicKey},
+    watch::{self, ConfigWatcher},
+    wgquick,
+};
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::signal::unix::{signal, SignalKind};
+use zeroize::Zeroizing;
 
 #[derive(Parser, Debug)]
 #[command(name = "RustyTunnel Server")]
@@ -19,6 +45,14 @@ enum Commands {
         /// 配置文件路径
         #[arg(short, long, default_value = "server.toml")]
         config: PathBuf,
+
+        /// 监视配置文件和 peers_dir，变更后自动重载
+        #[arg(long)]
+        watch: bool,
+
+        /// 覆盖单项配置，如 interface.listen_port=443（优先于 RT_ 环境变量，可重复）
+        #[arg(long = "set", value_name = "KEY=VALUE")]
+        set: Vec<Override>,
     },
 
     /// 生成密钥对
@@ -26,6 +60,146 @@ enum Commands {
         /// 生成的密钥对数量
         #[arg(short, long, default_value = "1")]
         count: usize,
+
+        /// 以 JSON 数组输出，便于脚本解析
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// 生成私钥并输出到标准输出（同 `wg genkey`）
+    Genkey,
+
+    /// 从标准输入读取私钥，输出对应的公钥（同 `wg pubkey`）
+    Pubkey,
+
+    /// 生成预共享密钥并输出到标准输出（同 `wg genpsk`）
+    Genpsk,
+
+    /// 将 next_private_key 提升为当前私钥，并输出新的公钥
+    Promote {
+        /// 配置文件路径
+        #[arg(short, long, default_value = "server.toml")]
+        config: PathBuf,
+    },
+
+    /// 添加对等体：生成密钥、分配地址并写出客户端配置
+    AddPeer {
+        /// 服务器配置文件路径
+        #[arg(short, long, default_value = "server.toml")]
+        config: PathBuf,
+
+        /// 对等体名称
+        #[arg(short, long)]
+        name: String,
+
+        /// 客户端连接的服务器地址（主机名或 IP，可带端口）
+        #[arg(short, long)]
+        endpoint: String,
+
+        /// 客户端配置输出路径，默认为 `<name>.toml`（使用 --wg-quick 时为 `<name>.conf`）
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// 输出 wg-quick 格式的客户端配置
+        #[arg(long)]
+        wg_quick: bool,
+
+        /// 同时生成预共享密钥
+        #[arg(long)]
+        psk: bool,
+
+        /// 客户端经隧道路由的地址范围，默认全部流量
+        #[arg(long, value_delimiter = ',')]
+        allowed_ips: Vec<IpNet>,
+
+        /// 客户端的 DNS 服务器
+        #[arg(long, value_delimiter = ',')]
+        dns: Vec<String>,
+
+        /// 客户端的保活间隔（秒）
+        #[arg(long)]
+        persistent_keepalive: Option<u16>,
+    },
+
+    /// 按名称删除对等体
+    RemovePeer {
+        /// 服务器配置文件路径
+        #[arg(short, long, default_value = "server.toml")]
+        config: PathBuf,
+
+        /// 对等体名称
+        #[arg(short, long)]
+        name: String,
+    },
+
+    /// 从 wg-quick 配置文件导入
+    Import {
+        /// wg-quick 配置文件，文件名（去掉扩展名）作为接口名
+        input: PathBuf,
+
+        /// 输出的配置文件路径
+        #[arg(short, long, default_value = "server.toml")]
+        output: PathBuf,
+
+        /// 导入为客户端配置
+        #[arg(long)]
+        client: bool,
+    },
+
+    /// 导出为 wg-quick 配置文件
+    Export {
+        /// 配置文件路径
+        #[arg(short, long, default_value = "server.toml")]
+        config: PathBuf,
+
+        /// 输出文件路径，省略时输出到标准输出
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// 配置文件为客户端配置
+        #[arg(long)]
+        client: bool,
+
+        /// 在终端中以二维码显示客户端配置，供移动端扫码导入
+        #[arg(long, requires = "client")]
+        qr: bool,
+
+        /// 将客户端配置的二维码写入 PNG 或 SVG 文件（按扩展名选择格式）
+        #[arg(long, requires = "client")]
+        qr_output: Option<PathBuf>,
+    },
+
+    /// 检查服务器配置，输出带行列号的诊断，有错误时以非零状态退出
+    Check {
+        /// 配置文件路径
+        #[arg(short, long, default_value = "server.toml")]
+        config: PathBuf,
+
+        /// 覆盖单项配置，同 `server --set`
+        #[arg(long = "set", value_name = "KEY=VALUE")]
+        set: Vec<Override>,
+    },
+
+    /// 输出应用环境变量和命令行覆盖后的完整服务器配置，密钥已隐去
+    EffectiveConfig {
+        /// 配置文件路径
+        #[arg(short, long, default_value = "server.toml")]
+        config: PathBuf,
+
+        /// 覆盖单项配置，同 `server --set`
+        #[arg(long = "set", value_name = "KEY=VALUE")]
+        set: Vec<Override>,
+    },
+
+    /// 将旧版本的配置文件迁移到当前版本，原文件备份为 `<文件>.v<版本>.bak`
+    Migrate {
+        /// 配置文件路径
+        #[arg(short, long, default_value = "server.toml")]
+        config: PathBuf,
+
+        /// 配置文件为客户端配置
+        #[arg(long)]
+        client: bool,
     },
 
     /// 生成示例配置文件
@@ -36,8 +210,7 @@ enum Commands {
     },
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     env_logger::Builder::from_default_env()
         .filter_level(log::LevelFilter::Info)
         .init();
@@ -45,11 +218,78 @@ async fn main() -> Result<()> {
     let args = Args::parse();
 
     match args.command {
-        Commands::Server { config } => {
-            run_server(config).await?;
+        Commands::Server { config, watch, set } => start_server(config, watch, overrides(set)?),
+        command => tokio::runtime::Runtime::new()?.block_on(run_command(command)),
+    }
+}
+
+async fn run_command(command: Commands) -> Result<()> {
+    match command {
+        Commands::Server { .. } => unreachable!("server runs in its own runtime"),
+        Commands::Keygen { count, json } => {
+            generate_keys(count, json)?;
+        }
+        Commands::Genkey => {
+            let (private_key, _) = crypto::generate_keypair()?;
+            println!("{}", private_key.to_base64().expose());
+        }
+        Commands::Pubkey => {
+            print_public_key()?;
+        }
+        Commands::Genpsk => {
+            println!("{}", crypto::generate_psk()?.to_base64().expose());
+        }
+        Commands::Promote { config } => {
+            promote_key(config)?;
         }
-        Commands::Keygen { count } => {
-            generate_keys(count)?;
+        Commands::AddPeer {
+            config,
+            name,
+            endpoint,
+            output,
+            wg_quick,
+            psk,
+            allowed_ips,
+            dns,
+            persistent_keepalive,
+        } => {
+            let new = NewPeer {
+                name,
+                endpoint,
+                psk,
+                allowed_ips,
+                dns,
+                persistent_keepalive,
+            };
+            add_peer(&config, &new, output, wg_quick)?;
+        }
+        Commands::RemovePeer { config, name } => {
+            remove_peer(&config, &name)?;
+        }
+        Commands::Import {
+            input,
+            output,
+            client,
+        } => {
+            import_config(&input, &output, client)?;
+        }
+        Commands::Export {
+            config,
+            output,
+            client,
+            qr,
+            qr_output,
+        } => {
+            export_config(&config, output.as_deref(), client, qr, qr_output.as_deref())?;
+        }
+        Commands::Check { config, set } => {
+            check_config(&config, &overrides(set)?).await?;
+        }
+        Commands::EffectiveConfig { config, set } => {
+            print_effective_config(&config, &overrides(set)?)?;
+        }
+        Commands::Migrate { config, client } => {
+            migrate_config(&config, client)?;
         }
         Commands::GenConfig { output } => {
             generate_config(output)?;
@@ -59,88 +299,614 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+/// `RT_` 环境变量加上命令行 `--set`，后者优先
+fn overrides(set: Vec<Override>) -> Result<Overrides> {
+    let mut overrides = Overrides::from_env()?;
+    overrides.extend(set);
+    Ok(overrides)
+}
+
+/// 静态检查后加载服务器配置并应用覆盖，诊断写入日志，有错误时拒绝加载
+///
+/// 主机名端点在这里解析一次，运行中的服务器不再查询 DNS。
+async fn load_config(config_path: &Path, overrides: &Overrides) -> Result<ServerConfig> {
+    let diagnostics = check::validate(config_path, overrides)?;
+    for diagnostic in &diagnostics {
+        match diagnostic.severity {
+            Severity::Error => error!("{}", diagnostic),
+            Severity::Warning => warn!("{}", diagnostic),
+        }
+    }
+    if check::has_errors(&diagnostics) {
+        return Err(Error::ConfigError(format!(
+            "Invalid configuration {:?}; run `check` for details",
+            config_path
+        )));
+    }
+    let mut config = ServerConfig::load(config_path, overrides)?;
+    config.resolve_endpoints().await;
+    Ok(config)
+}
+
+/// 输出合并后的配置，包括 `peers_dir` 中的对等体
+fn print_effective_config(config_path: &Path, overrides: &Overrides) -> Result<()> {
+    let config = ServerConfig::load(config_path, overrides)?;
+    let content = toml::to_string_pretty(&config.redacted()?)
+        .map_err(|e| Error::ConfigError(format!("Failed to serialize config: {}", e)))?;
+    print!("{}", content);
+    Ok(())
+}
+
+/// 迁移配置文件，服务器配置同时迁移 `peers_dir` 中的文件
+///
+/// 主配置文件最后写入，中途失败时可以重新运行。
+fn migrate_config(config_path: &Path, client: bool) -> Result<()> {
+    let kind = if client { Kind::Client } else { Kind::Server };
+    let content = std::fs::read_to_string(config_path)
+        .map_err(|e| Error::ConfigError(format!("Failed to read config file: {}", e)))?;
+    let Some(upgraded) = migrate::upgrade(&content, kind, true)? else {
+        println!(
+            "{} is already at version {}",
+            config_path.display(),
+            kind.current()
+        );
+        return Ok(());
+    };
+
+    if kind == Kind::Server {
+        let table: toml::Table = toml::from_str(&upgraded.content)
+            .map_err(|e| Error::ConfigError(format!("Failed to parse config: {}", e)))?;
+        if let Some(dir) = table.get("peers_dir").and_then(toml::Value::as_str) {
+            let dir = config_path.parent().unwrap_or(Path::new("")).join(dir);
+            for path in config::peer_files(&dir)? {
+                let content = std::fs::read_to_string(&path)
+                    .map_err(|e| Error::ConfigError(format!("Failed to read {:?}: {}", path, e)))?;
+                match migrate::upgrade_peers(&content, upgraded.from)? {
+                    Some(migrated) if migrated != content => {
+                        rewrite_config(&path, &migrated, upgraded.from)?
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    rewrite_config(config_path, &upgraded.content, upgraded.from)?;
+
+    println!(
+        "Migrated {} from version {} to {}:",
+        config_path.display(),
+        upgraded.from,
+        kind.current()
+    );
+    for step in &upgraded.steps {
+        println!("  - {}", step);
+    }
+    Ok(())
+}
+
+/// 备份原文件后原子地写入迁移后的内容，备份保留原文件的权限
+fn rewrite_config(path: &Path, content: &str, from: u32) -> Result<()> {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(".v{}.bak", from));
+    let backup = PathBuf::from(backup);
+    std::fs::copy(path, &backup)
+        .map_err(|e| Error::ConfigError(format!("Failed to back up {:?}: {}", path, e)))?;
+    config::write_atomic(path, content)
+        .map_err(|e| Error::ConfigError(format!("Failed to write {:?}: {}", path, e)))?;
+    println!("Backed up {} to {}", path.display(), backup.display());
+    Ok(())
+}
+
+/// 检查应用覆盖后的配置文件，包括端点解析和端口占用
+async fn check_config(config_path: &Path, overrides: &Overrides) -> Result<()> {
+    let diagnostics = check::check(config_path, overrides).await?;
+    for diagnostic in &diagnostics {
+        println!("{}", diagnostic);
+    }
+
+    let errors = diagnostics
+        .iter()
+        .filter(|d| d.severity == Severity::Error)
+        .count();
+    let warnings = diagnostics.len() - errors;
+    if errors > 0 {
+        return Err(Error::ConfigError(format!(
+            "{} error(s), {} warning(s) in {:?}",
+            errors, warnings, config_path
+        )));
+    }
+    println!("{}: OK, {} warning(s)", config_path.display(), warnings);
+    Ok(())
+}
+
 /// 运行服务器
-async fn run_server(config_path: PathBuf) -> Result<()> {
+fn start_server(config_path: PathBuf, watch: bool, overrides: Overrides) -> Result<()> {
     info!("Loading configuration from: {:?}", config_path);
 
-    let config = ServerConfig::from_file(&config_path)?;
+    // 单线程运行时在返回前回收解析端点用的阻塞线程
+    let config = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(load_config(&config_path, &overrides))?;
     info!("Configuration loaded successfully");
 
+    let security = config.security.clone().unwrap_or_default();
+    if security.group.is_some() && security.user.is_none() {
+        return Err(Error::ConfigError(
+            "security.group requires security.user".to_string(),
+        ));
+    }
+    // 边界集和 no_new_privs 只由之后创建的线程继承，必须赶在工作线程之前设置
+    let capabilities = privilege::required_capabilities(&config);
+    if security.user.is_some() {
+        privilege::restrict_process(&capabilities)?;
+    }
+
+    tokio::runtime::Runtime::new()?.block_on(run_server(
+        config_path,
+        config,
+        watch,
+        overrides,
+        capabilities,
+    ))
+}
+
+/// 重载在主线程上执行，降权后只有主线程保留所需的能力
+async fn run_server(
+    config_path: PathBuf,
+    config: ServerConfig,
+    watch: bool,
+    overrides: Overrides,
+    capabilities: CapsHashSet,
+) -> Result<()> {
+    let security = config.security.clone().unwrap_or_default();
+    let sandbox = if security.sandbox {
+        Some(Sandbox::new(&config_path, &config)?)
+    } else {
+        None
+    };
+
+    let peers_dir = config.peers_dir(&config_path);
     let mut server = VpnServer::new(config)?;
+    server.start().await?;
+
+    // inotify 需要在降权和启用沙箱之前初始化
+    let mut watcher = if watch {
+        match ConfigWatcher::new(&config_path, peers_dir.as_deref(), watch::DEBOUNCE) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                server.stop().await?;
+                return Err(e);
+            }
+        }
+    } else {
+        None
+    };
+
+    // 设备和套接字已就绪，降权失败时拒绝以 root 继续运行
+    if let Some(user) = &security.user {
+        if let Err(e) = privilege::drop_privileges(user, security.group.as_deref(), &capabilities) {
+            error!("Failed to drop privileges, refusing to continue: {}", e);
+            server.stop().await?;
+            return Err(e);
+        }
+    }
+
+    if let Some(sandbox) = &sandbox {
+        if let Err(e) = sandbox.apply() {
+            error!("Failed to enable sandbox, refusing to continue: {}", e);
+            server.stop().await?;
+            return Err(e);
+        }
+    }
+
+    let mut hangup = signal(SignalKind::hangup())?;
 
-    // 设置 Ctrl+C 处理
-    let server_handle = tokio::spawn(async move {
-        match server.start().await {
-            Ok(_) => {
-                // 保持运行直到收到信号
-                tokio::signal::ctrl_c()
-                    .await
-                    .expect("Failed to listen for Ctrl+C");
+    // 运行直到收到 Ctrl+C，SIGHUP 或配置文件变更触发配置重载
+    loop {
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => {
+                result?;
                 info!("Received Ctrl+C, shutting down...");
-                if let Err(e) = server.stop().await {
-                    eprintln!("Error stopping server: {}", e);
-                }
+                break;
             }
-            Err(e) => {
-                eprintln!("Failed to start server: {}", e);
+            _ = hangup.recv() => {
+                info!("Received SIGHUP, reloading {:?}", config_path);
+                reload(&mut server, &config_path, &overrides, watcher.as_mut()).await;
+            }
+            result = config_changed(watcher.as_mut()) => {
+                if let Err(e) = result {
+                    error!("Failed to watch configuration: {}", e);
+                    server.stop().await?;
+                    return Err(e);
+                }
+                info!("Configuration changed, reloading {:?}", config_path);
+                reload(&mut server, &config_path, &overrides, watcher.as_mut()).await;
             }
         }
-    });
+    }
 
-    server_handle.await.ok();
-    Ok(())
+    server.stop().await
+}
+
+/// 等待配置文件变更，未启用监视时永不返回
+async fn config_changed(watcher: Option<&mut ConfigWatcher>) -> Result<()> {
+    match watcher {
+        Some(watcher) => watcher.changed().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// 重新加载配置并应用到运行中的服务器，无效的配置被拒绝，运行状态保持不变
+async fn reload(
+    server: &mut VpnServer,
+    config_path: &Path,
+    overrides: &Overrides,
+    watcher: Option<&mut ConfigWatcher>,
+) {
+    let config = match load_config(config_path, overrides).await {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Rejected configuration update: {}", e);
+            return;
+        }
+    };
+    let peers_dir = config.peers_dir(config_path);
+    if let Err(e) = server.reload(config).await {
+        error!("Failed to reload configuration: {}", e);
+        return;
+    }
+    if let Some(watcher) = watcher {
+        if let Err(e) = watcher.watch_peers_dir(peers_dir.as_deref()) {
+            warn!("{}", e);
+        }
+    }
+}
+
+/// `keygen --json` 输出的密钥对
+#[derive(Serialize)]
+struct Keypair {
+    private_key: SecretString,
+    public_key: PublicKey,
 }
 
 /// 生成密钥对
-fn generate_keys(count: usize) -> Result<()> {
+fn generate_keys(count: usize, json: bool) -> Result<()> {
     info!("Generating {} keypair(s)...", count);
 
-    for i in 1..=count {
-        let (priv_key, pub_key) = crypto::generate_keypair()?;
-        println!("\n[Keypair {}]", i);
-        println!("PrivateKey = \"{}\"", priv_key);
-        println!("PublicKey = \"{}\"", pub_key);
+    let keypairs = (0..count)
+        .map(|_| {
+            crypto::generate_keypair().map(|(private_key, public_key)| Keypair {
+                private_key: private_key.to_base64(),
+                public_key,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if json {
+        let output = serde_json::to_string_pretty(&keypairs)
+            .map_err(|e| Error::Other(format!("Failed to serialize keypairs: {}", e)))?;
+        println!("{}", output);
+        return Ok(());
+    }
+
+    for (i, keypair) in keypairs.iter().enumerate() {
+        println!("\n[Keypair {}]", i + 1);
+        println!("PrivateKey = \"{}\"", keypair.private_key.expose());
+        println!("PublicKey = \"{}\"", keypair.public_key);
+    }
+
+    Ok(())
+}
+
+/// 从标准输入读取私钥并输出公钥
+fn print_public_key() -> Result<()> {
+    let mut input = Zeroizing::new(String::new());
+    io::stdin().read_to_string(&mut input)?;
+    let private_key = crypto::decode_private_key(input.trim())?;
+    println!("{}", crypto::public_key(&private_key));
+    Ok(())
+}
+
+/// 提升下一个私钥并保存配置，新的公钥输出到标准输出供分发给客户端
+fn promote_key(config_path: PathBuf) -> Result<()> {
+    let _lock = config::lock_config(&config_path)?;
+    let mut config = ServerConfig::load(&config_path, &Overrides::default())?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let public_key = config.interface.promote_next_key(now)?;
+    config.save(&config_path)?;
+
+    info!(
+        "Promoted next_private_key; the previous key is accepted until {}. Send SIGHUP to apply",
+        config.interface.previous_key_expires.unwrap_or(now)
+    );
+    println!("{}", public_key);
+    Ok(())
+}
+
+/// 添加对等体并写出客户端配置
+///
+/// 依次写入客户端配置、服务器配置和地址池状态，前面的步骤失败时服务器配置保持不变。
+/// 服务器配置只追加新对等体的表，注释和格式保持不变；配置了 `peers_dir` 时写入该目录中以名称命名的文件。
+/// 整个过程持有配置锁，并发执行的命令不会分配到相同的地址。
+fn add_peer(
+    config_path: &Path,
+    new: &NewPeer,
+    output: Option<PathBuf>,
+    wg_quick: bool,
+) -> Result<()> {
+    let _lock = config::lock_config(config_path)?;
+    provision::require_current_version(config_path)?;
+    let mut config = ServerConfig::load(config_path, &Overrides::default())?;
+    let mut pool = AddressPool::from_config(&config)?;
+    let client = provision::add_peer(&mut config, &mut pool, new)?;
+    let file = match config.peers_dir(config_path) {
+        Some(dir) => dir.join(format!("{}.toml", new.name)),
+        None => config_path.to_path_buf(),
+    };
+
+    let extension = if wg_quick { "conf" } else { "toml" };
+    let output = output.unwrap_or_else(|| PathBuf::from(format!("{}.{}", new.name, extension)));
+    if wg_quick {
+        let (content, warnings) = wgquick::export_client(&client);
+        for warning in &warnings {
+            warn!("{}", warning);
+        }
+        write_private_file(&output, &content)?;
+    } else {
+        client.save(&output)?;
+    }
+    let peer = config.peers.last().expect("add_peer appends the peer");
+    provision::append_peer(&file, peer)?;
+    pool.save()?;
+
+    info!(
+        "Added peer {} with address {}; client configuration written to {:?}. Send SIGHUP to apply",
+        new.name,
+        types::join_networks(&client.address),
+        output
+    );
+    Ok(())
+}
+
+/// 按名称删除对等体并释放其地址，`peers_dir` 中不再包含对等体的文件会被删除
+fn remove_peer(config_path: &Path, name: &str) -> Result<()> {
+    let _lock = config::lock_config(config_path)?;
+    provision::require_current_version(config_path)?;
+    let mut config = ServerConfig::load(config_path, &Overrides::default())?;
+    let mut pool = AddressPool::from_config(&config)?;
+    let peer = provision::remove_peer(&mut config, &mut pool, name)?;
+    match &peer.source {
+        Some(source) => {
+            if provision::delete_peer(source, &peer.public_key)? == 0 {
+                std::fs::remove_file(source).map_err(|e| {
+                    Error::ConfigError(format!("Failed to remove {:?}: {}", source, e))
+                })?;
+            }
+        }
+        None => {
+            provision::delete_peer(config_path, &peer.public_key)?;
+        }
+    }
+    pool.save()?;
+
+    info!(
+        "Removed peer {} ({}). Send SIGHUP to apply",
+        name,
+        types::join_networks(&peer.allowed_ips)
+    );
+    Ok(())
+}
+
+/// 写入仅所有者可读写的文件
+fn write_private_file(path: &Path, content: impl AsRef<[u8]>) -> Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(content.as_ref()))
+        .map_err(|e| Error::ConfigError(format!("Failed to write {:?}: {}", path, e)))
+}
+
+/// 将 wg-quick 配置转换为 TOML 配置
+fn import_config(input: &Path, output: &Path, client: bool) -> Result<()> {
+    let content = std::fs::read_to_string(input)
+        .map_err(|e| Error::ConfigError(format!("Failed to read {:?}: {}", input, e)))?;
+
+    let warnings = if client {
+        let (config, warnings) = wgquick::import_client(&content)?;
+        config.save(output)?;
+        warnings
+    } else {
+        let name = input
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or("wg0");
+        let (config, warnings) = wgquick::import_server(&content, name)?;
+        config.save(output)?;
+        warnings
+    };
+    for warning in &warnings {
+        warn!("{}", warning);
     }
 
+    info!("Imported {:?} into {:?}", input, output);
+    Ok(())
+}
+
+/// 将 TOML 配置导出为 wg-quick 配置，输出文件仅所有者可读写
+///
+/// 客户端配置可同时输出二维码，超出二维码容量时只给出警告。
+fn export_config(
+    config: &Path,
+    output: Option<&Path>,
+    client: bool,
+    show_qr: bool,
+    qr_output: Option<&Path>,
+) -> Result<()> {
+    let (content, warnings) = if client {
+        wgquick::export_client(&ClientConfig::from_file(config)?)
+    } else {
+        wgquick::export_server(&ServerConfig::from_file(config)?)
+    };
+    for warning in &warnings {
+        warn!("{}", warning);
+    }
+
+    match output {
+        Some(path) => {
+            write_private_file(path, &content)?;
+            info!("Exported {:?} to {:?}", config, path);
+        }
+        None if !show_qr && qr_output.is_none() => print!("{}", content),
+        None => {}
+    }
+
+    if show_qr || qr_output.is_some() {
+        match qr::encode(&content) {
+            Ok(code) => {
+                if show_qr {
+                    println!("{}", qr::to_terminal(&code));
+                }
+                if let Some(path) = qr_output {
+                    write_qr_image(&code, path)?;
+                    info!("Wrote QR code for {:?} to {:?}", config, path);
+                }
+            }
+            Err(e) => warn!("Skipping QR code: {}", e),
+        }
+    }
     Ok(())
 }
 
+/// 按扩展名将二维码写为 PNG 或 SVG，图像中含有私钥，文件仅所有者可读写
+fn write_qr_image(code: &qrcode::QrCode, path: &Path) -> Result<()> {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("png") => write_private_file(path, qr::to_png(code)?),
+        Some("svg") => write_private_file(path, qr::to_svg(code)),
+        _ => Err(Error::ConfigError(format!(
+            "Unsupported QR image format {:?}, use .png or .svg",
+            path
+        ))),
+    }
+}
+
 /// 生成示例配置文件
 fn generate_config(output: PathBuf) -> Result<()> {
     let (priv_key, _) = crypto::generate_keypair()?;
+    // 配置中含有私钥，只允许所有者读写
+    write_private_file(&output, sample_config(&priv_key))?;
+
+    info!("Configuration file generated: {:?}", output);
+    println!("Configuration file created at: {:?}", output);
+    println!("Please edit it and add your peer configurations.");
 
-    let config_content = format!(
+    Ok(())
+}
+
+/// 示例配置，对等体部分被注释掉，生成的文件可以直接通过 `check`
+fn sample_config(private_key: &SecretKey) -> String {
+    format!(
         r#"# RustyTunnel Server Configuration
+version = {}  # Configuration format version; older files are upgraded with `migrate`
+
+# peers_dir = "peers.d"  # Optional: also load [[peers]] from every *.toml in this directory
 
 [interface]
 name = "wg0"
 private_key = "{}"
+# private_key_file = "/etc/rusty-tunnel/server.key"  # Optional: read the key from a file (mode 600) instead
+# next_private_key = "NEXT_PRIVATE_KEY"  # Optional: also accept handshakes for this key, then run `promote`
+# next_private_key_file = "/etc/rusty-tunnel/server.next.key"  # Optional: read the next key from a file instead
+# key_overlap = 604800  # Seconds the old key stays valid after `promote`
 address = "10.8.0.1/24"
 listen_port = 51820
+# listen = ["203.0.113.5", "203.0.113.5:443"]  # Optional: specific addresses and extra ports
+# backend = "kernel"  # Optional: use the wireguard kernel module instead of boringtun
+# netns = "tenant-a"  # Optional: place the interface in this network namespace
+# mtu = 1420  # Optional
+# post_up = ["iptables -A FORWARD -i %i -j ACCEPT"]  # Optional: kept for wg-quick export, not run
+# pre_down = ["iptables -D FORWARD -i %i -j ACCEPT"]  # Optional: kept for wg-quick export, not run
+
+# Optional: accept obfuscated traffic from peers configured with the same key
+# [interface.obfuscation]
+# key = "BASE64_32_BYTE_SECRET"
+
+# Optional NAT masquerading for tunnel traffic
+# [nat]
+# egress_interface = "eth0"
+# masquerade = ["10.8.0.0/24"]
 
-# Example peer configuration
-[[peers]]
-public_key = "REPLACE_WITH_CLIENT_PUBLIC_KEY"
-allowed_ips = "10.8.0.2/32"
-endpoint = "client.example.com:51820"  # Optional
+# Optional TCP/TLS fallback for clients behind UDP-blocking firewalls
+# [stream]
+# listen = "0.0.0.0:443"
+# tls_cert = "/etc/rusty-tunnel/cert.pem"
+# tls_key = "/etc/rusty-tunnel/key.pem"
+
+# Optional: drop root after setup, keeping only CAP_NET_ADMIN
+# [security]
+# user = "rustytunnel"
+# group = "rustytunnel"
+# sandbox = true  # Optional: confine the process with seccomp and Landlock
+
+# Optional: more address pools for `add-peer`, e.g. IPv6 next to the interface subnet
+# [ipam]
+# pools = "fd00:8::1/64"
+# reserved = "10.8.0.2/31"  # Never allocated to peers
+
+# Optional: rotate preshared keys with ML-KEM (requires the pq feature)
+# [pq]
+# listen = "0.0.0.0:51821"
+# rotation_interval = 120
+
+# Example peer configuration; uncomment and fill in, or add peers with `add-peer`
+# [[peers]]
+# public_key = "CLIENT_PUBLIC_KEY"
+# allowed_ips = "10.8.0.2/32"
+# endpoint = "client.example.com:51820"  # Optional
+# persistent_keepalive = 25  # Optional: seconds between keepalives, for peers behind NAT
+# psk = "GENERATED_PRESHARED_KEY"  # Optional: generate with `genpsk`
+# psk_credential = "client-psk"  # Optional: read the PSK from a systemd credential instead
 
 # Add more peers as needed
 # [[peers]]
 # public_key = "ANOTHER_CLIENT_PUBLIC_KEY"
 # allowed_ips = "10.8.0.3/32"
 "#,
-        priv_key
-    );
+        migrate::SERVER_VERSION,
+        private_key.to_base64().expose()
+    )
+}
 
-    std::fs::write(&output, config_content)
-        .map_err(|e| rusty_tunnel_server::error::Error::ConfigError(format!(
-            "Failed to write config file: {}",
-            e
-        )))?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
 
-    info!("Configuration file generated: {:?}", output);
-    println!("Configuration file created at: {:?}", output);
-    println!("Please edit it and add your peer configurations.");
+    #[test]
+    fn test_generated_config_loads() {
+        let dir = std::env::temp_dir().join(format!("rusty-tunnel-gen-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.toml");
+        generate_config(path.clone()).unwrap();
 
-    Ok(())
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        let diagnostics = check::validate(&path, &Overrides::default()).unwrap();
+        let config = ServerConfig::load(&path, &Overrides::default());
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(!check::has_errors(&diagnostics), "{:?}", diagnostics);
+        assert!(config.unwrap().peers.is_empty());
+    }
 }
@@ -0,0 +1,293 @@
+use crate::error::{Error, Result};
+use std::net::IpAddr;
+use toml_edit::{DocumentMut, Item, Table, Value};
+
+/// 当前的服务器配置版本
+pub const SERVER_VERSION: u32 = 2;
+
+/// 当前的客户端配置版本
+pub const CLIENT_VERSION: u32 = 2;
+
+/// 没有 `version` 的配置视为版本 1，即引入版本号之前的格式
+pub const LEGACY_VERSION: u32 = 1;
+
+/// 配置文件类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Server,
+    Client,
+}
+
+/// 迁移步骤，把 `from` 版本的配置升级到下一个版本
+struct Step {
+    from: u32,
+    /// 步骤说明，`migrate` 时输出
+    description: &'static str,
+    apply: fn(&mut Table),
+}
+
+const SERVER_STEPS: &[Step] = &[Step {
+    from: 1,
+    description: "add host prefixes to bare addresses in peers.allowed_ips and nat.masquerade",
+    apply: server_v1,
+}];
+
+const CLIENT_STEPS: &[Step] = &[Step {
+    from: 1,
+    description: "add host prefixes to bare addresses in address and allowed_ips",
+    apply: client_v1,
+}];
+
+impl Kind {
+    /// 当前版本
+    pub fn current(self) -> u32 {
+        match self {
+            Kind::Server => SERVER_VERSION,
+            Kind::Client => CLIENT_VERSION,
+        }
+    }
+
+    fn steps(self) -> &'static [Step] {
+        match self {
+            Kind::Server => SERVER_STEPS,
+            Kind::Client => CLIENT_STEPS,
+        }
+    }
+}
+
+/// 迁移后的配置
+#[derive(Debug)]
+pub struct Upgrade {
+    /// 原来的版本
+    pub from: u32,
+    /// 迁移后的配置文本
+    pub content: String,
+    /// 执行的步骤说明
+    pub steps: Vec<&'static str>,
+}
+
+/// 把配置升级到当前版本，已是当前版本时返回 None
+///
+/// 只改写需要迁移的值，注释和格式保持不变。`stamp` 为 false 时不写入版本号，
+/// 各配置项的行号与原文件一致，供 `check` 定位诊断。
+pub fn upgrade(content: &str, kind: Kind, stamp: bool) -> Result<Option<Upgrade>> {
+    let mut document = parse(content)?;
+    let from = version(document.as_table(), kind)?;
+    if from == kind.current() {
+        return Ok(None);
+    }
+
+    let steps = run(document.as_table_mut(), kind.steps(), from);
+    let mut content = document.to_string();
+    if stamp {
+        match document.get_mut("version").and_then(Item::as_value_mut) {
+            Some(version) => {
+                replace(version, Value::from(i64::from(kind.current())));
+                content = document.to_string();
+            }
+            None => {
+                // 版本号放在文件开头的注释之后、其他配置项之前
+                let header = content
+                    .split_inclusive('\n')
+                    .take_while(|line| line.starts_with('#'))
+                    .map(str::len)
+                    .sum();
+                content.insert_str(header, &format!("version = {}\n", kind.current()));
+            }
+        }
+    }
+    Ok(Some(Upgrade {
+        from,
+        content,
+        steps,
+    }))
+}
+
+/// 按主配置文件的原版本迁移 `peers_dir` 中的文件，这些文件没有自己的版本号
+pub fn upgrade_peers(content: &str, from: u32) -> Result<Option<String>> {
+    if from == SERVER_VERSION {
+        return Ok(None);
+    }
+    let mut document = parse(content)?;
+    run(document.as_table_mut(), SERVER_STEPS, from);
+    Ok(Some(document.to_string()))
+}
+
+fn parse(content: &str) -> Result<DocumentMut> {
+    content
+        .parse()
+        .map_err(|e| Error::ConfigError(format!("Failed to parse config: {}", e)))
+}
+
+/// 配置声明的版本，比当前版本新时返回错误
+fn version(table: &Table, kind: Kind) -> Result<u32> {
+    let Some(item) = table.get("version") else {
+        return Ok(LEGACY_VERSION);
+    };
+    let version = item
+        .as_integer()
+        .and_then(|v| u32::try_from(v).ok())
+        .filter(|v| *v >= LEGACY_VERSION)
+        .ok_or_else(|| {
+            Error::ConfigError(format!(
+                "Invalid configuration version {}",
+                item.to_string().trim()
+            ))
+        })?;
+    if version > kind.current() {
+        return Err(Error::ConfigError(format!(
+            "Configuration version {} is newer than the supported version {}",
+            version,
+            kind.current()
+        )));
+    }
+    Ok(version)
+}
+
+fn run(table: &mut Table, steps: &[Step], from: u32) -> Vec<&'static str> {
+    steps
+        .iter()
+        .filter(|step| step.from >= from)
+        .map(|step| {
+            (step.apply)(table);
+            step.description
+        })
+        .collect()
+}
+
+/// 版本 1 的地址直接交给 `ip route` 和 `nft`，允许省略前缀；现在加载时要求 CIDR
+fn server_v1(table: &mut Table) {
+    match table.get_mut("peers") {
+        Some(Item::ArrayOfTables(peers)) => {
+            for peer in peers.iter_mut() {
+                if let Some(value) = peer.get_mut("allowed_ips").and_then(Item::as_value_mut) {
+                    add_host_prefixes(value);
+                }
+            }
+        }
+        Some(Item::Value(Value::Array(peers))) => {
+            for peer in peers.iter_mut().filter_map(Value::as_inline_table_mut) {
+                if let Some(value) = peer.get_mut("allowed_ips") {
+                    add_host_prefixes(value);
+                }
+            }
+        }
+        _ => {}
+    }
+    let masquerade = table
+        .get_mut("nat")
+        .and_then(|nat| nat.get_mut("masquerade"))
+        .and_then(Item::as_value_mut);
+    if let Some(value) = masquerade {
+        add_host_prefixes(value);
+    }
+}
+
+/// 同 [`server_v1`]，客户端的接口地址和路由同样可以省略前缀
+fn client_v1(table: &mut Table) {
+    for key in ["address", "allowed_ips"] {
+        if let Some(value) = table.get_mut(key).and_then(Item::as_value_mut) {
+            add_host_prefixes(value);
+        }
+    }
+}
+
+/// 为逗号分隔列表或字符串数组中的单个地址加上 /32 或 /128
+fn add_host_prefixes(value: &mut Value) {
+    match value {
+        Value::Array(array) => {
+            for entry in array.iter_mut() {
+                if let Some(network) = entry.as_str().and_then(host_prefix) {
+                    replace(entry, Value::from(network));
+                }
+            }
+        }
+        value => {
+            let Some(list) = value.as_str() else {
+                return;
+            };
+            if list.split(',').any(|entry| host_prefix(entry).is_some()) {
+                let networks = list
+                    .split(',')
+                    .map(|entry| host_prefix(entry).unwrap_or_else(|| entry.trim().to_string()))
+                    .filter(|entry| !entry.is_empty())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                replace(value, Value::from(networks));
+            }
+        }
+    }
+}
+
+fn host_prefix(entry: &str) -> Option<String> {
+    let address: IpAddr = entry.trim().parse().ok()?;
+    let prefix = if address.is_ipv4() { 32 } else { 128 };
+    Some(format!("{}/{}", address, prefix))
+}
+
+/// 替换值并保留其前后的空白和注释
+fn replace(value: &mut Value, new: Value) {
+    let decor = value.decor().clone();
+    *value = new;
+    *value.decor_mut() = decor;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER_V1: &str = include_str!("../tests/fixtures/migrate/server_v1.toml");
+    const SERVER_V2: &str = include_str!("../tests/fixtures/migrate/server_v2.toml");
+    const PEERS_V1: &str = include_str!("../tests/fixtures/migrate/peers_v1.toml");
+    const PEERS_V2: &str = include_str!("../tests/fixtures/migrate/peers_v2.toml");
+    const CLIENT_V1: &str = include_str!("../tests/fixtures/migrate/client_v1.toml");
+    const CLIENT_V2: &str = include_str!("../tests/fixtures/migrate/client_v2.toml");
+
+    #[test]
+    fn test_server_v1_to_v2() {
+        let upgraded = upgrade(SERVER_V1, Kind::Server, true).unwrap().unwrap();
+        assert_eq!(upgraded.from, 1);
+        assert_eq!(upgraded.steps.len(), 1);
+        assert_eq!(upgraded.content, SERVER_V2);
+        assert!(upgrade(SERVER_V2, Kind::Server, true).unwrap().is_none());
+
+        // 不写版本号时各行位置不变
+        let unstamped = upgrade(SERVER_V1, Kind::Server, false).unwrap().unwrap();
+        assert_eq!(unstamped.content.lines().count(), SERVER_V1.lines().count());
+
+        assert_eq!(upgrade_peers(PEERS_V1, 1).unwrap().unwrap(), PEERS_V2);
+        assert!(upgrade_peers(PEERS_V2, SERVER_VERSION).unwrap().is_none());
+
+        // 迁移结果可以直接加载
+        toml::from_str::<crate::config::ServerConfig>(SERVER_V2).unwrap();
+        toml::from_str::<crate::config::PeerFile>(PEERS_V2).unwrap();
+    }
+
+    #[test]
+    fn test_client_v1_to_v2() {
+        let upgraded = upgrade(CLIENT_V1, Kind::Client, true).unwrap().unwrap();
+        assert_eq!(upgraded.from, 1);
+        assert_eq!(upgraded.content, CLIENT_V2);
+        assert!(upgrade(CLIENT_V2, Kind::Client, true).unwrap().is_none());
+        toml::from_str::<crate::config::ClientConfig>(CLIENT_V2).unwrap();
+    }
+
+    #[test]
+    fn test_version_checks() {
+        let err = upgrade("version = 3\n", Kind::Server, true).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("newer than the supported version 2"),
+            "{}",
+            err
+        );
+        assert!(upgrade("version = 0\n", Kind::Server, true).is_err());
+        assert!(upgrade("version = \"2\"\n", Kind::Client, true).is_err());
+
+        // 显式的旧版本号被原地更新
+        let upgraded = upgrade("version = 1 # old\n", Kind::Client, true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(upgraded.content, "version = 2 # old\n");
+    }
+}
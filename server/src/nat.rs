@@ -0,0 +1,179 @@
+use crate::config::NatConfig;
+use crate::error::{Error, Result};
+use ipnet::IpNet;
+use log::info;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// 本程序独占的 nftables 表名，其他软件的规则不会被修改
+pub const NAT_TABLE: &str = "rusty_tunnel";
+
+/// nftables NAT 规则管理器
+#[derive(Debug, Clone)]
+pub struct NatTable {
+    /// 出口网卡
+    egress_interface: String,
+    /// 需要伪装的隧道子网
+    subnets: Vec<IpNet>,
+    /// 规则所在的网络命名空间（可选）
+    netns: Option<String>,
+}
+
+impl NatTable {
+    /// 从配置创建 NAT 规则，校验网卡名
+    pub fn from_config(config: &NatConfig) -> Result<Self> {
+        let name = &config.egress_interface;
+        let valid_name = !name.is_empty()
+            && name.len() < 16
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !valid_name {
+            return Err(Error::ConfigError(format!(
+                "Invalid egress interface name: {:?}",
+                name
+            )));
+        }
+
+        if config.masquerade.is_empty() {
+            return Err(Error::ConfigError(
+                "NAT section must list at least one subnet to masquerade".to_string(),
+            ));
+        }
+
+        Ok(NatTable {
+            egress_interface: name.clone(),
+            subnets: config.masquerade.iter().map(IpNet::trunc).collect(),
+            netns: None,
+        })
+    }
+
+    /// 在指定网络命名空间中管理规则
+    pub fn in_namespace(mut self, netns: Option<String>) -> Self {
+        self.netns = netns;
+        self
+    }
+
+    /// 生成 nft 脚本
+    ///
+    /// 先声明再删除表，保证重复安装（如 SIGHUP 重载）时原子替换旧规则。
+    pub fn ruleset(&self) -> String {
+        let mut script = format!(
+            "table inet {table}\ndelete table inet {table}\ntable inet {table} {{\n",
+            table = NAT_TABLE
+        );
+        script.push_str("    chain postrouting {\n");
+        script.push_str("        type nat hook postrouting priority srcnat; policy accept;\n");
+        for subnet in &self.subnets {
+            let family = match subnet {
+                IpNet::V4(_) => "ip",
+                IpNet::V6(_) => "ip6",
+            };
+            script.push_str(&format!(
+                "        oifname \"{}\" {} saddr {} masquerade\n",
+                self.egress_interface, family, subnet
+            ));
+        }
+        script.push_str("    }\n}\n");
+        script
+    }
+
+    /// 安装（或替换）NAT 规则
+    pub fn install(&self) -> Result<()> {
+        info!(
+            "Installing NAT rules: {} subnet(s) via {}",
+            self.subnets.len(),
+            self.egress_interface
+        );
+        run_nft(self.netns.as_deref(), &self.ruleset())
+    }
+
+    /// 删除 NAT 规则表
+    pub fn remove(&self) -> Result<()> {
+        info!("Removing NAT table {}", NAT_TABLE);
+        run_nft(
+            self.netns.as_deref(),
+            &format!(
+                "table inet {table}\ndelete table inet {table}\n",
+                table = NAT_TABLE
+            ),
+        )
+    }
+}
+
+/// 通过 stdin 将脚本交给 nft 执行
+fn run_nft(netns: Option<&str>, script: &str) -> Result<()> {
+    let mut command = match netns {
+        Some(ns) => {
+            let mut command = Command::new("ip");
+            command.args(["netns", "exec", ns, "nft"]);
+            command
+        }
+        None => Command::new("nft"),
+    };
+    let mut child = command
+        .args(["-f", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::FirewallError(format!("Failed to run nft: {}", e)))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(script.as_bytes())
+            .map_err(|e| Error::FirewallError(format!("Failed to write nft script: {}", e)))?;
+    }
+
+    let output = child
+        .wait_with_output()
+        .map_err(|e| Error::FirewallError(format!("Failed to run nft: {}", e)))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::FirewallError(format!("nft failed: {}", stderr)));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ruleset_generation() {
+        let config = NatConfig {
+            egress_interface: "eth0".to_string(),
+            masquerade: vec![
+                "10.8.0.1/24".parse().unwrap(),
+                "fd00:8::/64".parse().unwrap(),
+            ],
+        };
+
+        let table = NatTable::from_config(&config).unwrap();
+        let script = table.ruleset();
+        assert!(script.starts_with("table inet rusty_tunnel\ndelete table inet rusty_tunnel\n"));
+        assert!(script.contains("oifname \"eth0\" ip saddr 10.8.0.0/24 masquerade"));
+        assert!(script.contains("oifname \"eth0\" ip6 saddr fd00:8::/64 masquerade"));
+    }
+
+    #[test]
+    fn test_invalid_nat_config() {
+        let config = NatConfig {
+            egress_interface: "eth0\" accept".to_string(),
+            masquerade: vec!["10.8.0.0/24".parse().unwrap()],
+        };
+        assert!(NatTable::from_config(&config).is_err());
+
+        let config = NatConfig {
+            masquerade: vec![],
+            ..config
+        };
+        assert!(NatTable::from_config(&config).is_err());
+
+        // 无效的子网在加载配置时即被拒绝
+        let content = "egress_interface = \"eth0\"\nmasquerade = [\"10.8.0.0/33\"]\n";
+        assert!(toml::from_str::<NatConfig>(content).is_err());
+    }
+}
@@ -0,0 +1,107 @@
+use crate::error::{Error, Result};
+use log::info;
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::process::Command;
+use std::thread;
+
+/// `ip netns` 管理的命名空间目录
+const NETNS_DIR: &str = "/run/netns";
+
+/// 命名空间文件路径
+fn path(name: &str) -> PathBuf {
+    PathBuf::from(NETNS_DIR).join(name)
+}
+
+/// 校验命名空间名称
+pub fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name != "."
+        && name != ".."
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        return Err(Error::ConfigError(format!(
+            "Invalid network namespace name: {:?}",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// 检查命名空间是否存在
+pub fn exists(name: &str) -> bool {
+    path(name).exists()
+}
+
+/// 创建命名空间
+pub fn create(name: &str) -> Result<()> {
+    info!("Creating network namespace {}", name);
+    run_ip(&["netns", "add", name])
+}
+
+/// 删除命名空间
+pub fn delete(name: &str) -> Result<()> {
+    info!("Deleting network namespace {}", name);
+    run_ip(&["netns", "delete", name])
+}
+
+/// 在指定命名空间中执行闭包
+///
+/// `setns` 只影响调用线程，因此在独立线程中执行，不会改变其他线程（包括 UDP 套接字所在的）命名空间。
+pub fn run_in<T, F>(name: &str, f: F) -> Result<T>
+where
+    T: Send,
+    F: FnOnce() -> Result<T> + Send,
+{
+    let ns = File::open(path(name)).map_err(|e| {
+        Error::DeviceError(format!("Failed to open network namespace {}: {}", name, e))
+    })?;
+
+    thread::scope(|scope| {
+        scope
+            .spawn(move || {
+                // SAFETY: 描述符在调用期间有效，setns 只切换当前线程
+                let ret = unsafe { libc::setns(ns.as_raw_fd(), libc::CLONE_NEWNET) };
+                if ret != 0 {
+                    return Err(Error::DeviceError(format!(
+                        "Failed to enter network namespace: {}",
+                        io::Error::last_os_error()
+                    )));
+                }
+                f()
+            })
+            .join()
+            .map_err(|_| Error::Other("Network namespace worker panicked".to_string()))?
+    })
+}
+
+fn run_ip(args: &[&str]) -> Result<()> {
+    let output = Command::new("ip")
+        .args(args)
+        .output()
+        .map_err(|e| Error::DeviceError(format!("Failed to run command: {}", e)))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::DeviceError(format!("Command failed: {}", stderr)));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("tenant-a").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("..").is_err());
+        assert!(validate_name("a/b").is_err());
+    }
+}
@@ -0,0 +1,231 @@
+use crate::config::ObfuscationConfig;
+use crate::secret::SecretKey;
+use blake2::digest::{FixedOutput, KeyInit, Update};
+use blake2::Blake2sMac256;
+use rand::Rng;
+use std::time::Instant;
+
+/// 每个混淆报文前的随机 nonce 长度
+const NONCE_LEN: usize = 4;
+
+/// 被掩码的报文头长度：消息类型、保留字节和会话索引
+const MASK_LEN: usize = 16;
+
+/// 握手报文附加的最大随机填充
+const MAX_PADDING: usize = 64;
+
+/// 垃圾报文的长度范围
+const JUNK_SIZE: std::ops::RangeInclusive<usize> = 16..=256;
+
+/// WireGuard 消息类型
+const HANDSHAKE_INIT: u8 = 1;
+const HANDSHAKE_RESPONSE: u8 = 2;
+const COOKIE_REPLY: u8 = 3;
+const DATA: u8 = 4;
+
+/// 数据报文的最小长度（报文头加认证标签）
+const MIN_DATA_LEN: usize = 32;
+
+/// 每秒对未知来源的报文尝试解混淆的次数上限，每个候选密钥计一次
+pub const TRIALS_PER_SECOND: u32 = 20_000;
+
+/// 流量混淆器
+///
+/// 混淆报文格式为 `nonce || 掩码后的 WireGuard 报文 || 填充`：报文头前 16 字节与
+/// 由共享密钥和 nonce 派生的密钥流异或，握手报文再附加随机长度的填充，
+/// 接收方按消息类型的固定长度去掉填充。
+#[derive(Clone, PartialEq, Eq)]
+pub struct Obfuscator {
+    key: SecretKey,
+    junk_packets: u8,
+}
+
+impl Obfuscator {
+    /// 从配置创建混淆器
+    pub fn from_config(config: &ObfuscationConfig) -> Self {
+        Obfuscator {
+            key: config.key.clone(),
+            junk_packets: config.junk_packets,
+        }
+    }
+
+    /// 返回需要依次发出的数据报：握手发起前的垃圾报文，以及混淆后的报文
+    pub fn datagrams(&self, packet: &[u8]) -> Vec<Vec<u8>> {
+        let mut datagrams = Vec::new();
+        if packet.first() == Some(&HANDSHAKE_INIT) {
+            datagrams.extend((0..self.junk_packets).map(|_| junk()));
+        }
+        datagrams.push(self.encode(packet));
+        datagrams
+    }
+
+    /// 混淆一个 WireGuard 报文
+    pub fn encode(&self, packet: &[u8]) -> Vec<u8> {
+        let mut rng = rand::thread_rng();
+        let padding = match packet.first() {
+            Some(&HANDSHAKE_INIT | &HANDSHAKE_RESPONSE | &COOKIE_REPLY) => {
+                rng.gen_range(0..=MAX_PADDING)
+            }
+            _ => 0,
+        };
+
+        // nonce 不能像明文 WireGuard 报文头，否则接收方会按明文处理
+        let mut nonce = [0u8; NONCE_LEN];
+        while nonce == [0; NONCE_LEN] || looks_plain(&nonce) {
+            rng.fill(&mut nonce);
+        }
+
+        let mut out = Vec::with_capacity(NONCE_LEN + packet.len() + padding);
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(packet);
+        self.apply_mask(&nonce, &mut out[NONCE_LEN..]);
+        out.resize(out.len() + padding, 0);
+        rng.fill(&mut out[NONCE_LEN + packet.len()..]);
+        out
+    }
+
+    /// 还原混淆报文，密钥不匹配或不是混淆报文时返回 `None`
+    pub fn decode(&self, datagram: &[u8]) -> Option<Vec<u8>> {
+        if datagram.len() < NONCE_LEN + MIN_DATA_LEN {
+            return None;
+        }
+        let (nonce, body) = datagram.split_at(NONCE_LEN);
+        let mut packet = body.to_vec();
+        self.apply_mask(nonce, &mut packet);
+
+        if !looks_plain(&packet) {
+            return None;
+        }
+        let len = match packet[0] {
+            HANDSHAKE_INIT => 148,
+            HANDSHAKE_RESPONSE => 92,
+            COOKIE_REPLY => 64,
+            _ => packet.len(),
+        };
+        if packet.len() < len {
+            return None;
+        }
+        packet.truncate(len);
+        Some(packet)
+    }
+
+    fn apply_mask(&self, nonce: &[u8], packet: &mut [u8]) {
+        let mut mac = <Blake2sMac256 as KeyInit>::new_from_slice(self.key.as_bytes())
+            .expect("32-byte key is valid for BLAKE2s");
+        mac.update(nonce);
+        let stream = mac.finalize_fixed();
+        for (byte, mask) in packet.iter_mut().zip(stream.iter().take(MASK_LEN)) {
+            *byte ^= mask;
+        }
+    }
+}
+
+/// 未知来源报文的解混淆预算（令牌桶）
+///
+/// 无法从来源地址确定混淆密钥的报文要逐个尝试全部候选密钥，每次尝试计算一次 BLAKE2s；
+/// 没有预算时直接丢弃，使伪造报文消耗的 CPU 不随对等体数量放大。
+#[derive(Debug)]
+pub struct TrialBudget {
+    per_second: u32,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TrialBudget {
+    /// 每秒最多 `per_second` 次尝试，初始为满
+    pub fn new(per_second: u32) -> Self {
+        TrialBudget {
+            per_second,
+            tokens: per_second as f64,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// 扣除 `trials` 次尝试，预算不足时不扣除并返回 false
+    pub fn take(&mut self, trials: usize, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second as f64).min(self.per_second as f64);
+        self.refilled = now;
+        if self.tokens < trials as f64 {
+            return false;
+        }
+        self.tokens -= trials as f64;
+        true
+    }
+}
+
+/// 生成一个随机内容的垃圾报文，接收方无法识别而直接丢弃
+fn junk() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let mut junk = vec![0u8; rng.gen_range(JUNK_SIZE)];
+    rng.fill(&mut junk[..]);
+    junk
+}
+
+/// 判断数据是否以 WireGuard 报文头开始（类型 1-4，后跟三个零字节）
+pub fn looks_plain(packet: &[u8]) -> bool {
+    packet.len() >= 4 && (HANDSHAKE_INIT..=DATA).contains(&packet[0]) && packet[1..4] == [0, 0, 0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn obfuscator(seed: u8) -> Obfuscator {
+        Obfuscator {
+            key: SecretKey::from([seed; 32]),
+            junk_packets: 0,
+        }
+    }
+
+    #[test]
+    fn test_obfuscation_roundtrip() {
+        let obfuscator = obfuscator(7);
+        let mut init = vec![0u8; 148];
+        init[0] = HANDSHAKE_INIT;
+        init[4..]
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, b)| *b = i as u8);
+
+        let encoded = obfuscator.encode(&init);
+        assert!(!looks_plain(&encoded));
+        assert!(encoded.len() >= NONCE_LEN + init.len());
+        assert_ne!(&encoded[NONCE_LEN..NONCE_LEN + 4], &init[..4]);
+        assert_eq!(obfuscator.decode(&encoded), Some(init.clone()));
+
+        // 数据报文不加填充
+        let mut data = vec![0u8; 64];
+        data[0] = DATA;
+        let encoded = obfuscator.encode(&data);
+        assert_eq!(encoded.len(), NONCE_LEN + data.len());
+        assert_eq!(obfuscator.decode(&encoded), Some(data.clone()));
+
+        // 错误的密钥无法还原
+        assert_eq!(self::obfuscator(8).decode(&encoded), None);
+
+        // 只在握手发起前附加垃圾报文
+        let obfuscator = Obfuscator {
+            junk_packets: 3,
+            ..obfuscator
+        };
+        assert_eq!(obfuscator.datagrams(&init).len(), 4);
+        assert_eq!(obfuscator.datagrams(&data).len(), 1);
+    }
+
+    #[test]
+    fn test_trial_budget() {
+        let start = Instant::now();
+        let mut budget = TrialBudget::new(100);
+        assert!(budget.take(60, start));
+        assert!(!budget.take(60, start));
+        assert!(budget.take(40, start));
+
+        // 按时间补充，不超过每秒上限
+        assert!(!budget.take(1, start));
+        assert!(budget.take(50, start + Duration::from_millis(500)));
+        assert!(!budget.take(101, start + Duration::from_secs(10)));
+        assert!(budget.take(100, start + Duration::from_secs(10)));
+    }
+}
@@ -0,0 +1,297 @@
+use crate::error::{Error, Result};
+use std::fmt;
+use std::str::FromStr;
+use toml::{Table, Value};
+
+/// 环境变量覆盖的前缀
+pub const ENV_PREFIX: &str = "RT_";
+
+/// 环境变量中分隔嵌套层级的字符串，如 `RT_INTERFACE__LISTEN_PORT`
+pub const ENV_SEPARATOR: &str = "__";
+
+/// 互斥的配置项：覆盖其中一项时删除同一表中的其他项，使覆盖的值生效
+const EXCLUSIVE: &[&[&str]] = &[
+    &[
+        "private_key",
+        "private_key_file",
+        "private_key_env",
+        "private_key_credential",
+    ],
+    &[
+        "next_private_key",
+        "next_private_key_file",
+        "next_private_key_env",
+        "next_private_key_credential",
+    ],
+    &["psk", "psk_file", "psk_env", "psk_credential"],
+];
+
+/// 一项配置覆盖，来自环境变量或命令行 `--set`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Override {
+    /// 来源（环境变量名或 `--set` 的参数），用于错误信息
+    pub origin: String,
+    /// 键路径，如 `["interface", "listen_port"]`；数组元素用下标表示
+    pub path: Vec<String>,
+    /// 原始值
+    pub value: String,
+}
+
+/// 依次应用在配置文件上的覆盖，后面的优先
+#[derive(Debug, Clone, Default)]
+pub struct Overrides(Vec<Override>);
+
+impl Override {
+    /// 解析 `RT_` 开头的环境变量，其他变量返回 None
+    pub fn from_env(name: &str, value: &str) -> Option<Result<Self>> {
+        let key = name.strip_prefix(ENV_PREFIX)?;
+        let path: Vec<String> = key.split(ENV_SEPARATOR).map(str::to_lowercase).collect();
+        Some(Self::new(name, path, value))
+    }
+
+    fn new(origin: &str, path: Vec<String>, value: &str) -> Result<Self> {
+        if path.iter().any(String::is_empty) {
+            return Err(Error::ConfigError(format!("Invalid override {:?}", origin)));
+        }
+        Ok(Override {
+            origin: origin.to_string(),
+            path,
+            value: value.to_string(),
+        })
+    }
+
+    /// 写入配置表，中间缺少的表会被创建
+    fn apply(&self, table: &mut Table) -> Result<()> {
+        let mut current = table;
+        let mut rest = self.path.as_slice();
+        loop {
+            let (key, tail) = rest.split_first().expect("path is never empty");
+            if tail.is_empty() {
+                if let Some(group) = EXCLUSIVE.iter().find(|g| g.contains(&key.as_str())) {
+                    for other in group.iter().filter(|other| **other != key) {
+                        current.remove(*other);
+                    }
+                }
+                let value = parse_value(&self.value, current.get(key.as_str()));
+                current.insert(key.clone(), value);
+                return Ok(());
+            }
+            rest = tail;
+
+            let entry = current
+                .entry(key.as_str())
+                .or_insert_with(|| Value::Table(Table::new()));
+            let entry = match entry {
+                Value::Array(items) => {
+                    let (index, tail) = rest.split_first().expect("checked above");
+                    let remaining = tail.len();
+                    let item = index.parse::<usize>().ok().and_then(|i| items.get_mut(i));
+                    let Some(item) = item else {
+                        return Err(self.error(remaining, "not an existing array element"));
+                    };
+                    if tail.is_empty() {
+                        return Err(self.error(0, "an array element, override its keys instead"));
+                    }
+                    rest = tail;
+                    item
+                }
+                entry => entry,
+            };
+            current = match entry {
+                Value::Table(table) => table,
+                _ => return Err(self.error(rest.len(), "not a table")),
+            };
+        }
+    }
+
+    /// 路径中除最后 `remaining` 段以外的部分不符合要求
+    fn error(&self, remaining: usize, reason: &str) -> Error {
+        let prefix = self.path[..self.path.len() - remaining].join(".");
+        Error::ConfigError(format!(
+            "Cannot apply {}: {} is {}",
+            self.origin, prefix, reason
+        ))
+    }
+}
+
+impl fmt::Display for Override {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.path.join("."))
+    }
+}
+
+/// 命令行格式：`interface.listen_port=443`，数组元素写作 `peers.0.endpoint=...`
+impl FromStr for Override {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or_else(|| Error::ConfigError(format!("Expected KEY=VALUE, got {:?}", s)))?;
+        let path = key.trim().split('.').map(str::to_string).collect();
+        Self::new(s, path, value)
+    }
+}
+
+impl Overrides {
+    /// 读取当前进程的 `RT_` 环境变量
+    pub fn from_env() -> Result<Self> {
+        Self::from_vars(std::env::vars())
+    }
+
+    /// 从给定的环境变量中读取覆盖，按变量名排序以保证结果确定
+    pub fn from_vars<I: IntoIterator<Item = (String, String)>>(vars: I) -> Result<Self> {
+        let mut overrides = Vec::new();
+        for (name, value) in vars {
+            if let Some(item) = Override::from_env(&name, &value) {
+                overrides.push(item?);
+            }
+        }
+        overrides.sort_by(|a, b| a.origin.cmp(&b.origin));
+        Ok(Overrides(overrides))
+    }
+
+    /// 追加优先级更高的覆盖
+    pub fn extend<I: IntoIterator<Item = Override>>(&mut self, overrides: I) {
+        self.0.extend(overrides);
+    }
+
+    /// 是否没有覆盖
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// 依次应用到解析后的配置文件
+    pub fn apply(&self, table: &mut Table) -> Result<()> {
+        for item in &self.0 {
+            item.apply(table)?;
+        }
+        Ok(())
+    }
+
+    /// 在最终配置中找不到的覆盖，通常是拼错了键名
+    pub fn unmatched<'a>(&'a self, config: &'a Value) -> impl Iterator<Item = &'a Override> {
+        self.0.iter().filter(move |item| {
+            let mut current = config;
+            for key in &item.path {
+                let next = match current {
+                    Value::Table(table) => table.get(key),
+                    Value::Array(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)),
+                    _ => None,
+                };
+                match next {
+                    Some(value) => current = value,
+                    None => return true,
+                }
+            }
+            false
+        })
+    }
+}
+
+/// 解析覆盖的值：原值是字符串时保持字符串，否则按 TOML 值解析（`443`、`true`、`["a", "b"]`），
+/// 无法解析时作为字符串
+fn parse_value(raw: &str, current: Option<&Value>) -> Value {
+    if let Some(Value::String(_)) = current {
+        return Value::String(raw.to_string());
+    }
+    match format!("value = {}", raw).parse::<Table>() {
+        Ok(mut table) if table.len() == 1 => table.remove("value").expect("parsed key"),
+        _ => Value::String(raw.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(items: &[(&str, &str)]) -> Vec<(String, String)> {
+        items
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let mut table: Table = r#"
+peers_dir = "peers.d"
+
+[interface]
+name = "wg0"
+private_key = "KEY"
+listen_port = 51820
+
+[[peers]]
+public_key = "KEY"
+allowed_ips = "10.8.0.2/32"
+"#
+        .parse()
+        .unwrap();
+
+        let overrides = Overrides::from_vars(vars(&[
+            ("HOME", "/root"),
+            ("RT_INTERFACE__LISTEN_PORT", "443"),
+            ("RT_INTERFACE__NAME", "1234"),
+            ("RT_INTERFACE__PRIVATE_KEY_FILE", "/run/secrets/key"),
+            ("RT_INTERFACE__LISTEN", r#"["0.0.0.0:443"]"#),
+            ("RT_PEERS_DIR", "conf.d"),
+            ("RT_PEERS__0__PERSISTENT_KEEPALIVE", "25"),
+            ("RT_SECURITY__SANDBOX", "true"),
+        ]))
+        .unwrap();
+        overrides.apply(&mut table).unwrap();
+
+        let interface = table["interface"].as_table().unwrap();
+        assert_eq!(interface["listen_port"], Value::Integer(443));
+        // 原值是字符串时不按数字解析
+        assert_eq!(interface["name"], Value::String("1234".to_string()));
+        assert_eq!(
+            interface["private_key_file"].as_str(),
+            Some("/run/secrets/key")
+        );
+        // 覆盖的密钥来源取代配置文件中的内联密钥
+        assert!(!interface.contains_key("private_key"));
+        assert_eq!(interface["listen"].as_array().unwrap().len(), 1);
+        assert_eq!(table["peers_dir"].as_str(), Some("conf.d"));
+        assert_eq!(
+            table["peers"][0]["persistent_keepalive"],
+            Value::Integer(25)
+        );
+        assert_eq!(table["security"]["sandbox"], Value::Boolean(true));
+
+        let applied = Value::Table(table);
+        assert_eq!(overrides.unmatched(&applied).count(), 0);
+    }
+
+    #[test]
+    fn test_invalid_overrides() {
+        let mut table: Table = "[interface]\nname = \"wg0\"\n\n[[peers]]\npublic_key = \"KEY\"\n"
+            .parse()
+            .unwrap();
+
+        let item: Override = "interface.name.x=1".parse().unwrap();
+        let err = Overrides(vec![item]).apply(&mut table).unwrap_err();
+        assert!(
+            err.to_string().contains("interface.name is not a table"),
+            "{}",
+            err
+        );
+
+        let err = Overrides::from_vars(vars(&[("RT_PEERS__3__PSK", "x")]))
+            .unwrap()
+            .apply(&mut table)
+            .unwrap_err();
+        assert!(err.to_string().contains("RT_PEERS__3__PSK"), "{}", err);
+        let item: Override = "peers.0={}".parse().unwrap();
+        assert!(Overrides(vec![item]).apply(&mut table).is_err());
+
+        assert!("interface.name".parse::<Override>().is_err());
+        assert!(Overrides::from_vars(vars(&[("RT_INTERFACE____NAME", "x")])).is_err());
+
+        let item: Override = "interface.listen_prot=443".parse().unwrap();
+        let overrides = Overrides(vec![item]);
+        let config = Value::Table("[interface]\nlisten_port = 51820\n".parse().unwrap());
+        assert_eq!(overrides.unmatched(&config).count(), 1);
+    }
+}
@@ -1,6 +1,8 @@
 use crate::config::PeerConfig;
-use crate::error::Result;
-use std::net::{IpAddr, SocketAddr};
+use crate::secret::SecretKey;
+use crate::types::PublicKey;
+use ipnet::IpNet;
+use std::net::SocketAddr;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 /// 对等体状态
@@ -18,13 +20,13 @@ pub enum PeerStatus {
 #[derive(Debug, Clone)]
 pub struct Peer {
     /// 公钥
-    pub public_key: String,
+    pub public_key: PublicKey,
     /// 允许的 IP 地址
-    pub allowed_ips: String,
+    pub allowed_ips: Vec<IpNet>,
     /// 对等体端点
     pub endpoint: Option<SocketAddr>,
     /// 预共享密钥
-    pub psk: Option<String>,
+    pub psk: Option<SecretKey>,
     /// 状态
     pub status: PeerStatus,
     /// 最后握手时间戳
@@ -36,20 +38,18 @@ pub struct Peer {
 }
 
 impl Peer {
-    /// 从配置创建对等体
-    pub fn from_config(config: PeerConfig) -> Result<Self> {
-        let endpoint = config.endpoint.and_then(|ep| ep.parse::<SocketAddr>().ok());
-
-        Ok(Peer {
+    /// 从配置创建对等体，主机名端点在此时解析
+    pub fn from_config(config: PeerConfig) -> Self {
+        Peer {
+            endpoint: config.endpoint_addr(),
             public_key: config.public_key,
             allowed_ips: config.allowed_ips,
-            endpoint,
             psk: config.psk,
             status: PeerStatus::Disconnected,
             last_handshake: 0,
             bytes_received: 0,
             bytes_sent: 0,
-        })
+        }
     }
 
     /// 更新对等体状态
@@ -64,8 +64,12 @@ impl Peer {
     pub fn summary(&self) -> String {
         format!(
             "Peer {{ key: {}, ips: {}, status: {:?}, rx: {} bytes, tx: {} bytes }}",
-            &self.public_key[..8],
-            self.allowed_ips,
+            self.public_key.short(),
+            self.allowed_ips
+                .iter()
+                .map(IpNet::to_string)
+                .collect::<Vec<_>>()
+                .join(", "),
             self.status,
             self.bytes_received,
             self.bytes_sent
@@ -84,32 +88,39 @@ pub fn current_timestamp() -> u64 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::types::Endpoint;
 
     #[test]
     fn test_peer_creation() {
         let config = PeerConfig {
-            public_key: "test_key".to_string(),
-            allowed_ips: "10.8.0.2/32".to_string(),
-            endpoint: None,
-            psk: None,
+            public_key: PublicKey::from([1u8; 32]),
+            allowed_ips: vec!["10.8.0.2/32".parse().unwrap()],
+            ..Default::default()
         };
 
-        let peer = Peer::from_config(config).unwrap();
+        let peer = Peer::from_config(config.clone());
         assert_eq!(peer.status, PeerStatus::Disconnected);
         assert_eq!(peer.bytes_received, 0);
         assert_eq!(peer.bytes_sent, 0);
+        assert!(peer.summary().contains("ips: 10.8.0.2/32"));
+
+        let endpoint = "192.0.2.1:51820".parse::<SocketAddr>().unwrap();
+        let peer = Peer::from_config(PeerConfig {
+            endpoint: Some(Endpoint::Addr(endpoint)),
+            ..config
+        });
+        assert_eq!(peer.endpoint, Some(endpoint));
     }
 
     #[test]
     fn test_peer_status_update() {
         let config = PeerConfig {
-            public_key: "test_key".to_string(),
-            allowed_ips: "10.8.0.2/32".to_string(),
-            endpoint: None,
-            psk: None,
+            public_key: PublicKey::from([1u8; 32]),
+            allowed_ips: vec!["10.8.0.2/32".parse().unwrap()],
+            ..Default::default()
         };
 
-        let mut peer = Peer::from_config(config).unwrap();
+        let mut peer = Peer::from_config(config);
         peer.set_status(PeerStatus::Connected);
         assert_eq!(peer.status, PeerStatus::Connected);
         assert!(peer.last_handshake > 0);
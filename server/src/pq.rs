@@ -0,0 +1,402 @@
+use crate::config::ServerConfig;
+use crate::error::{Error, Result};
+use crate::peer::Peer;
+use crate::secret::SecretKey;
+use crate::transport::Transport;
+use crate::tunnel::Tunnel;
+use crate::types;
+use blake2::digest::Mac;
+use blake2::Blake2sMac256;
+use log::{debug, info, warn};
+use ml_kem::kem::{Decapsulate, Encapsulate, Kem, KeyExport, TryKeyInit};
+use ml_kem::MlKem768;
+use rand::Rng;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock as StdRwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// 消息类型
+const INIT_HELLO: u8 = 1;
+const RESP_HELLO: u8 = 2;
+
+/// 消息头：类型和交换标识
+const HEADER_LEN: usize = 5;
+const TIMESTAMP_LEN: usize = 8;
+const EK_LEN: usize = 1184;
+const CT_LEN: usize = 1088;
+const MAC_LEN: usize = 32;
+
+/// `type || id || timestamp || 封装公钥 || mac`
+const INIT_LEN: usize = HEADER_LEN + TIMESTAMP_LEN + EK_LEN + MAC_LEN;
+/// `type || id || 密文 || mac`
+const RESP_LEN: usize = HEADER_LEN + CT_LEN + MAC_LEN;
+
+/// 未收到响应时重新发起交换的间隔
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// 密钥派生的域分隔标签
+const AUTH_LABEL: &[u8] = b"rusty-tunnel pq auth v1";
+const PSK_LABEL: &[u8] = b"rusty-tunnel pq psk v1";
+
+type DecapsulationKey = <MlKem768 as Kem>::DecapsulationKey;
+type EncapsulationKey = <MlKem768 as Kem>::EncapsulationKey;
+
+/// 参与交换的对等体
+struct ExchangePeer {
+    /// 公钥
+    public_key: [u8; 32],
+    /// 对端交换地址，配置后由本端发起交换
+    endpoint: Option<SocketAddr>,
+    /// 消息认证密钥，由双方静态密钥的 DH 结果和配置的预共享密钥派生；
+    /// 每个接受握手的本端私钥各一个，第一个对应当前私钥
+    auth_keys: Vec<SecretKey>,
+    /// 配置的预共享密钥（未配置时为全零），混入每次派生的新密钥
+    base_psk: SecretKey,
+}
+
+impl ExchangePeer {
+    fn verify(&self, message: &[u8]) -> bool {
+        self.auth_keys
+            .iter()
+            .any(|key| verify(key.as_bytes(), message))
+    }
+}
+
+/// 发起方等待响应的交换
+struct Pending {
+    id: u32,
+    decapsulation_key: DecapsulationKey,
+    init_mac: [u8; MAC_LEN],
+    sent: Instant,
+}
+
+/// 每个对等体的交换进度，重载配置后保留
+#[derive(Default)]
+struct ExchangeState {
+    pending: Option<Pending>,
+    /// 最近一次轮换完成的时间
+    rotated: Option<Instant>,
+    /// 已接受的最新发起时间戳，用于拒绝重放
+    last_timestamp: u64,
+}
+
+/// 后量子预共享密钥交换
+///
+/// 在独立端口上定期进行 ML-KEM-768 密钥交换，把派生的密钥安装为对等体的预共享密钥。
+/// 发起方发送临时封装公钥，响应方封装共享密钥并立即安装，发起方解封后安装并用新密钥重新握手；
+/// 已建立的 WireGuard 会话在新握手完成前继续使用。消息用 X25519 静态密钥派生的密钥认证，
+/// 保护的是记录流量后再用量子计算机解密的被动攻击。
+pub struct PskExchange {
+    peers: StdRwLock<Vec<Arc<ExchangePeer>>>,
+    state: Mutex<HashMap<[u8; 32], ExchangeState>>,
+    transport: Arc<dyn Transport>,
+    tunnel: Arc<Tunnel>,
+    status: Arc<RwLock<Vec<Peer>>>,
+    interval: Duration,
+}
+
+impl PskExchange {
+    /// 创建密钥交换，新密钥安装到 `tunnel` 和 `status` 中对应的对等体
+    pub fn new(
+        config: &ServerConfig,
+        transport: Arc<dyn Transport>,
+        tunnel: Arc<Tunnel>,
+        status: Arc<RwLock<Vec<Peer>>>,
+    ) -> Result<Self> {
+        let pq = config.pq.as_ref().ok_or_else(|| {
+            Error::ConfigError("Post-quantum key exchange is not configured".to_string())
+        })?;
+        if pq.rotation_interval == 0 {
+            return Err(Error::ConfigError(
+                "pq.rotation_interval must be at least 1 second".to_string(),
+            ));
+        }
+
+        let exchange = PskExchange {
+            peers: StdRwLock::new(Vec::new()),
+            state: Mutex::new(HashMap::new()),
+            transport,
+            tunnel,
+            status,
+            interval: Duration::from_secs(pq.rotation_interval),
+        };
+        exchange.reload(config)?;
+        Ok(exchange)
+    }
+
+    /// 按新配置更新本端私钥和对等体，保留已有对等体的交换进度
+    pub fn reload(&self, config: &ServerConfig) -> Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let private_keys: Vec<StaticSecret> = config
+            .interface
+            .accepted_private_keys(now)?
+            .into_iter()
+            .map(|(key, _)| StaticSecret::from(*key.as_bytes()))
+            .collect();
+
+        let mut peers = Vec::with_capacity(config.peers.len());
+        for config in &config.peers {
+            let public_key = *config.public_key.as_bytes();
+            let endpoint = config.pq_endpoint_addr();
+            let base_psk = config
+                .psk
+                .clone()
+                .unwrap_or_else(|| SecretKey::from([0u8; 32]));
+            let auth_keys = private_keys
+                .iter()
+                .map(|key| {
+                    let shared = key.diffie_hellman(&PublicKey::from(public_key));
+                    SecretKey::from(mac(shared.as_bytes(), &[AUTH_LABEL, base_psk.as_bytes()]))
+                })
+                .collect();
+
+            peers.push(Arc::new(ExchangePeer {
+                public_key,
+                endpoint,
+                auth_keys,
+                base_psk,
+            }));
+        }
+
+        self.state
+            .lock()
+            .unwrap()
+            .retain(|key, _| peers.iter().any(|p| &p.public_key == key));
+        *self.peers.write().unwrap() = peers;
+        Ok(())
+    }
+
+    /// 启动接收和定期发起交换的任务
+    pub fn spawn(self: &Arc<Self>) -> Vec<JoinHandle<()>> {
+        info!(
+            "Starting post-quantum key exchange on {}",
+            self.transport
+                .local_addr()
+                .map(|a| a.to_string())
+                .unwrap_or_default()
+        );
+        let receiver = self.clone();
+        let initiator = self.clone();
+        vec![
+            tokio::spawn(async move { receiver.receive_loop().await }),
+            tokio::spawn(async move { initiator.rotation_loop().await }),
+        ]
+    }
+
+    /// 对配置了交换地址的对等体按间隔发起交换
+    async fn rotation_loop(&self) {
+        let mut ticker = tokio::time::interval(RETRY_INTERVAL.min(self.interval));
+        loop {
+            ticker.tick().await;
+
+            let peers = self.peers.read().unwrap().clone();
+            for peer in peers {
+                let Some(endpoint) = peer.endpoint else {
+                    continue;
+                };
+                let Some(message) = self.init_hello(&peer) else {
+                    continue;
+                };
+                if let Err(e) = self.transport.send_to(&message, endpoint).await {
+                    warn!("Failed to send key exchange to {}: {}", endpoint, e);
+                }
+            }
+        }
+    }
+
+    /// 需要轮换时生成临时密钥对并构造发起消息
+    fn init_hello(&self, peer: &ExchangePeer) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        let state = state.entry(peer.public_key).or_default();
+        if state.rotated.is_some_and(|t| t.elapsed() < self.interval)
+            || state
+                .pending
+                .as_ref()
+                .is_some_and(|p| p.sent.elapsed() < RETRY_INTERVAL)
+        {
+            return None;
+        }
+
+        let (decapsulation_key, encapsulation_key) = MlKem768::generate_keypair();
+        let id = rand::thread_rng().gen::<u32>();
+        let mut message = Vec::with_capacity(INIT_LEN);
+        message.push(INIT_HELLO);
+        message.extend_from_slice(&id.to_be_bytes());
+        message.extend_from_slice(&timestamp().to_be_bytes());
+        message.extend_from_slice(&encapsulation_key.to_bytes());
+        let init_mac = mac(peer.auth_keys[0].as_bytes(), &[&message]);
+        message.extend_from_slice(&init_mac);
+
+        state.pending = Some(Pending {
+            id,
+            decapsulation_key,
+            init_mac,
+            sent: Instant::now(),
+        });
+        Some(message)
+    }
+
+    async fn receive_loop(&self) {
+        let mut buf = vec![0u8; INIT_LEN + 1];
+        loop {
+            let (len, from) = match self.transport.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return,
+                Err(e) => {
+                    warn!("Failed to receive key exchange message: {}", e);
+                    continue;
+                }
+            };
+
+            let result = match (buf[0], len) {
+                (INIT_HELLO, INIT_LEN) => self.handle_init(&buf[..len], from).await,
+                (RESP_HELLO, RESP_LEN) => self.handle_response(&buf[..len]).await,
+                _ => Err(Error::CryptoError("Unknown message".to_string())),
+            };
+            if let Err(e) = result {
+                debug!("Dropping key exchange message from {}: {}", from, e);
+            }
+        }
+    }
+
+    /// 响应方：封装共享密钥，安装新密钥后回复
+    async fn handle_init(&self, message: &[u8], from: SocketAddr) -> Result<()> {
+        let (peer, auth_key) = self
+            .authenticate(message)
+            .ok_or_else(|| Error::CryptoError("Unauthenticated message".to_string()))?;
+
+        let (header, rest) = message.split_at(HEADER_LEN);
+        let (timestamp, rest) = rest.split_at(TIMESTAMP_LEN);
+        let (encapsulation_key, init_mac) = rest.split_at(EK_LEN);
+        let timestamp = u64::from_be_bytes(timestamp.try_into().unwrap());
+        {
+            let mut state = self.state.lock().unwrap();
+            let state = state.entry(peer.public_key).or_default();
+            if timestamp <= state.last_timestamp {
+                return Err(Error::CryptoError("Replayed message".to_string()));
+            }
+            state.last_timestamp = timestamp;
+        }
+
+        let encapsulation_key = EncapsulationKey::new_from_slice(encapsulation_key)
+            .map_err(|e| Error::CryptoError(format!("Invalid encapsulation key: {}", e)))?;
+        let (ciphertext, shared) = encapsulation_key.encapsulate();
+
+        let mut response = Vec::with_capacity(RESP_LEN);
+        response.push(RESP_HELLO);
+        response.extend_from_slice(&header[1..]);
+        response.extend_from_slice(&ciphertext);
+        let resp_mac = mac(auth_key.as_bytes(), &[&response]);
+        response.extend_from_slice(&resp_mac);
+
+        let psk = derive_psk(&shared, init_mac, &resp_mac, peer.base_psk.as_bytes());
+        self.install(&peer, psk, false).await?;
+        self.transport
+            .send_to(&response, from)
+            .await
+            .map_err(|e| Error::NetworkError(format!("Failed to send key exchange: {}", e)))?;
+        Ok(())
+    }
+
+    /// 发起方：解封共享密钥，安装新密钥并重新握手
+    async fn handle_response(&self, message: &[u8]) -> Result<()> {
+        let id = u32::from_be_bytes(message[1..HEADER_LEN].try_into().unwrap());
+        let (peer, pending) = {
+            let peers = self.peers.read().unwrap();
+            let mut state = self.state.lock().unwrap();
+            peers
+                .iter()
+                .find_map(|peer| {
+                    let state = state.get_mut(&peer.public_key)?;
+                    if state.pending.as_ref()?.id != id || !peer.verify(message) {
+                        return None;
+                    }
+                    Some((peer.clone(), state.pending.take()?))
+                })
+                .ok_or_else(|| Error::CryptoError("Unexpected response".to_string()))?
+        };
+
+        let (body, resp_mac) = message.split_at(RESP_LEN - MAC_LEN);
+        let shared = pending
+            .decapsulation_key
+            .decapsulate_slice(&body[HEADER_LEN..])
+            .map_err(|e| Error::CryptoError(format!("Invalid ciphertext: {}", e)))?;
+
+        let psk = derive_psk(
+            &shared,
+            &pending.init_mac,
+            resp_mac,
+            peer.base_psk.as_bytes(),
+        );
+        self.install(&peer, psk, true).await?;
+        if let Some(state) = self.state.lock().unwrap().get_mut(&peer.public_key) {
+            state.rotated = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    /// 找到能验证消息的对等体及其使用的认证密钥
+    fn authenticate(&self, message: &[u8]) -> Option<(Arc<ExchangePeer>, SecretKey)> {
+        self.peers.read().unwrap().iter().find_map(|peer| {
+            peer.auth_keys
+                .iter()
+                .find(|key| verify(key.as_bytes(), message))
+                .map(|key| (peer.clone(), key.clone()))
+        })
+    }
+
+    async fn install(&self, peer: &ExchangePeer, psk: SecretKey, initiate: bool) -> Result<()> {
+        self.tunnel
+            .set_preshared_key(&peer.public_key, psk.clone(), initiate)
+            .await?;
+        for status in self.status.write().await.iter_mut() {
+            if status.public_key.as_bytes() == &peer.public_key {
+                status.psk = Some(psk.clone());
+            }
+        }
+        info!(
+            "Rotated preshared key for peer {}",
+            types::PublicKey::from(peer.public_key).short()
+        );
+        Ok(())
+    }
+}
+
+/// 新的预共享密钥：由 ML-KEM 共享密钥、双方消息和配置的预共享密钥派生
+fn derive_psk(shared: &[u8], init_mac: &[u8], resp_mac: &[u8], base_psk: &[u8]) -> SecretKey {
+    SecretKey::from(mac(shared, &[PSK_LABEL, init_mac, resp_mac, base_psk]))
+}
+
+fn mac(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut mac =
+        <Blake2sMac256 as Mac>::new_from_slice(key).expect("32-byte key is valid for BLAKE2s");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// 以常数时间校验消息末尾的 MAC
+fn verify(key: &[u8], message: &[u8]) -> bool {
+    let (body, tag) = message.split_at(message.len() - MAC_LEN);
+    let mut mac =
+        <Blake2sMac256 as Mac>::new_from_slice(key).expect("32-byte key is valid for BLAKE2s");
+    mac.update(body);
+    mac.verify_slice(tag).is_ok()
+}
+
+/// 发起时间戳（纳秒），响应方只接受递增的时间戳
+fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
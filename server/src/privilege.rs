@@ -0,0 +1,275 @@
+use crate::config::ServerConfig;
+use crate::error::{Error, Result};
+use caps::{CapSet, Capability, CapsHashSet};
+use log::info;
+use nix::sys::prctl;
+use nix::unistd::{self, Gid, Group, Uid, User};
+
+/// 降权后仍需保留的能力
+///
+/// 路由和 NAT 变更需要 CAP_NET_ADMIN；接口位于其他网络命名空间时，`ip -n` 和 `setns` 还需要 CAP_SYS_ADMIN。
+pub fn required_capabilities(config: &ServerConfig) -> CapsHashSet {
+    let mut keep = CapsHashSet::new();
+    keep.insert(Capability::CAP_NET_ADMIN);
+    if config.interface.netns.is_some() {
+        keep.insert(Capability::CAP_SYS_ADMIN);
+    }
+    keep
+}
+
+/// 收紧能力边界集并设置 `PR_SET_NO_NEW_PRIVS`
+///
+/// 两者都是线程级属性，只由之后创建的线程继承，因此必须在运行时创建工作线程之前调用。
+pub fn restrict_process(keep: &CapsHashSet) -> Result<()> {
+    let threads = thread_ids()?.len();
+    if threads != 1 {
+        return Err(Error::SecurityError(format!(
+            "Process restrictions must be applied before threads start ({} running)",
+            threads
+        )));
+    }
+    restrict_thread(keep)
+}
+
+fn restrict_thread(keep: &CapsHashSet) -> Result<()> {
+    let bounding =
+        caps::read(None, CapSet::Bounding).map_err(|e| security_error("Failed to read caps", e))?;
+    for cap in bounding.difference(keep) {
+        caps::drop(None, CapSet::Bounding, *cap)
+            .map_err(|e| security_error("Failed to drop bounding capability", e))?;
+    }
+    prctl::set_no_new_privs().map_err(|e| security_error("Failed to set no_new_privs", e))
+}
+
+/// 切换到指定用户和组，只保留 `keep` 中的能力
+///
+/// 调用前须已通过 [`restrict_process`] 收紧边界集。glibc 会把 `setresuid` 等调用广播到所有线程，
+/// 未设置 keepcaps 的工作线程随之失去全部能力；只有调用线程保留 `keep`，
+/// 并同时设为 ambient，使 `ip`、`nft` 等子进程可以继承。
+pub fn drop_privileges(user: &str, group: Option<&str>, keep: &CapsHashSet) -> Result<()> {
+    if !Uid::effective().is_root() {
+        return Err(Error::SecurityError(
+            "Dropping privileges requires starting as root".to_string(),
+        ));
+    }
+
+    let user = User::from_name(user)
+        .map_err(|e| security_error("Failed to look up user", e))?
+        .ok_or_else(|| Error::SecurityError(format!("Unknown user: {}", user)))?;
+    let gid = match group {
+        Some(name) => {
+            Group::from_name(name)
+                .map_err(|e| security_error("Failed to look up group", e))?
+                .ok_or_else(|| Error::SecurityError(format!("Unknown group: {}", name)))?
+                .gid
+        }
+        None => user.gid,
+    };
+    let uid = user.uid;
+    if uid.is_root() {
+        return Err(Error::SecurityError(
+            "Refusing to drop privileges to root".to_string(),
+        ));
+    }
+
+    info!(
+        "Dropping privileges to {} (uid {}, gid {}), keeping {:?}",
+        user.name, uid, gid, keep
+    );
+
+    prctl::set_keepcaps(true).map_err(|e| security_error("Failed to set keepcaps", e))?;
+    unistd::setgroups(&[gid]).map_err(|e| security_error("Failed to set groups", e))?;
+    unistd::setresgid(gid, gid, gid).map_err(|e| security_error("Failed to set gid", e))?;
+    unistd::setresuid(uid, uid, uid).map_err(|e| security_error("Failed to set uid", e))?;
+    prctl::set_keepcaps(false).map_err(|e| security_error("Failed to clear keepcaps", e))?;
+
+    for set in [CapSet::Permitted, CapSet::Effective, CapSet::Inheritable] {
+        caps::set(None, set, keep).map_err(|e| security_error("Failed to set capabilities", e))?;
+    }
+    caps::clear(None, CapSet::Ambient)
+        .map_err(|e| security_error("Failed to clear ambient capabilities", e))?;
+    for cap in keep {
+        caps::raise(None, CapSet::Ambient, *cap)
+            .map_err(|e| security_error("Failed to raise ambient capability", e))?;
+    }
+
+    verify(uid, gid, keep)
+}
+
+/// 确认降权生效且无法恢复 root
+fn verify(uid: Uid, gid: Gid, keep: &CapsHashSet) -> Result<()> {
+    if Uid::current() != uid || Uid::effective() != uid || Gid::effective() != gid {
+        return Err(Error::SecurityError(
+            "Process credentials did not change".to_string(),
+        ));
+    }
+    if unistd::setresuid(Uid::from_raw(0), Uid::from_raw(0), Uid::from_raw(0)).is_ok() {
+        return Err(Error::SecurityError(
+            "Process was able to regain root".to_string(),
+        ));
+    }
+
+    let permitted = caps::read(None, CapSet::Permitted)
+        .map_err(|e| security_error("Failed to read caps", e))?;
+    if &permitted != keep {
+        return Err(Error::SecurityError(format!(
+            "Unexpected permitted capabilities: {:?}",
+            permitted
+        )));
+    }
+
+    // 逐个检查所有线程，任何线程都不能保留 root 身份或额外的能力
+    let mask = keep.iter().fold(0, |mask, cap| mask | cap.bitmask());
+    for tid in thread_ids()? {
+        let status = ThreadStatus::read(tid)?;
+        if status.uids.iter().any(|id| *id != uid.as_raw()) {
+            return Err(Error::SecurityError(format!(
+                "Thread {} still runs with uids {:?}",
+                tid, status.uids
+            )));
+        }
+        if !status.no_new_privs {
+            return Err(Error::SecurityError(format!(
+                "Thread {} can still gain privileges on exec",
+                tid
+            )));
+        }
+        if (status.bounding | status.permitted) & !mask != 0 {
+            return Err(Error::SecurityError(format!(
+                "Thread {} keeps capabilities outside {:?}",
+                tid, keep
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// 当前进程的所有线程号
+fn thread_ids() -> Result<Vec<i32>> {
+    let mut tids = Vec::new();
+    let entries = std::fs::read_dir("/proc/self/task")
+        .map_err(|e| security_error("Failed to list threads", e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| security_error("Failed to list threads", e))?;
+        if let Some(tid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        {
+            tids.push(tid);
+        }
+    }
+    Ok(tids)
+}
+
+/// `/proc/self/task/<tid>/status` 中与权限相关的字段
+#[derive(Debug, Default)]
+struct ThreadStatus {
+    uids: Vec<u32>,
+    no_new_privs: bool,
+    permitted: u64,
+    bounding: u64,
+}
+
+impl ThreadStatus {
+    fn read(tid: i32) -> Result<Self> {
+        let path = format!("/proc/self/task/{}/status", tid);
+        let content = std::fs::read_to_string(&path)
+            .map_err(|e| security_error("Failed to read thread status", e))?;
+        let mut status = ThreadStatus::default();
+        for line in content.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match key {
+                "Uid" => {
+                    status.uids = value
+                        .split_whitespace()
+                        .filter_map(|id| id.parse().ok())
+                        .collect()
+                }
+                "NoNewPrivs" => status.no_new_privs = value == "1",
+                "CapPrm" => status.permitted = parse_mask(value)?,
+                "CapBnd" => status.bounding = parse_mask(value)?,
+                _ => {}
+            }
+        }
+        Ok(status)
+    }
+}
+
+fn parse_mask(value: &str) -> Result<u64> {
+    u64::from_str_radix(value, 16).map_err(|e| security_error("Invalid capability mask", e))
+}
+
+fn security_error(context: &str, e: impl std::fmt::Display) -> Error {
+    Error::SecurityError(format!("{}: {}", context, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::InterfaceConfig;
+    use crate::migrate::SERVER_VERSION;
+    use crate::secret::SecretKey;
+
+    #[test]
+    fn test_required_capabilities() {
+        let mut config = ServerConfig {
+            version: SERVER_VERSION,
+            interface: InterfaceConfig {
+                name: "wg0".to_string(),
+                private_key: Some(SecretKey::from([7u8; 32])),
+                address: "10.8.0.1/24".parse().unwrap(),
+                listen_port: 51820,
+                ..Default::default()
+            },
+            nat: None,
+            stream: None,
+            security: None,
+            pq: None,
+            ipam: None,
+            peers_dir: None,
+            peers: vec![],
+        };
+
+        let keep = required_capabilities(&config);
+        assert_eq!(keep.len(), 1);
+        assert!(keep.contains(&Capability::CAP_NET_ADMIN));
+
+        config.interface.netns = Some("tenant-a".to_string());
+        assert!(required_capabilities(&config).contains(&Capability::CAP_SYS_ADMIN));
+    }
+
+    #[test]
+    fn test_restrictions_inherited_by_threads() {
+        if !caps::has_cap(None, CapSet::Effective, Capability::CAP_SETPCAP).unwrap() {
+            return;
+        }
+        let mut keep = CapsHashSet::new();
+        keep.insert(Capability::CAP_NET_ADMIN);
+
+        // 测试进程已有其他线程，限制只施加在独立线程及其子线程上
+        let status = std::thread::spawn(move || {
+            restrict_thread(&keep).unwrap();
+            std::thread::spawn(|| {
+                let status = ThreadStatus::read(unistd::gettid().as_raw()).unwrap();
+                let bounding = caps::read(None, CapSet::Bounding).unwrap();
+                (status, bounding)
+            })
+            .join()
+            .unwrap()
+        })
+        .join()
+        .unwrap();
+
+        let (status, bounding) = status;
+        assert!(status.no_new_privs);
+        assert_eq!(status.bounding, Capability::CAP_NET_ADMIN.bitmask());
+        assert_eq!(bounding.len(), 1);
+        assert!(bounding.contains(&Capability::CAP_NET_ADMIN));
+        assert!(restrict_process(&CapsHashSet::new()).is_err());
+    }
+}
@@ -0,0 +1,398 @@
+use crate::config::{self, ClientConfig, PeerConfig, ServerConfig};
+use crate::crypto;
+use crate::error::{Error, Result};
+use crate::ipam::AddressPool;
+use crate::migrate::{self, Kind, CLIENT_VERSION};
+use crate::types::{Endpoint, PublicKey};
+use ipnet::IpNet;
+use std::fs;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::path::Path;
+use toml_edit::{ArrayOfTables, DocumentMut, Item};
+
+/// `add-peer` 的参数
+#[derive(Debug, Clone, Default)]
+pub struct NewPeer {
+    /// 对等体名称，在服务器配置中唯一
+    pub name: String,
+    /// 客户端连接的服务器地址，未写端口时使用 `listen_port`
+    pub endpoint: String,
+    /// 同时生成预共享密钥
+    pub psk: bool,
+    /// 客户端经隧道路由的地址范围，为空时路由全部流量
+    pub allowed_ips: Vec<IpNet>,
+    /// 客户端的 DNS 服务器
+    pub dns: Vec<String>,
+    /// 客户端的保活间隔（秒，可选）
+    pub persistent_keepalive: Option<u16>,
+}
+
+/// 为新对等体生成密钥并从地址池分配隧道地址，将其加入服务器配置，返回客户端配置
+pub fn add_peer(
+    config: &mut ServerConfig,
+    pool: &mut AddressPool,
+    new: &NewPeer,
+) -> Result<ClientConfig> {
+    if new.name.trim().is_empty() {
+        return Err(Error::ConfigError(
+            "Peer name must not be empty".to_string(),
+        ));
+    }
+    // 名称同时用作客户端配置和 `peers_dir` 中的文件名
+    let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+    if new.name.starts_with('.') || !new.name.chars().all(valid) {
+        return Err(Error::ConfigError(format!(
+            "Peer name {:?} may only contain letters, digits, '-', '_' and '.'",
+            new.name
+        )));
+    }
+    if config
+        .peers
+        .iter()
+        .any(|p| p.name.as_deref() == Some(new.name.as_str()))
+    {
+        return Err(Error::ConfigError(format!(
+            "A peer named {} already exists",
+            new.name
+        )));
+    }
+
+    let (private_key, public_key) = crypto::generate_keypair()?;
+    let psk = new.psk.then(crypto::generate_psk).transpose()?;
+    let server_public_key = crypto::public_key(config.interface.private_key()?);
+    let routes = pool.allocate(&public_key)?;
+    // 客户端地址使用接口子网的前缀长度
+    let addresses: Vec<IpNet> = pool
+        .networks()
+        .iter()
+        .zip(&routes)
+        .map(|(network, route)| {
+            IpNet::new(route.addr(), network.prefix_len())
+                .expect("prefix comes from the pool network")
+        })
+        .collect();
+
+    let server_endpoint =
+        with_port(&new.endpoint, config.interface.listen_port).parse::<Endpoint>()?;
+    config.peers.push(PeerConfig {
+        name: Some(new.name.clone()),
+        public_key,
+        allowed_ips: routes,
+        psk: psk.clone(),
+        ..Default::default()
+    });
+
+    Ok(ClientConfig {
+        version: CLIENT_VERSION,
+        private_key: Some(private_key),
+        private_key_file: None,
+        private_key_env: None,
+        private_key_credential: None,
+        address: addresses,
+        server_public_key,
+        server_endpoint,
+        allowed_ips: new.allowed_ips.clone(),
+        persistent_keepalive: new.persistent_keepalive,
+        psk,
+        psk_file: None,
+        psk_env: None,
+        psk_credential: None,
+        dns: (!new.dns.is_empty()).then(|| new.dns.clone()),
+        mtu: config.interface.mtu,
+        post_up: vec![],
+        pre_down: vec![],
+    })
+}
+
+/// 按名称删除对等体并释放其地址，返回被删除的配置
+pub fn remove_peer(
+    config: &mut ServerConfig,
+    pool: &mut AddressPool,
+    name: &str,
+) -> Result<PeerConfig> {
+    let index = config
+        .peers
+        .iter()
+        .position(|p| p.name.as_deref() == Some(name))
+        .ok_or_else(|| Error::ConfigError(format!("No peer named {}", name)))?;
+    let peer = config.peers.remove(index);
+    pool.release(&peer.public_key);
+    Ok(peer)
+}
+
+/// 修改配置文件前确认其为当前版本，旧版本需要先运行 `migrate`
+pub fn require_current_version(config_path: &Path) -> Result<()> {
+    let content = read_config(config_path)?;
+    match migrate::upgrade(&content, Kind::Server, false)? {
+        Some(upgraded) => Err(Error::ConfigError(format!(
+            "{:?} uses configuration version {}; run `migrate` before changing peers",
+            config_path, upgraded.from
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// 在 `path`（主配置文件或 `peers_dir` 中的文件）末尾追加对等体的 `[[peers]]` 表
+///
+/// 只改动新增的表，原有的注释和格式保持不变；文件不存在时创建。
+pub fn append_peer(path: &Path, peer: &PeerConfig) -> Result<()> {
+    let mut document = match fs::read_to_string(path) {
+        Ok(content) => parse_document(path, &content)?,
+        Err(e) if e.kind() == ErrorKind::NotFound => DocumentMut::new(),
+        Err(e) => {
+            return Err(Error::ConfigError(format!(
+                "Failed to read {:?}: {}",
+                path, e
+            )))
+        }
+    };
+    let serialized = toml::to_string(peer)
+        .map_err(|e| Error::ConfigError(format!("Failed to serialize peer: {}", e)))?;
+    let mut table = parse_document(path, &serialized)?.as_table().clone();
+    if !document.as_table().is_empty() {
+        table.decor_mut().set_prefix("\n");
+    }
+
+    let peers = document
+        .entry("peers")
+        .or_insert_with(|| Item::ArrayOfTables(ArrayOfTables::new()));
+    let Some(peers) = peers.as_array_of_tables_mut() else {
+        return Err(Error::ConfigError(format!(
+            "{:?}: peers must be written as [[peers]] tables",
+            path
+        )));
+    };
+    peers.push(table);
+    write_document(path, &document)
+}
+
+/// 从 `path` 中删除公钥为 `public_key` 的 `[[peers]]` 表，其余内容保持不变，返回剩余的对等体数
+pub fn delete_peer(path: &Path, public_key: &PublicKey) -> Result<usize> {
+    let mut document = parse_document(path, &read_config(path)?)?;
+    let Some(peers) = document
+        .get_mut("peers")
+        .and_then(Item::as_array_of_tables_mut)
+    else {
+        return Err(Error::ConfigError(format!(
+            "{:?} has no [[peers]] tables",
+            path
+        )));
+    };
+    let index = peers
+        .iter()
+        .position(|table| {
+            table
+                .get("public_key")
+                .and_then(Item::as_str)
+                .and_then(|key| key.parse::<PublicKey>().ok())
+                .as_ref()
+                == Some(public_key)
+        })
+        .ok_or_else(|| {
+            Error::ConfigError(format!("{:?} has no peer {}", path, public_key.short()))
+        })?;
+    peers.remove(index);
+    let remaining = peers.len();
+    write_document(path, &document)?;
+    Ok(remaining)
+}
+
+fn read_config(path: &Path) -> Result<String> {
+    fs::read_to_string(path)
+        .map_err(|e| Error::ConfigError(format!("Failed to read {:?}: {}", path, e)))
+}
+
+fn parse_document(path: &Path, content: &str) -> Result<DocumentMut> {
+    content
+        .parse()
+        .map_err(|e| Error::ConfigError(format!("Failed to parse {:?}: {}", path, e)))
+}
+
+fn write_document(path: &Path, document: &DocumentMut) -> Result<()> {
+    config::write_atomic(path, &document.to_string())
+        .map_err(|e| Error::ConfigError(format!("Failed to write config file {:?}: {}", path, e)))
+}
+
+/// 端点未写端口时补上监听端口
+fn with_port(endpoint: &str, port: u16) -> String {
+    let has_port = endpoint.parse::<SocketAddr>().is_ok()
+        || endpoint
+            .rsplit_once(':')
+            .is_some_and(|(host, p)| !host.contains(':') && p.parse::<u16>().is_ok());
+    if has_port {
+        endpoint.to_string()
+    } else if endpoint.contains(':') && !endpoint.starts_with('[') {
+        format!("[{}]:{}", endpoint, port)
+    } else {
+        format!("{}:{}", endpoint, port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::PublicKey;
+
+    fn net(s: &str) -> IpNet {
+        s.parse().unwrap()
+    }
+
+    fn server_config() -> ServerConfig {
+        let (private_key, _) = crypto::generate_keypair().unwrap();
+        toml::from_str(&format!(
+            r#"
+[interface]
+name = "wg0"
+private_key = "{}"
+address = "10.8.0.1/29"
+listen_port = 51820
+
+[[peers]]
+public_key = "{}"
+allowed_ips = "10.8.0.2/32"
+
+[[peers]]
+public_key = "{}"
+allowed_ips = "10.8.0.4/31, 0.0.0.0/0"
+"#,
+            private_key.to_base64().expose(),
+            PublicKey::from([1u8; 32]),
+            PublicKey::from([2u8; 32])
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_add_and_remove_peer() {
+        let mut config = server_config();
+        let mut pool = AddressPool::from_config(&config).unwrap();
+        let new = NewPeer {
+            name: "alice".to_string(),
+            endpoint: "vpn.example.com".to_string(),
+            psk: true,
+            ..Default::default()
+        };
+
+        let client = add_peer(&mut config, &mut pool, &new).unwrap();
+        assert_eq!(client.address, vec![net("10.8.0.3/29")]);
+        assert_eq!(client.server_endpoint.to_string(), "vpn.example.com:51820");
+        assert_eq!(
+            client.server_public_key,
+            crypto::public_key(config.interface.private_key().unwrap())
+        );
+        let peer = config.peers.last().unwrap();
+        assert_eq!(peer.allowed_ips, vec![net("10.8.0.3/32")]);
+        assert_eq!(peer.psk, client.psk);
+        assert_eq!(
+            peer.public_key,
+            crypto::public_key(client.private_key.as_ref().unwrap())
+        );
+
+        // 名称唯一且可用作文件名，.4/.5 被网关占用，.6 是最后一个可用地址
+        assert!(add_peer(&mut config, &mut pool, &new).is_err());
+        let unsafe_name = NewPeer {
+            name: "../alice".to_string(),
+            ..new.clone()
+        };
+        assert!(add_peer(&mut config, &mut pool, &unsafe_name).is_err());
+        let bob = NewPeer {
+            name: "bob".to_string(),
+            ..new.clone()
+        };
+        assert_eq!(
+            add_peer(&mut config, &mut pool, &bob).unwrap().address,
+            vec![net("10.8.0.6/29")]
+        );
+        let carol = NewPeer {
+            name: "carol".to_string(),
+            ..new
+        };
+        assert!(add_peer(&mut config, &mut pool, &carol)
+            .unwrap_err()
+            .to_string()
+            .contains("No free address left in 10.8.0.0/29"));
+
+        assert_eq!(
+            remove_peer(&mut config, &mut pool, "alice")
+                .unwrap()
+                .allowed_ips,
+            vec![net("10.8.0.3/32")]
+        );
+        assert!(remove_peer(&mut config, &mut pool, "alice").is_err());
+        let dave = pool.allocate(&PublicKey::from([4u8; 32])).unwrap();
+        assert_eq!(dave[0].to_string(), "10.8.0.3/32");
+    }
+
+    #[test]
+    fn test_edit_config_file() {
+        let dir = std::env::temp_dir().join(format!("rusty-tunnel-edit-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (private_key, _) = crypto::generate_keypair().unwrap();
+        let original = format!(
+            r#"version = 2
+# 服务器配置
+
+[interface]
+name = "wg0"
+private_key = "{}"
+address = "10.8.0.1/24"   # 隧道网段
+listen_port = 51820
+
+# 手工添加的对等体
+[[peers]]
+public_key = "{}"
+allowed_ips = "10.8.0.2/32"
+"#,
+            private_key.to_base64().expose(),
+            PublicKey::from([1u8; 32])
+        );
+        let path = dir.join("server.toml");
+        fs::write(&path, &original).unwrap();
+        require_current_version(&path).unwrap();
+
+        let peer = PeerConfig {
+            name: Some("alice".to_string()),
+            public_key: PublicKey::from([2u8; 32]),
+            allowed_ips: vec![net("10.8.0.3/32")],
+            ..Default::default()
+        };
+        append_peer(&path, &peer).unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.starts_with(&original), "{}", content);
+        let config: ServerConfig = toml::from_str(&content).unwrap();
+        assert_eq!(config.peers.len(), 2);
+        assert_eq!(config.peers[1].name.as_deref(), Some("alice"));
+
+        assert_eq!(delete_peer(&path, &PublicKey::from([2u8; 32])).unwrap(), 1);
+        assert_eq!(fs::read_to_string(&path).unwrap(), original);
+        assert!(delete_peer(&path, &PublicKey::from([3u8; 32])).is_err());
+
+        // peers_dir 中的新文件
+        let file = dir.join("alice.toml");
+        append_peer(&file, &peer).unwrap();
+        assert!(fs::read_to_string(&file).unwrap().starts_with("[[peers]]"));
+        assert_eq!(delete_peer(&file, &peer.public_key).unwrap(), 0);
+
+        // 旧版本的配置需要先迁移
+        fs::write(&path, original.replace("version = 2\n", "")).unwrap();
+        assert!(require_current_version(&path)
+            .unwrap_err()
+            .to_string()
+            .contains("run `migrate`"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_endpoint_port() {
+        assert_eq!(with_port("vpn.example.com", 51820), "vpn.example.com:51820");
+        assert_eq!(
+            with_port("vpn.example.com:443", 51820),
+            "vpn.example.com:443"
+        );
+        assert_eq!(with_port("203.0.113.5", 51820), "203.0.113.5:51820");
+        assert_eq!(with_port("2001:db8::1", 51820), "[2001:db8::1]:51820");
+        assert_eq!(with_port("[2001:db8::1]:443", 51820), "[2001:db8::1]:443");
+    }
+}
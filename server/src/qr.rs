@@ -0,0 +1,148 @@
+use crate::error::{Error, Result};
+use qrcode::render::{svg, unicode};
+use qrcode::types::QrError;
+use qrcode::{Color, EcLevel, QrCode};
+
+/// 二维码（40 版本、L 纠错级别）能容纳的最大字节数
+pub const MAX_PAYLOAD: usize = 2953;
+
+/// PNG 中每个模块的像素边长
+const PNG_MODULE_SIZE: usize = 8;
+
+/// 二维码四周空白区的模块数
+const QUIET_ZONE: usize = 4;
+
+/// 将配置文本编码为二维码
+///
+/// 使用最低纠错级别以得到最小的版本，屏幕显示不会出现污损。
+pub fn encode(text: &str) -> Result<QrCode> {
+    QrCode::with_error_correction_level(text, EcLevel::L).map_err(|e| match e {
+        QrError::DataTooLong => Error::ConfigError(format!(
+            "Configuration is {} bytes, more than a QR code can hold ({} bytes)",
+            text.len(),
+            MAX_PAYLOAD
+        )),
+        e => Error::ConfigError(format!("Failed to encode QR code: {}", e)),
+    })
+}
+
+/// 渲染为终端中显示的 UTF-8 方块字符，每个字符表示上下两个模块
+///
+/// 深色模块输出为空白，适合深色背景的终端。
+pub fn to_terminal(code: &QrCode) -> String {
+    code.render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .quiet_zone(true)
+        .build()
+}
+
+/// 渲染为 SVG 图像
+pub fn to_svg(code: &QrCode) -> String {
+    code.render::<svg::Color>()
+        .min_dimensions(256, 256)
+        .quiet_zone(true)
+        .build()
+}
+
+/// 渲染为 8 位灰度 PNG 图像
+pub fn to_png(code: &QrCode) -> Result<Vec<u8>> {
+    let width = code.width();
+    let side = (width + 2 * QUIET_ZONE) * PNG_MODULE_SIZE;
+    let colors = code.to_colors();
+
+    let mut pixels = vec![0xff; side * side];
+    for (i, color) in colors.iter().enumerate() {
+        if *color == Color::Light {
+            continue;
+        }
+        let x = (i % width + QUIET_ZONE) * PNG_MODULE_SIZE;
+        let y = (i / width + QUIET_ZONE) * PNG_MODULE_SIZE;
+        for row in y..y + PNG_MODULE_SIZE {
+            pixels[row * side + x..row * side + x + PNG_MODULE_SIZE].fill(0);
+        }
+    }
+
+    let mut image = Vec::new();
+    let mut encoder = png::Encoder::new(&mut image, side as u32, side as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .write_header()
+        .and_then(|mut writer| {
+            writer.write_image_data(&pixels)?;
+            writer.finish()
+        })
+        .map_err(|e| Error::Other(format!("Failed to encode PNG: {}", e)))?;
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 用独立的解码器识别图像中唯一的二维码，`dark(x, y)` 表示像素是否为深色
+    fn decode(width: usize, height: usize, dark: impl Fn(usize, usize) -> bool) -> String {
+        let mut image = rqrr::PreparedImage::prepare_from_bitmap(width, height, dark);
+        let grids = image.detect_grids();
+        assert_eq!(grids.len(), 1);
+        grids[0].decode().unwrap().1
+    }
+
+    fn client_config() -> String {
+        let (private_key, _) = crate::crypto::generate_keypair().unwrap();
+        let (_, server_public_key) = crate::crypto::generate_keypair().unwrap();
+        format!(
+            "[Interface]\nPrivateKey = {}\nAddress = 10.8.0.2/24\nDNS = 1.1.1.1\n\n\
+             [Peer]\nPublicKey = {}\nPresharedKey = {}\nAllowedIPs = 0.0.0.0/0, ::/0\n\
+             Endpoint = vpn.example.com:51820\nPersistentKeepalive = 25\n",
+            private_key.to_base64().expose(),
+            server_public_key,
+            crate::crypto::generate_psk().unwrap().to_base64().expose()
+        )
+    }
+
+    #[test]
+    fn test_terminal_and_png_decode_to_config() {
+        let config = client_config();
+        let code = encode(&config).unwrap();
+
+        // PNG
+        let png = to_png(&code).unwrap();
+        let mut reader = png::Decoder::new(std::io::Cursor::new(png))
+            .read_info()
+            .unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size().unwrap()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        let (width, height) = (info.width as usize, info.height as usize);
+        assert_eq!(
+            decode(width, height, |x, y| pixels[y * width + x] < 0x80),
+            config
+        );
+
+        // 终端输出：实心的半格表示浅色模块，每个字符放大为上下两个模块
+        const SCALE: usize = 4;
+        let art = to_terminal(&code);
+        let rows: Vec<Vec<char>> = art.lines().map(|line| line.chars().collect()).collect();
+        let side = rows[0].len();
+        let light = |x: usize, y: usize| match rows[y / 2][x] {
+            '\u{2588}' => true,
+            '\u{2580}' => y.is_multiple_of(2),
+            '\u{2584}' => !y.is_multiple_of(2),
+            _ => false,
+        };
+        let decoded = decode(side * SCALE, side * SCALE, |x, y| {
+            !light(x / SCALE, y / SCALE)
+        });
+        assert_eq!(decoded, config);
+
+        assert!(to_svg(&code).starts_with("<?xml"));
+    }
+
+    #[test]
+    fn test_payload_too_long() {
+        assert!(encode(&"x".repeat(MAX_PAYLOAD)).is_ok());
+        let err = encode(&"x".repeat(MAX_PAYLOAD + 1)).err().unwrap();
+        assert!(err.to_string().contains("2954 bytes"));
+    }
+}
@@ -0,0 +1,433 @@
+use crate::config::ServerConfig;
+use crate::error::{Error, Result};
+use landlock::{
+    path_beneath_rules, Access, AccessFs, RestrictSelfAttr, Ruleset, RulesetAttr,
+    RulesetCreatedAttr, RulesetStatus, ABI,
+};
+use log::{info, warn};
+use seccompiler::{
+    BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter,
+    SeccompRule,
+};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// 运行 `ip`、`nft` 等子命令所需的只读系统路径
+const SYSTEM_PATHS: &[&str] = &["/usr", "/bin", "/sbin", "/lib", "/lib64", "/etc"];
+
+/// 可读写的设备文件，子命令的标准输入输出重定向到这里
+const DEVICE_PATHS: &[&str] = &["/dev/null"];
+
+/// 只读的内核接口路径
+const KERNEL_PATHS: &[&str] = &["/proc", "/sys", "/run/netns"];
+
+/// 数据面和 tokio 运行时所需的系统调用
+///
+/// `socket` 只允许 [`SOCKET_FAMILIES`]，`clone` 只允许创建线程，见 [`syscall_filters`]。
+const ALLOWED_SYSCALLS: &[i64] = &[
+    // 文件和描述符
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_openat,
+    libc::SYS_close,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_lseek,
+    libc::SYS_getdents64,
+    libc::SYS_readlinkat,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_pipe2,
+    libc::SYS_getcwd,
+    // 原子写入状态文件，见 `config::write_atomic`
+    libc::SYS_fchmod,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_unlinkat,
+    // 网络
+    libc::SYS_socketpair,
+    libc::SYS_bind,
+    libc::SYS_connect,
+    libc::SYS_listen,
+    libc::SYS_accept4,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockopt,
+    libc::SYS_shutdown,
+    libc::SYS_recvfrom,
+    libc::SYS_sendto,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmmsg,
+    libc::SYS_sendmmsg,
+    // 事件循环
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_eventfd2,
+    libc::SYS_ppoll,
+    libc::SYS_inotify_add_watch,
+    libc::SYS_inotify_rm_watch,
+    libc::SYS_futex,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_nanosleep,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_clock_gettime,
+    libc::SYS_gettimeofday,
+    libc::SYS_getrandom,
+    // 内存
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_brk,
+    // 信号
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    libc::SYS_tgkill,
+    // 线程
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_set_robust_list,
+    libc::SYS_set_tid_address,
+    libc::SYS_rseq,
+    libc::SYS_prctl,
+    libc::SYS_prlimit64,
+    libc::SYS_capget,
+    libc::SYS_uname,
+    libc::SYS_sysinfo,
+    libc::SYS_gettid,
+    libc::SYS_getpid,
+    libc::SYS_getppid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_arch_prctl,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_access,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_open,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_lstat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_readlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_pipe,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_dup2,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rename,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_unlink,
+];
+
+/// 运行 `ip`、`nft` 子命令所需的系统调用，只在初始化之后仍要运行子命令时允许
+const COMMAND_SYSCALLS: &[i64] = &[libc::SYS_execve, libc::SYS_wait4, libc::SYS_waitid];
+
+/// 允许创建的套接字协议族：UDP 和 TCP 隧道、路由和 WireGuard netlink、DNS 查询使用的本地套接字
+const SOCKET_FAMILIES: &[libc::c_int] = &[
+    libc::AF_UNIX,
+    libc::AF_INET,
+    libc::AF_INET6,
+    libc::AF_NETLINK,
+];
+
+/// 进程沙箱：Landlock 文件系统限制和 seccomp 系统调用白名单
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    /// 配置文件所在目录（只读，用于 SIGHUP 重载）
+    config_dir: PathBuf,
+    /// 对等体目录（只读，可选，用于 SIGHUP 重载）
+    peers_dir: Option<PathBuf>,
+    /// 状态目录（可读写，可选）
+    state_dir: Option<PathBuf>,
+    /// 密钥文件和 systemd 凭据目录（只读，用于 SIGHUP 重载）
+    secret_paths: Vec<PathBuf>,
+    /// 是否允许运行子命令
+    commands: bool,
+}
+
+impl Sandbox {
+    /// 根据配置创建沙箱
+    ///
+    /// `ip netns exec` 和删除命名空间需要挂载操作，Landlock 下无法执行，因此不支持与 `netns` 同时使用。
+    ///
+    /// 只有配置了 NAT，或有对等体路由不在接口网段内时，初始化之后才需要运行 `ip`、`nft`；
+    /// 否则禁止 `execve` 和创建子进程。
+    pub fn new(config_path: &Path, config: &ServerConfig) -> Result<Self> {
+        if config.interface.netns.is_some() {
+            return Err(Error::ConfigError(
+                "security.sandbox cannot be combined with interface.netns".to_string(),
+            ));
+        }
+
+        let config_path = config_path
+            .canonicalize()
+            .map_err(|e| Error::ConfigError(format!("Failed to resolve config path: {}", e)))?;
+        let config_dir = config_path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| PathBuf::from("/"));
+        let state_dir = config
+            .security
+            .as_ref()
+            .and_then(|s| s.state_dir.as_ref())
+            .map(PathBuf::from);
+
+        Ok(Sandbox {
+            config_dir,
+            peers_dir: config.peers_dir(&config_path),
+            state_dir,
+            secret_paths: config.secret_paths(),
+            commands: needs_commands(config),
+        })
+    }
+
+    /// 安装 Landlock 规则集和 seccomp 过滤器，之后无法撤销
+    pub fn apply(&self) -> Result<()> {
+        self.restrict_filesystem(true)?;
+
+        let filters = syscall_filters(self.commands, SeccompAction::Errno(libc::EPERM as u32))?;
+        for program in &filters {
+            seccompiler::apply_filter_all_threads(program).map_err(|e| {
+                Error::SecurityError(format!("Failed to install seccomp filter: {}", e))
+            })?;
+        }
+
+        if self.commands {
+            info!("Sandbox enabled, running ip and nft is allowed");
+        } else {
+            info!("Sandbox enabled, running subprocesses is not allowed");
+        }
+        Ok(())
+    }
+
+    /// 限制文件系统访问，`all_threads` 为假时只限制当前线程
+    fn restrict_filesystem(&self, all_threads: bool) -> Result<()> {
+        let abi = ABI::V2;
+        let read_only = AccessFs::from_read(abi);
+        let existing = |paths: &[&str]| -> Vec<PathBuf> {
+            paths
+                .iter()
+                .map(PathBuf::from)
+                .filter(|p| p.exists())
+                .collect()
+        };
+
+        let device = AccessFs::ReadFile | AccessFs::WriteFile;
+
+        let status = Ruleset::default()
+            .handle_access(AccessFs::from_all(abi))
+            .and_then(|r| r.create())
+            .and_then(|r| r.add_rules(path_beneath_rules(existing(SYSTEM_PATHS), read_only)))
+            .and_then(|r| r.add_rules(path_beneath_rules(existing(KERNEL_PATHS), read_only)))
+            .and_then(|r| r.add_rules(path_beneath_rules([&self.config_dir], read_only)))
+            .and_then(|r| r.add_rules(path_beneath_rules(&self.peers_dir, read_only)))
+            .and_then(|r| r.add_rules(path_beneath_rules(&self.secret_paths, read_only)))
+            .and_then(|r| r.add_rules(path_beneath_rules(existing(DEVICE_PATHS), device)))
+            .and_then(|r| r.add_rules(path_beneath_rules(&self.state_dir, AccessFs::from_all(abi))))
+            // 内核支持时同时限制 tokio 工作线程，否则只作用于当前线程及其子进程
+            .and_then(|r| r.all_threads(all_threads))
+            .and_then(|r| r.restrict_self())
+            .map_err(|e| {
+                Error::SecurityError(format!("Failed to apply Landlock ruleset: {}", e))
+            })?;
+
+        match status.ruleset {
+            RulesetStatus::NotEnforced => {
+                return Err(Error::SecurityError(
+                    "Landlock is not supported by this kernel".to_string(),
+                ))
+            }
+            RulesetStatus::PartiallyEnforced => {
+                warn!("Landlock ruleset is only partially enforced by this kernel")
+            }
+            RulesetStatus::FullyEnforced => {}
+        }
+        if all_threads && !status.all_threads {
+            warn!("Kernel cannot apply Landlock to existing threads; only the control thread is confined");
+        }
+
+        Ok(())
+    }
+}
+
+/// 初始化之后是否还要运行 `ip`、`nft`：NAT 规则在重载和退出时更新，接口网段外的路由在重载时更新
+fn needs_commands(config: &ServerConfig) -> bool {
+    let subnet = config.interface.address.trunc();
+    config.nat.is_some()
+        || config
+            .peers
+            .iter()
+            .flat_map(|peer| &peer.allowed_ips)
+            .any(|route| !subnet.contains(route))
+}
+
+/// 编译系统调用过滤器，按顺序安装，不在白名单中的调用执行 `mismatch`
+///
+/// 不允许子命令时 `clone` 必须带 `CLONE_THREAD`；`clone3` 的参数在内存中无法检查，
+/// 由第二个过滤器返回 `ENOSYS`，使 glibc 回退到 `clone`。
+fn syscall_filters(commands: bool, mismatch: SeccompAction) -> Result<Vec<BpfProgram>> {
+    let condition = |op, value| {
+        SeccompCondition::new(0, SeccompCmpArgLen::Dword, op, value)
+            .and_then(|condition| SeccompRule::new(vec![condition]))
+            .map_err(|e| Error::SecurityError(format!("Failed to build seccomp rule: {}", e)))
+    };
+
+    let mut rules: BTreeMap<i64, Vec<SeccompRule>> =
+        ALLOWED_SYSCALLS.iter().map(|&nr| (nr, vec![])).collect();
+    let families = SOCKET_FAMILIES
+        .iter()
+        .map(|&family| condition(SeccompCmpOp::Eq, family as u64))
+        .collect::<Result<_>>()?;
+    rules.insert(libc::SYS_socket, families);
+    rules.insert(libc::SYS_clone3, vec![]);
+    if commands {
+        rules.insert(libc::SYS_clone, vec![]);
+        rules.extend(COMMAND_SYSCALLS.iter().map(|&nr| (nr, vec![])));
+    } else {
+        let thread = libc::CLONE_THREAD as u64;
+        rules.insert(
+            libc::SYS_clone,
+            vec![condition(SeccompCmpOp::MaskedEq(thread), thread)?],
+        );
+    }
+
+    // 安装白名单后不能再调用 seccomp，clone3 过滤器先安装
+    let mut filters = Vec::new();
+    if !commands {
+        let clone3 = BTreeMap::from([(libc::SYS_clone3, vec![])]);
+        filters.push(compile(
+            clone3,
+            SeccompAction::Allow,
+            SeccompAction::Errno(libc::ENOSYS as u32),
+        )?);
+    }
+    filters.push(compile(rules, mismatch, SeccompAction::Allow)?);
+    Ok(filters)
+}
+
+/// 为当前架构编译过滤器
+fn compile(
+    rules: BTreeMap<i64, Vec<SeccompRule>>,
+    mismatch: SeccompAction,
+    matched: SeccompAction,
+) -> Result<BpfProgram> {
+    let arch = std::env::consts::ARCH
+        .try_into()
+        .map_err(|e| Error::SecurityError(format!("Unsupported architecture: {}", e)))?;
+    SeccompFilter::new(rules, mismatch, matched, arch)
+        .and_then(|filter| filter.try_into())
+        .map_err(|e| Error::SecurityError(format!("Failed to build seccomp filter: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::write_atomic;
+    use std::fs::{self, File, OpenOptions};
+    use std::io;
+    use std::thread;
+
+    #[test]
+    fn test_forbidden_syscall_blocked() {
+        let filters = syscall_filters(false, SeccompAction::Errno(libc::EPERM as u32)).unwrap();
+
+        // 只在独立线程中安装，避免影响其他测试
+        let (allowed, blocked) = thread::spawn(move || {
+            for program in &filters {
+                seccompiler::apply_filter(program).unwrap();
+            }
+            let errno = || io::Error::last_os_error().raw_os_error();
+            // SAFETY: 调用不访问调用方的内存；personality(0xffffffff) 只查询当前值，
+            // 创建的套接字立即关闭，execve 的参数是以空字符结尾的常量
+            unsafe {
+                let udp = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0);
+                libc::close(udp);
+                let packet = libc::socket(libc::AF_PACKET, libc::SOCK_RAW, 0);
+                let packet = (packet, errno());
+                let personality = libc::personality(0xffff_ffff);
+                let personality = (personality, errno());
+                let argv = [c"/bin/true".as_ptr(), std::ptr::null()];
+                let execve = libc::execve(argv[0], argv.as_ptr(), std::ptr::null());
+                let execve = (execve, errno());
+                // 创建线程时 clone3 返回 ENOSYS，回退到带 CLONE_THREAD 的 clone
+                let spawned = thread::spawn(|| libc::getppid()).join().unwrap();
+                ((udp, spawned), [packet, personality, execve])
+            }
+        })
+        .join()
+        .unwrap();
+
+        assert!(allowed.0 >= 0 && allowed.1 > 0);
+        assert_eq!(blocked, [(-1, Some(libc::EPERM)); 3]);
+    }
+
+    #[test]
+    fn test_state_dir_writable_under_sandbox() {
+        let dir = std::env::temp_dir().join(format!("rusty-tunnel-sandbox-{}", std::process::id()));
+        let state_dir = dir.join("state");
+        fs::create_dir_all(&state_dir).unwrap();
+        let sandbox = Sandbox {
+            config_dir: dir.clone(),
+            peers_dir: None,
+            state_dir: Some(state_dir.clone()),
+            secret_paths: vec![],
+            commands: false,
+        };
+        let filters = syscall_filters(false, SeccompAction::Errno(libc::EPERM as u32)).unwrap();
+        let path = state_dir.join("ipam.json");
+        let outside = dir.join("outside");
+
+        let (content, allowed, denied) = thread::spawn(move || {
+            sandbox.restrict_filesystem(false).unwrap();
+            for program in &filters {
+                seccompiler::apply_filter(program).unwrap();
+            }
+            // 第二次写入替换已有文件
+            write_atomic(&path, "first").unwrap();
+            write_atomic(&path, "second").unwrap();
+            let content = fs::read_to_string(&path).unwrap();
+            let allowed = OpenOptions::new().write(true).open("/dev/null").is_ok();
+            let denied = [
+                File::open("/dev/zero").is_err(),
+                File::create("/dev/shm/rusty-tunnel-sandbox").is_err(),
+                File::create(&outside).is_err(),
+            ];
+            (content, allowed, denied)
+        })
+        .join()
+        .unwrap();
+
+        let leftovers = fs::read_dir(&state_dir).unwrap().count();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(content, "second");
+        assert_eq!(leftovers, 1);
+        assert!(allowed);
+        assert_eq!(denied, [true; 3]);
+    }
+}
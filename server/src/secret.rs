@@ -0,0 +1,163 @@
+use crate::crypto;
+use crate::types::ParseError;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use zeroize::{Zeroize, Zeroizing};
+
+/// 密钥在 `Debug`、`Display` 和 `effective-config` 输出中的替代文本
+pub const REDACTED: &str = "[redacted]";
+
+/// 密钥的 Base64 文本，用于输出生成的密钥
+///
+/// 释放时清零，`Debug` 和 `Display` 只输出 `[redacted]`，需要明文时调用 [`SecretString::expose`]。
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SecretString(String);
+
+impl SecretString {
+    /// 返回密钥明文
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// 是否为空
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// 清零并清空
+    pub fn clear(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(value: String) -> Self {
+        SecretString(value)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(value: &str) -> Self {
+        SecretString(value.to_string())
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl fmt::Display for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+/// 32 字节密钥（私钥、预共享密钥、混淆密钥），配置中为 Base64
+///
+/// 释放时清零，`Debug` 和 `Display` 只输出 `[redacted]`，需要明文时调用 [`SecretKey::to_base64`]。
+#[derive(Clone, PartialEq, Eq)]
+pub struct SecretKey([u8; 32]);
+
+impl SecretKey {
+    /// 返回密钥字节
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// 编码为 Base64
+    pub fn to_base64(&self) -> SecretString {
+        STANDARD.encode(self.0).into()
+    }
+}
+
+impl FromStr for SecretKey {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        crypto::decode_secret(s).map_err(ParseError::from)
+    }
+}
+
+impl Serialize for SecretKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.to_base64().expose())
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let encoded = Zeroizing::new(String::deserialize(deserializer)?);
+        encoded.parse().map_err(de::Error::custom)
+    }
+}
+
+impl From<[u8; 32]> for SecretKey {
+    fn from(bytes: [u8; 32]) -> Self {
+        SecretKey(bytes)
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl fmt::Display for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secrets_are_redacted() {
+        let secret = SecretString::from("c2VjcmV0");
+        assert_eq!(format!("{:?}", secret), "[redacted]");
+        assert_eq!(secret.to_string(), "[redacted]");
+        assert_eq!(secret.expose(), "c2VjcmV0");
+
+        let key = SecretKey::from([7u8; 32]);
+        assert_eq!(format!("{:?} {}", key, key), "[redacted] [redacted]");
+        assert_eq!(key.as_bytes(), &[7u8; 32]);
+    }
+
+    #[test]
+    fn test_secret_serialization() {
+        #[derive(Debug, Serialize, Deserialize)]
+        struct Wrapper {
+            secret: SecretString,
+            key: SecretKey,
+        }
+
+        let encoded = STANDARD.encode([7u8; 32]);
+        let content = format!("secret = \"c2VjcmV0\"\nkey = \"{}\"", encoded);
+        let wrapper: Wrapper = toml::from_str(&content).unwrap();
+        assert_eq!(wrapper.secret.expose(), "c2VjcmV0");
+        assert_eq!(wrapper.key.as_bytes(), &[7u8; 32]);
+        assert_eq!(toml::to_string(&wrapper).unwrap().trim(), content);
+
+        let err = toml::from_str::<Wrapper>("secret = \"\"\nkey = \"c2VjcmV0\"").unwrap_err();
+        assert!(err.to_string().contains("Key must be 32 bytes, got 6"));
+    }
+}
@@ -1,12 +1,22 @@
-use crate::config::ServerConfig;
-use crate::crypto;
-use crate::device::TunDevice;
-use crate::error::Result;
+use crate::config::{Backend, PeerChanges, ServerConfig, StreamConfig};
+use crate::device::{Device, TunDevice};
+use crate::error::{Error, Result};
+use crate::ipam::AddressPool;
+use crate::kernel::KernelDevice;
+use crate::nat::NatTable;
 use crate::peer::{Peer, PeerStatus};
+#[cfg(feature = "pq")]
+use crate::pq::PskExchange;
+use crate::stream::{self, StreamLimits, StreamTransport};
+use crate::transport::{Transport, UdpTransport};
+use crate::tunnel::Tunnel;
+use crate::types::PublicKey;
+use ipnet::IpNet;
 use log::{info, warn};
-use std::net::UdpSocket;
+use std::path::Path;
 use std::sync::Arc;
 use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
 
 /// VPN 服务器
 pub struct VpnServer {
@@ -14,67 +24,508 @@ pub struct VpnServer {
     config: ServerConfig,
     /// 对等体列表
     peers: Arc<RwLock<Vec<Peer>>>,
-    /// TUN 设备
-    device: TunDevice,
-    /// UDP 套接字
-    socket: Option<UdpSocket>,
+    /// 数据包设备
+    device: Arc<dyn Device>,
+    /// 数据报传输，未指定时启动时绑定各监听地址
+    transports: Vec<Arc<dyn Transport>>,
+    /// 用户空间数据面
+    tunnel: Option<Arc<Tunnel>>,
+    /// 数据面任务
+    tasks: Vec<JoinHandle<()>>,
+    /// NAT 规则
+    nat: Option<NatTable>,
+    /// 隧道地址池，启动和重新加载时写入状态目录
+    pool: AddressPool,
+    /// 内核 WireGuard 设备（仅内核后端）
+    kernel: Option<KernelDevice>,
+    /// 后量子密钥交换的传输，未指定时启动时绑定 `pq.listen`
+    #[cfg(feature = "pq")]
+    pq_transport: Option<Arc<dyn Transport>>,
+    /// 后量子预共享密钥交换
+    #[cfg(feature = "pq")]
+    pq: Option<Arc<PskExchange>>,
 }
 
 impl VpnServer {
     /// 创建新的 VPN 服务器
     pub fn new(config: ServerConfig) -> Result<Self> {
-        let device = TunDevice::new(&config.interface.name, &config.interface.address);
+        let device = TunDevice::with_netns(
+            &config.interface.name,
+            config.interface.address,
+            config.interface.netns.clone(),
+        )
+        .with_mtu(config.interface.mtu);
+        Self::build(config, Arc::new(device), Vec::new())
+    }
+
+    /// 使用指定的设备和传输创建 VPN 服务器
+    pub fn with_io(
+        config: ServerConfig,
+        device: Arc<dyn Device>,
+        transports: Vec<Arc<dyn Transport>>,
+    ) -> Result<Self> {
+        Self::build(config, device, transports)
+    }
 
+    fn build(
+        config: ServerConfig,
+        device: Arc<dyn Device>,
+        transports: Vec<Arc<dyn Transport>>,
+    ) -> Result<Self> {
         // 从配置创建对等体
         let mut peers = Vec::new();
         for peer_config in &config.peers {
-            let peer = Peer::from_config(peer_config.clone())?;
+            let peer = Peer::from_config(peer_config.clone());
             info!("Loaded peer: {}", peer.summary());
             peers.push(peer);
         }
 
+        let nat = Self::nat_table(&config)?;
+        // 尽早发现重叠的对等体地址
+        let pool = AddressPool::from_config(&config)?;
+
+        let kernel = match config.interface.backend {
+            Backend::Kernel => Some(KernelDevice::new(
+                &config.interface.name,
+                config.interface.netns.clone(),
+            )),
+            Backend::Userspace => None,
+        };
+        if kernel.is_some() && !config.interface.listen.is_empty() {
+            return Err(Error::ConfigError(
+                "The kernel backend listens on listen_port only; remove interface.listen"
+                    .to_string(),
+            ));
+        }
+        if kernel.is_some() && config.stream.is_some() {
+            return Err(Error::ConfigError(
+                "The stream transport requires the userspace backend".to_string(),
+            ));
+        }
+        let obfuscated = config.interface.obfuscation.is_some()
+            || config.peers.iter().any(|p| p.obfuscation.is_some());
+        if kernel.is_some() && obfuscated {
+            return Err(Error::ConfigError(
+                "Obfuscation requires the userspace backend".to_string(),
+            ));
+        }
+        let rotating = config.interface.next_private_key.is_some()
+            || config.interface.previous_private_key.is_some();
+        if kernel.is_some() && rotating {
+            return Err(Error::ConfigError(
+                "Accepting multiple private keys requires the userspace backend".to_string(),
+            ));
+        }
+        if config.pq.is_some() {
+            if !cfg!(feature = "pq") {
+                return Err(Error::ConfigError(
+                    "Post-quantum key exchange requires building with the pq feature".to_string(),
+                ));
+            }
+            if kernel.is_some() {
+                return Err(Error::ConfigError(
+                    "Post-quantum key exchange requires the userspace backend".to_string(),
+                ));
+            }
+        }
+
         Ok(VpnServer {
             config,
             peers: Arc::new(RwLock::new(peers)),
             device,
-            socket: None,
+            transports,
+            tunnel: None,
+            tasks: Vec::new(),
+            nat,
+            pool,
+            kernel,
+            #[cfg(feature = "pq")]
+            pq_transport: None,
+            #[cfg(feature = "pq")]
+            pq: None,
         })
     }
 
+    /// 使用指定的传输进行后量子密钥交换
+    #[cfg(feature = "pq")]
+    pub fn with_pq_transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.pq_transport = Some(transport);
+        self
+    }
+
     /// 启动服务器
     pub async fn start(&mut self) -> Result<()> {
-        info!("Starting VPN server on port {}", self.config.interface.listen_port);
+        info!(
+            "Starting VPN server ({:?} backend)",
+            self.config.interface.backend
+        );
+        Self::save_address_pool(&self.pool);
+
+        // 内核后端：在宿主命名空间创建 wireguard 链路
+        if let Some(kernel) = &self.kernel {
+            if !KernelDevice::is_available() {
+                return Err(Error::DeviceError(
+                    "wireguard kernel module is not loaded".to_string(),
+                ));
+            }
+            kernel.create_link()?;
+        }
 
         // 配置 TUN 设备
         self.setup_device().await?;
 
-        // 绑定 UDP 套接字
-        let addr = format!("0.0.0.0:{}", self.config.interface.listen_port);
-        let socket = UdpSocket::bind(&addr)
-            .map_err(|e| crate::error::Error::NetworkError(format!("Failed to bind socket: {}", e)))?;
-        socket.set_nonblocking(true)
-            .map_err(|e| crate::error::Error::NetworkError(format!("Failed to set nonblocking: {}", e)))?;
+        // 内核后端：下发密钥和对等体
+        if let Some(kernel) = &self.kernel {
+            kernel.configure(&self.config)?;
+        }
+
+        // 安装 NAT 规则
+        if let Some(nat) = &self.nat {
+            nat.install()?;
+        }
+
+        if !self.config.interface.post_up.is_empty() || !self.config.interface.pre_down.is_empty() {
+            warn!("post_up/pre_down are kept for wg-quick export only and are not executed");
+        }
+
+        // 内核后端由内核负责监听 UDP 端口
+        if self.kernel.is_some() {
+            info!("VPN server started successfully");
+            return Ok(());
+        }
+
+        // 在每个监听地址上绑定 UDP 套接字
+        if self.transports.is_empty() {
+            for addr in self.config.interface.listen_addrs() {
+                self.transports
+                    .push(Arc::new(UdpTransport::bind(addr).await?));
+            }
+            if let Some(stream) = &self.config.stream {
+                self.transports
+                    .push(Arc::new(Self::bind_stream(stream).await?));
+            }
+        }
 
-        self.socket = Some(socket);
+        // 启动数据面
+        let tunnel = Arc::new(Tunnel::new(
+            &self.config,
+            self.device.clone(),
+            self.transports.clone(),
+        )?);
+        self.tasks = tunnel.spawn();
+        #[cfg(feature = "pq")]
+        self.start_psk_exchange(&tunnel).await?;
+        self.tunnel = Some(tunnel);
         info!("VPN server started successfully");
 
         Ok(())
     }
 
+    /// 启动后量子预共享密钥交换
+    #[cfg(feature = "pq")]
+    async fn start_psk_exchange(&mut self, tunnel: &Arc<Tunnel>) -> Result<()> {
+        let Some(pq) = &self.config.pq else {
+            return Ok(());
+        };
+        let transport = match self.pq_transport.take() {
+            Some(transport) => transport,
+            None => Arc::new(UdpTransport::bind(pq.listen).await?),
+        };
+
+        let exchange = Arc::new(PskExchange::new(
+            &self.config,
+            transport,
+            tunnel.clone(),
+            self.peers.clone(),
+        )?);
+        self.tasks.extend(exchange.spawn());
+        self.pq = Some(exchange);
+        Ok(())
+    }
+
     /// 停止服务器
     pub async fn stop(&mut self) -> Result<()> {
         info!("Stopping VPN server");
 
+        // 停止数据面
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+        self.tunnel = None;
+        #[cfg(feature = "pq")]
+        {
+            self.pq = None;
+        }
+
+        // 删除 NAT 规则
+        if let Some(nat) = &self.nat {
+            if let Err(e) = nat.remove() {
+                warn!("Failed to remove NAT rules: {}", e);
+            }
+        }
+
         // 清理设备
         self.cleanup_device().await?;
 
+        if let Some(kernel) = &self.kernel {
+            if let Err(e) = kernel.delete() {
+                warn!("Failed to delete kernel device: {}", e);
+            }
+        }
+
+        // 删除命名空间中残留的设备
+        if let Err(e) = self.device.destroy() {
+            warn!("Failed to destroy device: {}", e);
+        }
+
         info!("VPN server stopped");
         Ok(())
     }
 
+    /// 重新加载配置（SIGHUP）
+    ///
+    /// 接口本身的变更需要重启才能生效；NAT 规则和对等体路由会按新配置更新。
+    /// 下发失败时恢复旧配置，`self.config` 始终与运行状态一致。
+    pub async fn reload(&mut self, config: ServerConfig) -> Result<()> {
+        if config == self.config {
+            info!("Configuration unchanged");
+            return Ok(());
+        }
+        info!("Reloading configuration");
+
+        // 先校验并解析新配置，此前不改动任何运行状态
+        config.interface.private_key()?;
+        let nat = Self::nat_table(&config)?;
+        let pool = AddressPool::from_config(&config)?;
+        let mut peers = Vec::new();
+        for peer_config in &config.peers {
+            peers.push(Peer::from_config(peer_config.clone()));
+        }
+
+        if config.interface.name != self.config.interface.name
+            || config.interface.address != self.config.interface.address
+            || config.interface.listen_port != self.config.interface.listen_port
+            || config.interface.listen != self.config.interface.listen
+            || config.interface.backend != self.config.interface.backend
+            || config.interface.netns != self.config.interface.netns
+            || config.interface.mtu != self.config.interface.mtu
+            || config.stream != self.config.stream
+            || config.pq != self.config.pq
+        {
+            warn!("Interface or transport settings changed; restart the server to apply them");
+        }
+        if Self::forwards_ipv6(&config) && !Self::forwards_ipv6(&self.config) {
+            warn!("IPv6 networks added; restart the server to enable IPv6 forwarding");
+        }
+
+        let changes = PeerChanges::between(&self.config.peers, &config.peers);
+        for peer in &changes.added {
+            info!("Adding peer {}", peer.public_key.short());
+        }
+        for peer in &changes.changed {
+            info!("Updating peer {}", peer.public_key.short());
+        }
+        for peer in &changes.removed {
+            info!("Removing peer {}", peer.public_key.short());
+        }
+
+        let subnet = self.config.interface.address.trunc();
+        let old_routes = Self::routes(&subnet, &self.peers.read().await);
+        let new_routes = Self::routes(&subnet, &peers);
+        let routes: Vec<IpNet> = new_routes
+            .iter()
+            .filter(|r| !old_routes.contains(r))
+            .copied()
+            .collect();
+        let mut added = Vec::new();
+        if let Err(e) = self.apply(&config, &changes, nat.as_ref(), &routes, &mut added) {
+            self.restore(nat.as_ref(), &added);
+            return Err(e);
+        }
+
+        // 新配置已全部生效，再删除过期的路由和 NAT 规则
+        for route in old_routes.iter().filter(|r| !new_routes.contains(r)) {
+            if let Err(e) = self.device.remove_route(route) {
+                warn!("Failed to remove route: {}", e);
+            }
+        }
+        if let (None, Some(old)) = (&nat, &self.nat) {
+            if let Err(e) = old.remove() {
+                warn!("Failed to remove NAT rules: {}", e);
+            }
+        }
+        self.nat = nat;
+        Self::save_address_pool(&pool);
+        self.pool = pool;
+
+        // 保留已有对等体的运行状态
+        let mut current = self.peers.write().await;
+        for peer in peers.iter_mut() {
+            if let Some(old) = current.iter().find(|p| p.public_key == peer.public_key) {
+                peer.status = old.status;
+                peer.last_handshake = old.last_handshake;
+                peer.bytes_received = old.bytes_received;
+                peer.bytes_sent = old.bytes_sent;
+                // 配置的预共享密钥未变时保留运行时轮换的密钥
+                let configured = self
+                    .config
+                    .peers
+                    .iter()
+                    .find(|p| p.public_key == peer.public_key)
+                    .and_then(|p| p.psk.as_ref());
+                if configured == peer.psk.as_ref() {
+                    peer.psk = old.psk.clone();
+                }
+            }
+        }
+        *current = peers;
+        drop(current);
+
+        self.config = config;
+        info!("Configuration reloaded");
+        Ok(())
+    }
+
+    /// 下发新配置：先添加新路由（成功的记入 `added`），再更新密钥、对等体和 NAT 规则
+    fn apply(
+        &self,
+        config: &ServerConfig,
+        changes: &PeerChanges,
+        nat: Option<&NatTable>,
+        routes: &[IpNet],
+        added: &mut Vec<IpNet>,
+    ) -> Result<()> {
+        for route in routes {
+            self.device.add_route(route)?;
+            added.push(*route);
+        }
+
+        // 接口设置未变时只下发变化的对等体
+        if let Some(kernel) = &self.kernel {
+            if config.interface != self.config.interface {
+                kernel.configure(config)?;
+            } else if !changes.is_empty() {
+                kernel.apply_changes(changes)?;
+            }
+        }
+        if let Some(tunnel) = &self.tunnel {
+            tunnel.set_keys(&config.interface)?;
+            tunnel.set_peers(&config.peers)?;
+        }
+        #[cfg(feature = "pq")]
+        if let Some(exchange) = &self.pq {
+            exchange.reload(config)?;
+        }
+        if let Some(table) = nat {
+            table.install()?;
+        }
+        Ok(())
+    }
+
+    /// 下发失败后按当前配置恢复运行状态，并删除已添加的新路由
+    fn restore(&self, nat: Option<&NatTable>, added: &[IpNet]) {
+        warn!("Failed to apply the new configuration; restoring the previous one");
+        for route in added {
+            if let Err(e) = self.device.remove_route(route) {
+                warn!("Failed to remove route: {}", e);
+            }
+        }
+        if let Some(kernel) = &self.kernel {
+            if let Err(e) = kernel.configure(&self.config) {
+                warn!("Failed to restore the kernel device: {}", e);
+            }
+        }
+        if let Some(tunnel) = &self.tunnel {
+            let restored = tunnel
+                .set_keys(&self.config.interface)
+                .and_then(|_| tunnel.set_peers(&self.config.peers));
+            if let Err(e) = restored {
+                warn!("Failed to restore the tunnel peers: {}", e);
+            }
+        }
+        #[cfg(feature = "pq")]
+        if let Some(exchange) = &self.pq {
+            if let Err(e) = exchange.reload(&self.config) {
+                warn!("Failed to restore the key exchange: {}", e);
+            }
+        }
+        let restored = match (&self.nat, nat) {
+            (Some(old), _) => old.install(),
+            (None, Some(new)) => new.remove(),
+            (None, None) => Ok(()),
+        };
+        if let Err(e) = restored {
+            warn!("Failed to restore NAT rules: {}", e);
+        }
+    }
+
+    /// 接口、地址池或任一对等体有 IPv6 网段时需要 IPv6 转发
+    fn forwards_ipv6(config: &ServerConfig) -> bool {
+        let pools = config.ipam.iter().flat_map(|ipam| &ipam.pools);
+        let peers = config.peers.iter().flat_map(|peer| &peer.allowed_ips);
+        std::iter::once(&config.interface.address)
+            .chain(pools)
+            .chain(peers)
+            .any(|network| matches!(network, IpNet::V6(_)))
+    }
+
+    /// 需要单独添加路由的对等体地址范围，去掉重复项
+    ///
+    /// 接口网段 `subnet` 内的地址已由接口地址的直连路由覆盖，不再添加。
+    fn routes(subnet: &IpNet, peers: &[Peer]) -> Vec<IpNet> {
+        let mut routes = Vec::new();
+        for route in peers.iter().flat_map(|p| &p.allowed_ips) {
+            if !subnet.contains(route) && !routes.contains(route) {
+                routes.push(*route);
+            }
+        }
+        routes
+    }
+
+    /// 将地址池写入状态目录，失败时只记录警告
+    fn save_address_pool(pool: &AddressPool) {
+        if let Err(e) = pool.save() {
+            warn!("Failed to save the address pool: {}", e);
+        }
+    }
+
+    /// 根据配置创建 NAT 规则，规则与接口位于同一命名空间
+    fn nat_table(config: &ServerConfig) -> Result<Option<NatTable>> {
+        config
+            .nat
+            .as_ref()
+            .map(|nat| {
+                NatTable::from_config(nat)
+                    .map(|table| table.in_namespace(config.interface.netns.clone()))
+            })
+            .transpose()
+    }
+
+    /// 绑定 TCP/TLS 流式传输
+    async fn bind_stream(config: &StreamConfig) -> Result<StreamTransport> {
+        let tls = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => Some(stream::tls_acceptor(Path::new(cert), Path::new(key))?),
+            (None, None) => None,
+            _ => {
+                return Err(Error::ConfigError(
+                    "stream.tls_cert and stream.tls_key must be set together".to_string(),
+                ))
+            }
+        };
+        StreamTransport::bind(config.listen, tls, StreamLimits::default()).await
+    }
+
     /// 设置 TUN 设备
     async fn setup_device(&self) -> Result<()> {
-        info!("Setting up TUN device: {}", self.device.name);
+        info!("Setting up TUN device: {}", self.device.name());
+
+        // 用户空间后端需要自行创建 TUN 接口
+        if self.kernel.is_none() {
+            self.device.create()?;
+        }
+
+        // 设备在宿主命名空间创建后再移入目标命名空间
+        self.device.move_to_namespace()?;
 
         // 配置 IP 地址
         self.device.set_address()?;
@@ -83,11 +534,13 @@ impl VpnServer {
         self.device.up()?;
 
         // 启用 IP 转发
-        TunDevice::enable_forwarding()?;
+        self.device
+            .enable_forwarding(Self::forwards_ipv6(&self.config))?;
 
         // 添加路由
-        for peer in self.peers.read().await.iter() {
-            self.device.add_route(&peer.allowed_ips)?;
+        let subnet = self.config.interface.address.trunc();
+        for route in Self::routes(&subnet, &self.peers.read().await) {
+            self.device.add_route(&route)?;
         }
 
         info!("TUN device configured successfully");
@@ -99,8 +552,9 @@ impl VpnServer {
         info!("Cleaning up TUN device");
 
         // 删除路由
-        for peer in self.peers.read().await.iter() {
-            if let Err(e) = self.device.remove_route(&peer.allowed_ips) {
+        let subnet = self.config.interface.address.trunc();
+        for route in Self::routes(&subnet, &self.peers.read().await) {
+            if let Err(e) = self.device.remove_route(&route) {
                 warn!("Failed to remove route: {}", e);
             }
         }
@@ -121,31 +575,53 @@ impl VpnServer {
 
     /// 获取对等体列表
     pub async fn get_peers(&self) -> Vec<Peer> {
+        self.refresh_stats().await;
         self.peers.read().await.clone()
     }
 
+    /// 从内核计数器或用户空间会话刷新对等体状态
+    async fn refresh_stats(&self) {
+        if let Some(kernel) = &self.kernel {
+            let mut peers = self.peers.write().await;
+            if let Err(e) = kernel.update_peers(&mut peers) {
+                warn!("Failed to read kernel peer stats: {}", e);
+            }
+        } else if let Some(tunnel) = &self.tunnel {
+            tunnel.update_peers(&mut self.peers.write().await);
+        }
+    }
+
     /// 更新对等体状态
-    pub async fn update_peer_status(&self, public_key: &str, status: PeerStatus) -> Result<()> {
+    pub async fn update_peer_status(
+        &self,
+        public_key: &PublicKey,
+        status: PeerStatus,
+    ) -> Result<()> {
         let mut peers = self.peers.write().await;
         for peer in peers.iter_mut() {
-            if peer.public_key == public_key {
+            if peer.public_key == *public_key {
                 peer.set_status(status);
-                info!("Updated peer status: {} -> {:?}", &public_key[..8], status);
+                info!(
+                    "Updated peer status: {} -> {:?}",
+                    public_key.short(),
+                    status
+                );
                 return Ok(());
             }
         }
-        Err(crate::error::Error::Other(format!(
-            "Peer not found: {}",
-            public_key
-        )))
+        Err(Error::Other(format!("Peer not found: {}", public_key)))
     }
 
     /// 获取服务器统计信息
     pub async fn get_stats(&self) -> ServerStats {
+        self.refresh_stats().await;
         let peers = self.peers.read().await;
         let total_bytes_received: u64 = peers.iter().map(|p| p.bytes_received).sum();
         let total_bytes_sent: u64 = peers.iter().map(|p| p.bytes_sent).sum();
-        let connected_peers = peers.iter().filter(|p| p.status == PeerStatus::Connected).count();
+        let connected_peers = peers
+            .iter()
+            .filter(|p| p.status == PeerStatus::Connected)
+            .count();
 
         ServerStats {
             total_peers: peers.len(),
@@ -168,21 +644,696 @@ pub struct ServerStats {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::{InterfaceConfig, PeerConfig};
+    use crate::config::{InterfaceConfig, IpamConfig, ObfuscationConfig, PeerConfig};
+    use crate::crypto;
+    use crate::device::{MemoryDevice, MemoryDeviceHandle};
+    use crate::migrate::SERVER_VERSION;
+    use crate::secret::SecretKey;
+    use crate::transport::MemoryNetwork;
+    use crate::types::{Endpoint, ListenAddr};
+    use std::net::SocketAddr;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    fn test_config(private_key: SecretKey, address: &str, peers: Vec<PeerConfig>) -> ServerConfig {
+        ServerConfig {
+            version: SERVER_VERSION,
+            interface: InterfaceConfig {
+                name: "wg0".to_string(),
+                private_key: Some(private_key),
+                address: address.parse().unwrap(),
+                listen_port: 51820,
+                ..Default::default()
+            },
+            nat: None,
+            stream: None,
+            security: None,
+            pq: None,
+            ipam: None,
+            peers_dir: None,
+            peers,
+        }
+    }
+
+    /// 构造最小的 IPv4 数据包
+    fn ipv4_packet(src: [u8; 4], dst: [u8; 4], payload: &[u8]) -> Vec<u8> {
+        let total_len = (20 + payload.len()) as u16;
+        let mut packet = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, 17, 0, 0];
+        packet[2..4].copy_from_slice(&total_len.to_be_bytes());
+        packet.extend_from_slice(&src);
+        packet.extend_from_slice(&dst);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    /// 客户端（隧道地址 `client`）发出 ping，服务器回复 pong，两端都应原样收到
+    async fn ping_pong(
+        client: [u8; 4],
+        client_io: &mut MemoryDeviceHandle,
+        server_io: &mut MemoryDeviceHandle,
+    ) {
+        let ping = ipv4_packet(client, [10, 8, 0, 1], b"ping");
+        client_io.inject.send(ping.clone()).unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(5), server_io.received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, ping);
+
+        let pong = ipv4_packet([10, 8, 0, 1], client, b"pong");
+        server_io.inject.send(pong.clone()).unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(5), client_io.received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, pong);
+    }
 
     #[tokio::test]
     async fn test_server_creation() {
         let config = ServerConfig {
+            version: SERVER_VERSION,
             interface: InterfaceConfig {
                 name: "wg0".to_string(),
-                private_key: "test_key".to_string(),
-                address: "10.8.0.1/24".to_string(),
+                private_key: Some(SecretKey::from([1u8; 32])),
+                address: "10.8.0.1/24".parse().unwrap(),
                 listen_port: 51820,
+                ..Default::default()
             },
+            nat: None,
+            stream: None,
+            security: None,
+            pq: None,
+            ipam: None,
+            peers_dir: None,
             peers: vec![],
         };
 
         let server = VpnServer::new(config).unwrap();
-        assert_eq!(server.device.name, "wg0");
+        assert_eq!(server.device.name(), "wg0");
+    }
+
+    #[tokio::test]
+    async fn test_reload_routes() {
+        let peer = |key: u8, ip: &str| PeerConfig {
+            public_key: PublicKey::from([key; 32]),
+            allowed_ips: vec![ip.parse().unwrap()],
+            ..Default::default()
+        };
+        let old: IpNet = "10.9.0.2/32".parse().unwrap();
+        let new: IpNet = "10.9.0.3/32".parse().unwrap();
+        let (key, _) = crypto::generate_keypair().unwrap();
+        let peers = vec![peer(2, "10.9.0.2/32"), peer(5, "10.8.0.5/32")];
+        let config = test_config(key.clone(), "10.8.0.1/24", peers);
+
+        let network = MemoryNetwork::new();
+        let (device, io) = MemoryDevice::new("wg0");
+        let mut server = VpnServer::with_io(
+            config,
+            Arc::new(device),
+            vec![Arc::new(
+                network.bind("192.0.2.1:51820".parse().unwrap()).unwrap(),
+            )],
+        )
+        .unwrap();
+        server.start().await.unwrap();
+        // 接口网段内的对等体由直连路由覆盖
+        assert_eq!(io.routes.lock().unwrap().installed, vec![old]);
+
+        // 新路由添加失败时旧路由保留，已添加的新路由和配置回滚
+        io.routes.lock().unwrap().rejected.push(new);
+        let previous = server.config.clone();
+        let config = test_config(
+            key.clone(),
+            "10.8.0.1/24",
+            vec![peer(4, "10.9.0.4/32"), peer(3, "10.9.0.3/32")],
+        );
+        assert!(server.reload(config.clone()).await.is_err());
+        assert_eq!(io.routes.lock().unwrap().installed, vec![old]);
+        assert_eq!(server.config, previous);
+        assert_eq!(
+            server.peers.read().await[0].public_key,
+            PublicKey::from([2; 32])
+        );
+
+        io.routes.lock().unwrap().rejected.clear();
+        server.reload(config).await.unwrap();
+        let extra: IpNet = "10.9.0.4/32".parse().unwrap();
+        assert_eq!(io.routes.lock().unwrap().installed, vec![extra, new]);
+
+        server.stop().await.unwrap();
+        assert!(io.routes.lock().unwrap().installed.is_empty());
+    }
+
+    #[test]
+    fn test_ipv6_forwarding() {
+        let peer = PeerConfig {
+            public_key: PublicKey::from([2; 32]),
+            allowed_ips: vec!["10.8.0.2/32".parse().unwrap()],
+            ..Default::default()
+        };
+        let mut config = test_config(SecretKey::from([1; 32]), "10.8.0.1/24", vec![peer]);
+        assert!(!VpnServer::forwards_ipv6(&config));
+
+        config.peers[0]
+            .allowed_ips
+            .push("fd00::2/128".parse().unwrap());
+        assert!(VpnServer::forwards_ipv6(&config));
+
+        config.peers.clear();
+        config.ipam = Some(IpamConfig {
+            pools: vec!["fd00::1/64".parse().unwrap()],
+            reserved: vec![],
+        });
+        assert!(VpnServer::forwards_ipv6(&config));
+        let config = test_config(SecretKey::from([1; 32]), "fd00::1/64", vec![]);
+        assert!(VpnServer::forwards_ipv6(&config));
+    }
+
+    #[tokio::test]
+    async fn test_back_to_back_servers() {
+        let (server_key, server_pub) = crypto::generate_keypair().unwrap();
+        let (client_key, client_pub) = crypto::generate_keypair().unwrap();
+        let server_addr: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let client_addr: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+
+        let mut server_config = test_config(
+            server_key,
+            "10.8.0.1/24",
+            vec![PeerConfig {
+                public_key: client_pub,
+                allowed_ips: vec!["10.8.0.2/32".parse().unwrap()],
+                ..Default::default()
+            }],
+        );
+        let hook_log =
+            std::env::temp_dir().join(format!("rusty-tunnel-hooks-{}", std::process::id()));
+        server_config.interface.post_up = vec![format!("touch {}", hook_log.display())];
+        server_config.interface.pre_down = vec![format!("touch {}", hook_log.display())];
+        let client_config = test_config(
+            client_key,
+            "10.8.0.2/24",
+            vec![PeerConfig {
+                public_key: server_pub,
+                allowed_ips: vec!["10.8.0.0/24".parse().unwrap()],
+                endpoint: Some(Endpoint::Addr(server_addr)),
+                persistent_keepalive: Some(25),
+                ..Default::default()
+            }],
+        );
+
+        let network = MemoryNetwork::new();
+        let (server_device, mut server_io) = MemoryDevice::new("wg0");
+        let (client_device, mut client_io) = MemoryDevice::new("wg1");
+        let mut server = VpnServer::with_io(
+            server_config,
+            Arc::new(server_device),
+            vec![Arc::new(network.bind(server_addr).unwrap())],
+        )
+        .unwrap();
+        let mut client = VpnServer::with_io(
+            client_config,
+            Arc::new(client_device),
+            vec![Arc::new(network.bind(client_addr).unwrap())],
+        )
+        .unwrap();
+        server.start().await.unwrap();
+        client.start().await.unwrap();
+
+        // 客户端发出的第一个数据包触发握手，握手完成后送达服务器
+        let ping = ipv4_packet([10, 8, 0, 2], [10, 8, 0, 1], b"ping");
+        client_io.inject.send(ping.clone()).unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(5), server_io.received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, ping);
+
+        // 服务器从握手中学到客户端端点并回复
+        let pong = ipv4_packet([10, 8, 0, 1], [10, 8, 0, 2], b"pong");
+        server_io.inject.send(pong.clone()).unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(5), client_io.received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, pong);
+
+        let peers = server.get_peers().await;
+        assert_eq!(peers[0].status, PeerStatus::Connected);
+        assert_eq!(peers[0].endpoint, Some(client_addr));
+        assert_eq!(peers[0].bytes_received, ping.len() as u64);
+
+        let stats = server.get_stats().await;
+        assert_eq!(stats.connected_peers, 1);
+
+        client.stop().await.unwrap();
+        server.stop().await.unwrap();
+
+        // PostUp/PreDown 只用于导出，不会执行
+        assert!(!hook_log.exists());
+    }
+
+    #[tokio::test]
+    async fn test_reply_from_listen_address_reached() {
+        let (server_key, server_pub) = crypto::generate_keypair().unwrap();
+        let (client_key, client_pub) = crypto::generate_keypair().unwrap();
+        let primary: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let fallback: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        let client_addr: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+
+        let mut server_config = test_config(
+            server_key,
+            "10.8.0.1/24",
+            vec![PeerConfig {
+                public_key: client_pub,
+                allowed_ips: vec!["10.8.0.2/32".parse().unwrap()],
+                ..Default::default()
+            }],
+        );
+        server_config.interface.listen =
+            vec![ListenAddr::Socket(primary), ListenAddr::Socket(fallback)];
+        let client_config = test_config(
+            client_key,
+            "10.8.0.2/24",
+            vec![PeerConfig {
+                public_key: server_pub,
+                allowed_ips: vec!["10.8.0.0/24".parse().unwrap()],
+                endpoint: Some(Endpoint::Addr(fallback)),
+                ..Default::default()
+            }],
+        );
+
+        let network = MemoryNetwork::new();
+        let (server_device, mut server_io) = MemoryDevice::new("wg0");
+        let (client_device, mut client_io) = MemoryDevice::new("wg1");
+        let mut server = VpnServer::with_io(
+            server_config,
+            Arc::new(server_device),
+            vec![
+                Arc::new(network.bind(primary).unwrap()),
+                Arc::new(network.bind(fallback).unwrap()),
+            ],
+        )
+        .unwrap();
+        let mut client = VpnServer::with_io(
+            client_config,
+            Arc::new(client_device),
+            vec![Arc::new(network.bind(client_addr).unwrap())],
+        )
+        .unwrap();
+        server.start().await.unwrap();
+        client.start().await.unwrap();
+        ping_pong([10, 8, 0, 2], &mut client_io, &mut server_io).await;
+
+        // 客户端从回复的来源地址学习端点，应始终是它连接的 443 端口
+        let peers = client.get_peers().await;
+        assert_eq!(peers[0].endpoint, Some(fallback));
+
+        client.stop().await.unwrap();
+        server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handshake_over_stream_transport() {
+        let (server_key, server_pub) = crypto::generate_keypair().unwrap();
+        let (client_key, client_pub) = crypto::generate_keypair().unwrap();
+
+        let listener = StreamTransport::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            None,
+            StreamLimits::default(),
+        )
+        .await
+        .unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let connection = StreamTransport::connect(server_addr, None).await.unwrap();
+
+        let server_config = test_config(
+            server_key,
+            "10.8.0.1/24",
+            vec![PeerConfig {
+                public_key: client_pub,
+                allowed_ips: vec!["10.8.0.2/32".parse().unwrap()],
+                ..Default::default()
+            }],
+        );
+        let client_config = test_config(
+            client_key,
+            "10.8.0.2/24",
+            vec![PeerConfig {
+                public_key: server_pub,
+                allowed_ips: vec!["10.8.0.0/24".parse().unwrap()],
+                endpoint: Some(Endpoint::Addr(server_addr)),
+                ..Default::default()
+            }],
+        );
+
+        let (server_device, mut server_io) = MemoryDevice::new("wg0");
+        let (client_device, mut client_io) = MemoryDevice::new("wg1");
+        let mut server = VpnServer::with_io(
+            server_config,
+            Arc::new(server_device),
+            vec![Arc::new(listener)],
+        )
+        .unwrap();
+        let mut client = VpnServer::with_io(
+            client_config,
+            Arc::new(client_device),
+            vec![Arc::new(connection)],
+        )
+        .unwrap();
+        server.start().await.unwrap();
+        client.start().await.unwrap();
+        ping_pong([10, 8, 0, 2], &mut client_io, &mut server_io).await;
+
+        let peers = server.get_peers().await;
+        assert_eq!(peers[0].status, PeerStatus::Connected);
+
+        client.stop().await.unwrap();
+        server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_obfuscated_and_plain_peers() {
+        let (server_key, server_pub) = crypto::generate_keypair().unwrap();
+        let (hidden_key, hidden_pub) = crypto::generate_keypair().unwrap();
+        let (plain_key, plain_pub) = crypto::generate_keypair().unwrap();
+        let (obfuscation_key, _) = crypto::generate_keypair().unwrap();
+        let server_addr: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let obfuscation = ObfuscationConfig {
+            key: obfuscation_key,
+            junk_packets: 2,
+        };
+
+        let client_peer = |public_key: PublicKey, allowed_ips: &str| PeerConfig {
+            public_key,
+            allowed_ips: vec![allowed_ips.parse().unwrap()],
+            ..Default::default()
+        };
+        let mut server_config = test_config(
+            server_key,
+            "10.8.0.1/24",
+            vec![
+                client_peer(hidden_pub, "10.8.0.2/32"),
+                client_peer(plain_pub, "10.8.0.3/32"),
+            ],
+        );
+        server_config.interface.obfuscation = Some(obfuscation.clone());
+        let server_peer = |obfuscation: Option<ObfuscationConfig>| PeerConfig {
+            endpoint: Some(Endpoint::Addr(server_addr)),
+            obfuscation,
+            ..client_peer(server_pub, "10.8.0.0/24")
+        };
+
+        let network = MemoryNetwork::new();
+        let (server_device, mut server_io) = MemoryDevice::new("wg0");
+        let mut server = VpnServer::with_io(
+            server_config,
+            Arc::new(server_device),
+            vec![Arc::new(network.bind(server_addr).unwrap())],
+        )
+        .unwrap();
+        server.start().await.unwrap();
+
+        // 混淆客户端和普通 WireGuard 客户端同时连接同一端口
+        let mut clients = Vec::new();
+        for (key, address, obfuscation, port) in [
+            (hidden_key, "10.8.0.2/24", Some(obfuscation), 1),
+            (plain_key, "10.8.0.3/24", None, 2),
+        ] {
+            let (device, io) = MemoryDevice::new("wg1");
+            let addr = SocketAddr::from(([192, 0, 2, 2], port));
+            let mut client = VpnServer::with_io(
+                test_config(key, address, vec![server_peer(obfuscation)]),
+                Arc::new(device),
+                vec![Arc::new(network.bind(addr).unwrap())],
+            )
+            .unwrap();
+            client.start().await.unwrap();
+            clients.push((client, io));
+        }
+
+        ping_pong([10, 8, 0, 2], &mut clients[0].1, &mut server_io).await;
+        ping_pong([10, 8, 0, 3], &mut clients[1].1, &mut server_io).await;
+
+        let stats = server.get_stats().await;
+        assert_eq!(stats.connected_peers, 2);
+
+        for (mut client, _) in clients {
+            client.stop().await.unwrap();
+        }
+        server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_preshared_key_mismatch() {
+        let (server_key, server_pub) = crypto::generate_keypair().unwrap();
+        let (client_key, client_pub) = crypto::generate_keypair().unwrap();
+        let psk = crypto::generate_psk().unwrap();
+        let server_addr: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let client_addr: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+
+        let server_config = test_config(
+            server_key,
+            "10.8.0.1/24",
+            vec![PeerConfig {
+                public_key: client_pub,
+                allowed_ips: vec!["10.8.0.2/32".parse().unwrap()],
+                psk: Some(psk.clone()),
+                ..Default::default()
+            }],
+        );
+        let client_config = |psk: SecretKey| {
+            test_config(
+                client_key.clone(),
+                "10.8.0.2/24",
+                vec![PeerConfig {
+                    public_key: server_pub,
+                    allowed_ips: vec!["10.8.0.0/24".parse().unwrap()],
+                    endpoint: Some(Endpoint::Addr(server_addr)),
+                    psk: Some(psk),
+                    ..Default::default()
+                }],
+            )
+        };
+
+        // 长度错误的预共享密钥在加载时即被拒绝
+        let mut content = toml::to_string(&client_config(psk.clone())).unwrap();
+        content = content.replace(psk.to_base64().expose(), "c2hvcnQ=");
+        let err = toml::from_str::<ServerConfig>(&content).unwrap_err();
+        assert!(err.to_string().contains("Key must be 32 bytes, got 5"));
+
+        let network = MemoryNetwork::new();
+        let (server_device, mut server_io) = MemoryDevice::new("wg0");
+        let mut server = VpnServer::with_io(
+            server_config,
+            Arc::new(server_device),
+            vec![Arc::new(network.bind(server_addr).unwrap())],
+        )
+        .unwrap();
+        server.start().await.unwrap();
+
+        let (client_device, mut client_io) = MemoryDevice::new("wg1");
+        let mut client = VpnServer::with_io(
+            client_config(crypto::generate_psk().unwrap()),
+            Arc::new(client_device),
+            vec![Arc::new(network.bind(client_addr).unwrap())],
+        )
+        .unwrap();
+        client.start().await.unwrap();
+
+        // 预共享密钥不一致时握手无法完成，数据包不会到达服务器
+        let ping = ipv4_packet([10, 8, 0, 2], [10, 8, 0, 1], b"ping");
+        client_io.inject.send(ping).unwrap();
+        let received =
+            tokio::time::timeout(Duration::from_secs(1), server_io.received.recv()).await;
+        assert!(received.is_err());
+        assert_eq!(server.get_stats().await.connected_peers, 0);
+
+        // 换成相同的预共享密钥后握手成功
+        client.reload(client_config(psk)).await.unwrap();
+        ping_pong([10, 8, 0, 2], &mut client_io, &mut server_io).await;
+
+        client.stop().await.unwrap();
+        server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_private_key_rotation() {
+        let (old_key, old_pub) = crypto::generate_keypair().unwrap();
+        let (next_key, next_pub) = crypto::generate_keypair().unwrap();
+        let (early_key, early_pub) = crypto::generate_keypair().unwrap();
+        let (late_key, late_pub) = crypto::generate_keypair().unwrap();
+        let server_addr: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+
+        let client_peer = |public_key: PublicKey, allowed_ips: &str| PeerConfig {
+            public_key,
+            allowed_ips: vec![allowed_ips.parse().unwrap()],
+            ..Default::default()
+        };
+        let mut config = test_config(
+            old_key,
+            "10.8.0.1/24",
+            vec![
+                client_peer(early_pub, "10.8.0.2/32"),
+                client_peer(late_pub, "10.8.0.3/32"),
+            ],
+        );
+        assert!(config.interface.clone().promote_next_key(0).is_err());
+        config.interface.next_private_key = Some(next_key);
+
+        let network = MemoryNetwork::new();
+        let (server_device, mut server_io) = MemoryDevice::new("wg0");
+        let mut server = VpnServer::with_io(
+            config.clone(),
+            Arc::new(server_device),
+            vec![Arc::new(network.bind(server_addr).unwrap())],
+        )
+        .unwrap();
+        server.start().await.unwrap();
+
+        // 客户端使用指定的服务器公钥，每次从新的端口连接以触发新的握手
+        let mut port = 0;
+        let mut connect = |key: &SecretKey, address: &str, server_pub: &PublicKey| {
+            port += 1;
+            let (device, io) = MemoryDevice::new("wg1");
+            let mut peer = client_peer(*server_pub, "10.8.0.0/24");
+            peer.endpoint = Some(Endpoint::Addr(server_addr));
+            let client = VpnServer::with_io(
+                test_config(key.clone(), address, vec![peer]),
+                Arc::new(device),
+                vec![Arc::new(
+                    network
+                        .bind(SocketAddr::from(([192, 0, 2, 2], port)))
+                        .unwrap(),
+                )],
+            )
+            .unwrap();
+            (client, io)
+        };
+
+        // 提升前两个私钥都接受握手
+        let (mut early, mut early_io) = connect(&early_key, "10.8.0.2/24", &next_pub);
+        early.start().await.unwrap();
+        ping_pong([10, 8, 0, 2], &mut early_io, &mut server_io).await;
+        let (mut late, mut late_io) = connect(&late_key, "10.8.0.3/24", &old_pub);
+        late.start().await.unwrap();
+        ping_pong([10, 8, 0, 3], &mut late_io, &mut server_io).await;
+        late.stop().await.unwrap();
+
+        // 提升后旧私钥在重叠期内仍然有效
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert_eq!(config.interface.promote_next_key(now).unwrap(), next_pub);
+        assert!(config.interface.next_private_key.is_none());
+        server.reload(config.clone()).await.unwrap();
+        let (mut late, mut late_io) = connect(&late_key, "10.8.0.3/24", &old_pub);
+        late.start().await.unwrap();
+        ping_pong([10, 8, 0, 3], &mut late_io, &mut server_io).await;
+        ping_pong([10, 8, 0, 2], &mut early_io, &mut server_io).await;
+
+        // 重叠期结束后使用旧私钥的会话被关闭，新的握手被拒绝
+        config.interface.previous_key_expires = Some(now - 1);
+        server.reload(config).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        let (mut rejected, rejected_io) = connect(&late_key, "10.8.0.3/24", &old_pub);
+        rejected.start().await.unwrap();
+        for io in [&late_io, &rejected_io] {
+            io.inject
+                .send(ipv4_packet([10, 8, 0, 3], [10, 8, 0, 1], b"ping"))
+                .unwrap();
+            let received =
+                tokio::time::timeout(Duration::from_secs(1), server_io.received.recv()).await;
+            assert!(received.is_err());
+        }
+
+        rejected.stop().await.unwrap();
+        late.stop().await.unwrap();
+        early.stop().await.unwrap();
+        server.stop().await.unwrap();
+    }
+
+    #[cfg(feature = "pq")]
+    #[tokio::test]
+    async fn test_post_quantum_psk_rotation() {
+        use crate::config::PqConfig;
+        use tokio::time::Instant;
+
+        let (server_key, server_pub) = crypto::generate_keypair().unwrap();
+        let (client_key, client_pub) = crypto::generate_keypair().unwrap();
+        let server_addr: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let client_addr: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let server_pq: SocketAddr = "192.0.2.1:51821".parse().unwrap();
+        let client_pq: SocketAddr = "192.0.2.2:51821".parse().unwrap();
+        let pq = |listen: SocketAddr| PqConfig {
+            listen,
+            rotation_interval: 1,
+        };
+
+        let mut server_config = test_config(
+            server_key,
+            "10.8.0.1/24",
+            vec![PeerConfig {
+                public_key: client_pub,
+                allowed_ips: vec!["10.8.0.2/32".parse().unwrap()],
+                ..Default::default()
+            }],
+        );
+        server_config.pq = Some(pq(server_pq));
+        let mut client_config = test_config(
+            client_key,
+            "10.8.0.2/24",
+            vec![PeerConfig {
+                public_key: server_pub,
+                allowed_ips: vec!["10.8.0.0/24".parse().unwrap()],
+                endpoint: Some(Endpoint::Addr(server_addr)),
+                pq_endpoint: Some(Endpoint::Addr(server_pq)),
+                ..Default::default()
+            }],
+        );
+        client_config.pq = Some(pq(client_pq));
+
+        let network = MemoryNetwork::new();
+        let (server_device, mut server_io) = MemoryDevice::new("wg0");
+        let mut server = VpnServer::with_io(
+            server_config,
+            Arc::new(server_device),
+            vec![Arc::new(network.bind(server_addr).unwrap())],
+        )
+        .unwrap()
+        .with_pq_transport(Arc::new(network.bind(server_pq).unwrap()));
+        server.start().await.unwrap();
+
+        let (client_device, mut client_io) = MemoryDevice::new("wg1");
+        let mut client = VpnServer::with_io(
+            client_config,
+            Arc::new(client_device),
+            vec![Arc::new(network.bind(client_addr).unwrap())],
+        )
+        .unwrap()
+        .with_pq_transport(Arc::new(network.bind(client_pq).unwrap()));
+        client.start().await.unwrap();
+
+        // 持续收发数据，期间密钥应轮换多次且不丢包
+        let mut installed = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while installed.len() < 3 && Instant::now() < deadline {
+            ping_pong([10, 8, 0, 2], &mut client_io, &mut server_io).await;
+
+            let server_psk = server.get_peers().await[0].psk.clone();
+            let client_psk = client.get_peers().await[0].psk.clone();
+            if let Some(psk) = &client_psk {
+                if server_psk == client_psk && installed.last() != Some(psk) {
+                    installed.push(psk.clone());
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(installed.len(), 3);
+        assert_eq!(server.get_stats().await.connected_peers, 1);
+
+        client.stop().await.unwrap();
+        server.stop().await.unwrap();
     }
 }
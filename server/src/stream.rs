@@ -0,0 +1,456 @@
+use crate::error::{Error, Result};
+use crate::transport::Transport;
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// 每个连接上排队等待发送的报文数
+const SEND_QUEUE: usize = 256;
+
+/// 服务端的连接限制，防止未认证的连接无限期占用任务和套接字
+#[derive(Debug, Clone, Copy)]
+pub struct StreamLimits {
+    /// TLS 握手和收到第一个报文的期限
+    pub handshake_timeout: Duration,
+    /// 连接上没有收到报文的最长时间，超过后关闭，客户端有流量时重新连接
+    pub idle_timeout: Duration,
+    /// 同时保持的连接数上限，超出时直接关闭新连接
+    pub max_connections: usize,
+    /// 所有连接共用的接收队列长度，数据面处理不及时丢弃新到的报文
+    pub recv_queue: usize,
+}
+
+impl Default for StreamLimits {
+    fn default() -> Self {
+        StreamLimits {
+            handshake_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(180),
+            max_connections: 1024,
+            recv_queue: 1024,
+        }
+    }
+}
+
+type Datagram = (Vec<u8>, SocketAddr);
+type Connections = Arc<StdMutex<HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>>;
+
+/// 流式传输：在 TCP（可选 TLS）连接上承载 WireGuard 报文
+///
+/// 每个报文前加 2 字节大端长度。服务端以连接的远端地址区分对等体，
+/// 因此与 UDP 共用同一套对等体和会话逻辑。
+pub struct StreamTransport {
+    local_addr: SocketAddr,
+    /// 客户端模式下连接的服务器地址，所有报文都经该连接发出
+    server: Option<SocketAddr>,
+    connections: Connections,
+    inbound: Mutex<mpsc::Receiver<Datagram>>,
+    accept_task: Option<JoinHandle<()>>,
+}
+
+impl StreamTransport {
+    /// 监听 TCP 端口，`tls` 不为空时要求客户端使用 TLS
+    pub async fn bind(
+        addr: SocketAddr,
+        tls: Option<TlsAcceptor>,
+        limits: StreamLimits,
+    ) -> Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .map_err(|e| Error::NetworkError(format!("Failed to bind stream listener: {}", e)))?;
+        let local_addr = listener.local_addr()?;
+        let connections: Connections = Arc::default();
+        let (inbound_tx, inbound_rx) = mpsc::channel(limits.recv_queue);
+
+        info!(
+            "Stream transport listening on {} ({})",
+            local_addr,
+            if tls.is_some() { "TLS" } else { "TCP" }
+        );
+
+        let accept_connections = connections.clone();
+        let slots = Arc::new(Semaphore::new(limits.max_connections));
+        let accept_task = tokio::spawn(async move {
+            loop {
+                let (stream, remote) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Failed to accept stream connection: {}", e);
+                        continue;
+                    }
+                };
+                let Ok(permit) = slots.clone().try_acquire_owned() else {
+                    debug!(
+                        "Rejecting stream connection from {}: too many connections",
+                        remote
+                    );
+                    continue;
+                };
+                let _ = stream.set_nodelay(true);
+                debug!("Accepted stream connection from {}", remote);
+
+                let connections = accept_connections.clone();
+                let inbound = inbound_tx.clone();
+                let accepted = Accepted {
+                    limits,
+                    _permit: permit,
+                };
+                match &tls {
+                    Some(acceptor) => {
+                        let handshake = acceptor.accept(stream);
+                        tokio::spawn(async move {
+                            match tokio::time::timeout(limits.handshake_timeout, handshake).await {
+                                Ok(Ok(stream)) => {
+                                    register(stream, remote, &connections, inbound, Some(accepted))
+                                }
+                                Ok(Err(e)) => debug!("TLS handshake with {} failed: {}", remote, e),
+                                Err(_) => debug!("TLS handshake with {} timed out", remote),
+                            }
+                        });
+                    }
+                    None => register(stream, remote, &connections, inbound, Some(accepted)),
+                }
+            }
+        });
+
+        Ok(StreamTransport {
+            local_addr,
+            server: None,
+            connections,
+            inbound: Mutex::new(inbound_rx),
+            accept_task: Some(accept_task),
+        })
+    }
+
+    /// 连接到服务器（客户端），`tls` 为连接器和证书中的服务器名称
+    pub async fn connect(
+        addr: SocketAddr,
+        tls: Option<(TlsConnector, ServerName<'static>)>,
+    ) -> Result<Self> {
+        let stream = TcpStream::connect(addr)
+            .await
+            .map_err(|e| Error::NetworkError(format!("Failed to connect to {}: {}", addr, e)))?;
+        let _ = stream.set_nodelay(true);
+        let local_addr = stream.local_addr()?;
+        let connections: Connections = Arc::default();
+        let (inbound_tx, inbound_rx) = mpsc::channel(StreamLimits::default().recv_queue);
+
+        match tls {
+            Some((connector, name)) => {
+                let stream = connector.connect(name, stream).await.map_err(|e| {
+                    Error::NetworkError(format!("TLS handshake with {} failed: {}", addr, e))
+                })?;
+                register(stream, addr, &connections, inbound_tx, None);
+            }
+            None => register(stream, addr, &connections, inbound_tx, None),
+        }
+
+        Ok(StreamTransport {
+            local_addr,
+            server: Some(addr),
+            connections,
+            inbound: Mutex::new(inbound_rx),
+            accept_task: None,
+        })
+    }
+}
+
+#[async_trait]
+impl Transport for StreamTransport {
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (data, from) = self
+            .inbound
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "transport closed"))?;
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok((len, from))
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        let target = self.server.unwrap_or(target);
+        let sender = self.connections.lock().unwrap().get(&target).cloned();
+        // 与 UDP 一样，连接不存在或队列已满时丢弃
+        match sender {
+            Some(sender) => {
+                if sender.try_send(buf.to_vec()).is_err() {
+                    debug!("Dropping datagram for {}: send queue full", target);
+                }
+            }
+            None => debug!("No stream connection to {}", target),
+        }
+        Ok(buf.len())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+impl Drop for StreamTransport {
+    fn drop(&mut self) {
+        // 已建立的连接在接收队列释放后自行关闭
+        if let Some(task) = self.accept_task.take() {
+            task.abort();
+        }
+    }
+}
+
+/// 服务端接受的连接，占用一个连接名额直到关闭
+struct Accepted {
+    limits: StreamLimits,
+    _permit: OwnedSemaphorePermit,
+}
+
+/// 登记连接并在后台收发报文，`accepted` 为空时（客户端）不限制空闲时间
+fn register<S>(
+    stream: S,
+    remote: SocketAddr,
+    connections: &Connections,
+    inbound: mpsc::Sender<Datagram>,
+    accepted: Option<Accepted>,
+) where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(SEND_QUEUE);
+    connections.lock().unwrap().insert(remote, tx.clone());
+    tokio::spawn(serve(
+        stream,
+        remote,
+        connections.clone(),
+        (tx, rx),
+        inbound,
+        accepted,
+    ));
+}
+
+/// 处理单个连接直到关闭
+async fn serve<S>(
+    stream: S,
+    remote: SocketAddr,
+    connections: Connections,
+    (tx, mut rx): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>),
+    inbound: mpsc::Sender<Datagram>,
+    accepted: Option<Accepted>,
+) where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    let write_task = tokio::spawn(async move {
+        while let Some(datagram) = rx.recv().await {
+            if let Err(e) = write_frame(&mut writer, &datagram).await {
+                debug!("Failed to write to {}: {}", remote, e);
+                break;
+            }
+        }
+        let _ = writer.shutdown().await;
+    });
+
+    let mut buf = vec![0u8; u16::MAX as usize];
+    // 第一个报文须在握手期限内到达，之后按空闲时间计算
+    let mut deadline = accepted.as_ref().map(|a| a.limits.handshake_timeout);
+    loop {
+        let read = read_frame(&mut reader, &mut buf);
+        let result = tokio::select! {
+            result = async {
+                match deadline {
+                    Some(deadline) => tokio::time::timeout(deadline, read)
+                        .await
+                        .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+                    None => read.await,
+                }
+            } => result,
+            // 传输已释放
+            _ = inbound.closed() => break,
+        };
+        deadline = accepted.as_ref().map(|a| a.limits.idle_timeout);
+        match result {
+            Ok(len) => match inbound.try_send((buf[..len].to_vec(), remote)) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    debug!("Dropping datagram from {}: receive queue full", remote);
+                }
+                Err(TrySendError::Closed(_)) => break,
+            },
+            Err(e) => {
+                if e.kind() != io::ErrorKind::UnexpectedEof {
+                    debug!("Stream connection from {} failed: {}", remote, e);
+                }
+                break;
+            }
+        }
+    }
+
+    // 只移除属于本连接的登记，避免覆盖同地址的新连接
+    {
+        let mut connections = connections.lock().unwrap();
+        if connections
+            .get(&remote)
+            .is_some_and(|sender| sender.same_channel(&tx))
+        {
+            connections.remove(&remote);
+        }
+    }
+    write_task.abort();
+    debug!("Stream connection from {} closed", remote);
+}
+
+/// 读取一个带长度前缀的报文
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let len = reader.read_u16().await? as usize;
+    reader.read_exact(&mut buf[..len]).await?;
+    Ok(len)
+}
+
+/// 写入一个带长度前缀的报文
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, datagram: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(datagram.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "datagram too large"))?;
+    let mut frame = Vec::with_capacity(2 + datagram.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(datagram);
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+/// 从 PEM 文件加载服务端 TLS 证书链和私钥
+pub fn tls_acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| Error::ConfigError(format!("Failed to load TLS certificate: {}", e)))?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| Error::ConfigError(format!("Failed to load TLS key: {}", e)))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| Error::ConfigError(format!("Invalid TLS certificate: {}", e)))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// 创建只信任指定 CA 证书的客户端 TLS 连接器
+pub fn tls_connector(ca_path: &Path) -> Result<TlsConnector> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(ca_path)
+        .map_err(|e| Error::ConfigError(format!("Failed to load CA certificate: {}", e)))?
+    {
+        let cert =
+            cert.map_err(|e| Error::ConfigError(format!("Invalid CA certificate: {}", e)))?;
+        roots
+            .add(cert)
+            .map_err(|e| Error::ConfigError(format!("Invalid CA certificate: {}", e)))?;
+    }
+
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tls_stream_transport() {
+        let dir = std::env::temp_dir().join(format!("rusty-tunnel-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert = rcgen::generate_simple_self_signed(vec!["vpn.example.com".to_string()]).unwrap();
+        std::fs::write(dir.join("cert.pem"), cert.cert.pem()).unwrap();
+        std::fs::write(dir.join("key.pem"), cert.key_pair.serialize_pem()).unwrap();
+
+        let acceptor = tls_acceptor(&dir.join("cert.pem"), &dir.join("key.pem")).unwrap();
+        let connector = tls_connector(&dir.join("cert.pem")).unwrap();
+        let server = StreamTransport::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            Some(acceptor),
+            StreamLimits::default(),
+        )
+        .await
+        .unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let name = ServerName::try_from("vpn.example.com").unwrap();
+        let client = StreamTransport::connect(server_addr, Some((connector, name)))
+            .await
+            .unwrap();
+
+        client.send_to(b"handshake", server_addr).await.unwrap();
+        let mut buf = [0u8; 32];
+        let (len, from) = server.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"handshake");
+        assert_eq!(from, client.local_addr().unwrap());
+
+        // 服务端按来源地址回复到同一连接
+        server.send_to(b"response", from).await.unwrap();
+        let (len, from) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"response");
+        assert_eq!(from, server_addr);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// 服务端是否关闭了连接
+    async fn closed(stream: &mut TcpStream) -> bool {
+        let mut buf = [0u8; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf));
+        matches!(read.await, Ok(Ok(0) | Err(_)))
+    }
+
+    #[tokio::test]
+    async fn test_stream_limits() {
+        let limits = StreamLimits {
+            handshake_timeout: Duration::from_millis(200),
+            idle_timeout: Duration::from_millis(200),
+            max_connections: 1,
+            recv_queue: 4,
+        };
+        let server = StreamTransport::bind("127.0.0.1:0".parse().unwrap(), None, limits)
+            .await
+            .unwrap();
+        let server_addr = server.local_addr().unwrap();
+        // 超出上限的连接被直接关闭
+        let mut silent = TcpStream::connect(server_addr).await.unwrap();
+        let mut rejected = TcpStream::connect(server_addr).await.unwrap();
+        assert!(closed(&mut rejected).await);
+
+        // 不发送报文的连接在期限后关闭并释放名额
+        assert!(closed(&mut silent).await);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let client = StreamTransport::connect(server_addr, None).await.unwrap();
+        client.send_to(b"handshake", server_addr).await.unwrap();
+        let mut buf = [0u8; 32];
+        let (len, _) = server.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"handshake");
+
+        // 数据面未及时读取时接收队列不超过上限，多出的报文被丢弃
+        for _ in 0..10 {
+            client.send_to(b"flood", server_addr).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut queued = 0;
+        while let Ok(received) =
+            tokio::time::timeout(Duration::from_millis(50), server.recv_from(&mut buf)).await
+        {
+            received.unwrap();
+            queued += 1;
+        }
+        assert_eq!(queued, limits.recv_queue);
+    }
+}
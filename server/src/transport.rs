@@ -0,0 +1,143 @@
+use crate::error::{Error, Result};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, Mutex};
+
+/// 数据报传输：承载加密后的 WireGuard 报文
+#[async_trait]
+pub trait Transport: Send + Sync {
+    /// 接收一个数据报，返回长度和来源地址
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+    /// 发送一个数据报
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize>;
+    /// 本地地址
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+/// UDP 传输
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    /// 绑定 UDP 套接字
+    pub async fn bind(addr: SocketAddr) -> Result<Self> {
+        let socket = UdpSocket::bind(addr)
+            .await
+            .map_err(|e| Error::NetworkError(format!("Failed to bind socket: {}", e)))?;
+        Ok(UdpTransport { socket })
+    }
+}
+
+#[async_trait]
+impl Transport for UdpTransport {
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.socket.recv_from(buf).await
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        self.socket.send_to(buf, target).await
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// 内存网络：在进程内按地址投递数据报，用于测试
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    endpoints: Arc<StdMutex<HashMap<SocketAddr, mpsc::UnboundedSender<Datagram>>>>,
+}
+
+impl MemoryNetwork {
+    /// 创建空网络
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 在指定地址上创建传输端点
+    pub fn bind(&self, addr: SocketAddr) -> Result<MemoryTransport> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut endpoints = self.endpoints.lock().unwrap();
+        if endpoints.contains_key(&addr) {
+            return Err(Error::NetworkError(format!("Address in use: {}", addr)));
+        }
+        endpoints.insert(addr, tx);
+
+        Ok(MemoryTransport {
+            addr,
+            network: self.clone(),
+            inbound: Mutex::new(rx),
+        })
+    }
+}
+
+/// 内存网络上的传输端点
+pub struct MemoryTransport {
+    addr: SocketAddr,
+    network: MemoryNetwork,
+    inbound: Mutex<mpsc::UnboundedReceiver<Datagram>>,
+}
+
+#[async_trait]
+impl Transport for MemoryTransport {
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (data, from) = self
+            .inbound
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "transport closed"))?;
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok((len, from))
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        // 与 UDP 一样，目标不存在时静默丢弃
+        let endpoints = self.network.endpoints.lock().unwrap();
+        if let Some(tx) = endpoints.get(&target) {
+            let _ = tx.send((buf.to_vec(), self.addr));
+        }
+        Ok(buf.len())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        if let Ok(mut endpoints) = self.network.endpoints.lock() {
+            endpoints.remove(&self.addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_transport() {
+        let network = MemoryNetwork::new();
+        let a = network.bind("10.0.0.1:51820".parse().unwrap()).unwrap();
+        let b = network.bind("10.0.0.2:51820".parse().unwrap()).unwrap();
+        assert!(network.bind(a.local_addr().unwrap()).is_err());
+
+        a.send_to(b"hello", b.local_addr().unwrap()).await.unwrap();
+        let mut buf = [0u8; 16];
+        let (len, from) = b.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"hello");
+        assert_eq!(from, a.local_addr().unwrap());
+    }
+}
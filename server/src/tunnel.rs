@@ -0,0 +1,1064 @@
+use crate::config::{InterfaceConfig, PeerConfig, ServerConfig};
+use crate::device::Device;
+use crate::error::{Error, Result};
+use crate::obfuscation::{self, Obfuscator, TrialBudget, TRIALS_PER_SECOND};
+use crate::peer::{current_timestamp, Peer, PeerStatus};
+use crate::secret::SecretKey;
+use crate::transport::Transport;
+use boringtun::noise::errors::WireGuardError;
+use boringtun::noise::handshake::parse_handshake_anon;
+use boringtun::noise::rate_limiter::RateLimiter;
+use boringtun::noise::{Packet, Tunn, TunnResult};
+use ipnet::IpNet;
+use log::{debug, info, warn};
+use std::borrow::Cow;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// 单个数据报的最大长度
+const MAX_PACKET: usize = 65_536;
+
+/// boringtun 计时器的驱动间隔
+const TIMER_INTERVAL: Duration = Duration::from_millis(250);
+
+/// 超过该时间未握手的对等体视为已断开
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// 每个本端密钥每秒处理的握手数，超过后要求对端先用 cookie 证明地址
+const HANDSHAKE_RATE_LIMIT: u64 = 1000;
+
+/// 新会话建立后旧会话的保留时间，用于接收途中的报文
+const PREVIOUS_SESSION_GRACE: Duration = Duration::from_secs(10);
+
+/// 还原后的报文及对端使用的混淆器
+type Revealed<'a> = (Cow<'a, [u8]>, Option<Arc<Obfuscator>>);
+
+/// 对等体端点及到达时使用的传输
+#[derive(Clone)]
+struct Endpoint {
+    /// 对端地址
+    addr: SocketAddr,
+    /// 收发所用传输在 `Tunnel::transports` 中的下标
+    transport: usize,
+    /// 对端使用的混淆，回复时保持一致
+    obfuscator: Option<Arc<Obfuscator>>,
+}
+
+/// 本端静态密钥
+struct LocalKey {
+    secret: StaticSecret,
+    public: PublicKey,
+    /// 失效时间（Unix 秒），仅被替换的旧私钥设置
+    expires: Option<u64>,
+    /// 校验发往该密钥的握手 MAC 并限速，所有对等体的会话共用
+    limiter: Arc<RateLimiter>,
+}
+
+impl LocalKey {
+    /// 是否仍接受发往该密钥的握手
+    fn accepts(&self) -> bool {
+        self.expires.is_none_or(|expires| unix_now() < expires)
+    }
+}
+
+/// 一个 boringtun 隧道及其会话索引
+struct Session {
+    /// 会话索引（握手消息中的 receiver index 高 24 位）
+    index: u32,
+    /// 本端使用的静态密钥
+    local_key: Arc<LocalKey>,
+    /// 握手使用的预共享密钥
+    psk: Option<SecretKey>,
+    /// boringtun 隧道状态
+    tunn: Tunn,
+}
+
+impl Session {
+    fn established(&self) -> bool {
+        self.tunn.time_since_last_handshake().is_some()
+    }
+}
+
+/// 对等体的会话状态
+///
+/// 更换预共享密钥需要新的 `Tunn`；旧隧道保留为 `previous`，在新会话建立前继续收发数据。
+struct Sessions {
+    current: Session,
+    previous: Option<Session>,
+    /// 已丢弃隧道的发送和接收字节数
+    retired_bytes: (usize, usize),
+}
+
+impl Sessions {
+    fn new(current: Session) -> Self {
+        Sessions {
+            current,
+            previous: None,
+            retired_bytes: (0, 0),
+        }
+    }
+
+    fn owns(&self, index: u32) -> bool {
+        self.current.index == index || self.previous.as_ref().is_some_and(|p| p.index == index)
+    }
+
+    /// 按会话索引选择隧道，不匹配时使用当前隧道
+    fn tunn(&mut self, index: u32) -> &mut Tunn {
+        match &mut self.previous {
+            Some(previous) if previous.index == index => &mut previous.tunn,
+            _ => &mut self.current.tunn,
+        }
+    }
+
+    /// 发送数据所用的隧道：新会话建立前沿用旧会话
+    fn outbound(&mut self) -> &mut Tunn {
+        match &mut self.previous {
+            Some(previous) if !self.current.established() => &mut previous.tunn,
+            _ => &mut self.current.tunn,
+        }
+    }
+
+    /// 用新隧道替换当前隧道，保留仍可用的旧会话
+    fn replace(&mut self, session: Session) {
+        let old = std::mem::replace(&mut self.current, session);
+        match &self.previous {
+            Some(_) if !old.established() => self.retire(old),
+            _ => {
+                if let Some(previous) = self.previous.replace(old) {
+                    self.retire(previous);
+                }
+            }
+        }
+    }
+
+    /// 新会话建立足够久后丢弃旧会话
+    fn expire_previous(&mut self) {
+        let settled = self
+            .current
+            .tunn
+            .time_since_last_handshake()
+            .is_some_and(|age| age >= PREVIOUS_SESSION_GRACE);
+        if settled {
+            if let Some(previous) = self.previous.take() {
+                self.retire(previous);
+            }
+        }
+    }
+
+    /// 丢弃本端密钥不再被接受的旧会话，返回当前会话的本端密钥是否仍被接受
+    fn retire_rejected(&mut self, accepted: impl Fn(&LocalKey) -> bool) -> bool {
+        if self
+            .previous
+            .as_ref()
+            .is_some_and(|p| !accepted(&p.local_key))
+        {
+            let previous = self.previous.take().expect("checked above");
+            self.retire(previous);
+        }
+        accepted(&self.current.local_key)
+    }
+
+    /// 丢弃当前会话，换成尚未握手的新会话
+    fn reset(&mut self, session: Session) {
+        let old = std::mem::replace(&mut self.current, session);
+        self.retire(old);
+    }
+
+    fn retire(&mut self, session: Session) {
+        let (_, tx_bytes, rx_bytes, _, _) = session.tunn.stats();
+        self.retired_bytes.0 += tx_bytes;
+        self.retired_bytes.1 += rx_bytes;
+    }
+
+    /// 最近一次握手距今的时间，以及累计的发送和接收字节数
+    fn stats(&self) -> (Option<Duration>, usize, usize) {
+        let (current_age, mut tx_bytes, mut rx_bytes, _, _) = self.current.tunn.stats();
+        let mut age = current_age;
+        if let Some(previous) = &self.previous {
+            let (previous_age, tx, rx, _, _) = previous.tunn.stats();
+            age = age.or(previous_age);
+            tx_bytes += tx;
+            rx_bytes += rx;
+        }
+        (
+            age,
+            tx_bytes + self.retired_bytes.0,
+            rx_bytes + self.retired_bytes.1,
+        )
+    }
+}
+
+/// 单个对等体的 Noise 会话
+struct PeerTunnel {
+    /// 公钥
+    public_key: [u8; 32],
+    /// 配置的预共享密钥，运行时轮换的密钥只保存在会话中
+    psk: Option<SecretKey>,
+    /// 保活报文间隔（秒）
+    keepalive: Option<u16>,
+    /// 允许的 IP 地址
+    allowed_ips: Vec<IpNet>,
+    /// 为该对等体配置的混淆，用于主动发往配置端点的流量
+    obfuscator: Option<Arc<Obfuscator>>,
+    /// boringtun 会话状态
+    sessions: Arc<Mutex<Sessions>>,
+    /// 最近一次收到有效报文的端点，回复从同一地址和端口发出
+    endpoint: Arc<Mutex<Option<Endpoint>>>,
+}
+
+impl PeerTunnel {
+    fn allows(&self, addr: IpAddr) -> bool {
+        self.allowed_ips.iter().any(|net| net.contains(&addr))
+    }
+
+    fn endpoint(&self) -> Option<Endpoint> {
+        self.endpoint.lock().unwrap().clone()
+    }
+}
+
+/// 收到的报文应交给的会话
+enum Target {
+    /// 握手发起报文，交给使用对端所选本端密钥的当前会话
+    Handshake(Arc<LocalKey>),
+    /// 按 receiver index 查找会话
+    Session(u32),
+}
+
+/// 查找收到的报文所属会话的结果
+enum Lookup {
+    /// 交给对等体的会话
+    Peer(Arc<PeerTunnel>, Target),
+    /// 握手过多，回复 cookie 要求对端证明地址
+    Cookie(Vec<u8>),
+    /// 无法识别、MAC 无效或被限速
+    Drop,
+}
+
+/// 用户空间 WireGuard 数据面
+///
+/// 在传输与数据包设备之间转发报文：网络侧解密后写入设备，设备侧按目标地址选择对等体加密后发出。
+pub struct Tunnel {
+    /// 接受握手的本端密钥，第一个为当前私钥
+    keys: RwLock<Vec<Arc<LocalKey>>>,
+    peers: RwLock<Vec<Arc<PeerTunnel>>>,
+    /// 接口级混淆，接受使用该密钥的对等体
+    obfuscator: Option<Arc<Obfuscator>>,
+    /// 接口和各对等体的混淆，去掉重复项，用于识别未知来源的混淆报文
+    candidates: RwLock<Vec<Arc<Obfuscator>>>,
+    /// 对未知来源的报文逐个尝试 `candidates` 的预算
+    trials: Mutex<TrialBudget>,
+    next_index: AtomicU32,
+    device: Arc<dyn Device>,
+    transports: Vec<Arc<dyn Transport>>,
+}
+
+impl Tunnel {
+    /// 根据配置创建数据面，`transports` 为各监听地址上的传输
+    pub fn new(
+        config: &ServerConfig,
+        device: Arc<dyn Device>,
+        transports: Vec<Arc<dyn Transport>>,
+    ) -> Result<Self> {
+        if transports.is_empty() {
+            return Err(Error::NetworkError(
+                "At least one transport is required".to_string(),
+            ));
+        }
+
+        let obfuscator = config
+            .interface
+            .obfuscation
+            .as_ref()
+            .map(Obfuscator::from_config)
+            .map(Arc::new);
+
+        let tunnel = Tunnel {
+            keys: RwLock::new(Vec::new()),
+            peers: RwLock::new(Vec::new()),
+            obfuscator,
+            candidates: RwLock::new(Vec::new()),
+            trials: Mutex::new(TrialBudget::new(TRIALS_PER_SECOND)),
+            next_index: AtomicU32::new(0),
+            device,
+            transports,
+        };
+        tunnel.set_keys(&config.interface)?;
+        tunnel.set_peers(&config.peers)?;
+        Ok(tunnel)
+    }
+
+    /// 更新本端私钥，新建的会话使用当前私钥
+    ///
+    /// 已建立的会话不受影响，直到其使用的私钥失效后由计时器关闭。
+    /// 公钥不变的密钥沿用原有的限速器，重载不会清空握手计数。
+    pub fn set_keys(&self, config: &InterfaceConfig) -> Result<()> {
+        let current = self.keys.read().unwrap().clone();
+        let mut keys = Vec::new();
+        for (private_key, expires) in config.accepted_private_keys(unix_now())? {
+            let secret = StaticSecret::from(*private_key.as_bytes());
+            let public = PublicKey::from(&secret);
+            let limiter = current
+                .iter()
+                .find(|key| key.public == public)
+                .map(|key| key.limiter.clone())
+                .unwrap_or_else(|| Arc::new(RateLimiter::new(&public, HANDSHAKE_RATE_LIMIT)));
+            keys.push(Arc::new(LocalKey {
+                public,
+                secret,
+                expires,
+                limiter,
+            }));
+        }
+        if keys.len() > 1 {
+            info!("Accepting handshakes for {} private keys", keys.len());
+        }
+        *self.keys.write().unwrap() = keys;
+        Ok(())
+    }
+
+    /// 替换对等体列表，保留公钥、预共享密钥和保活间隔未变的对等体的会话
+    pub fn set_peers(&self, configs: &[PeerConfig]) -> Result<()> {
+        let current = self.peers.read().unwrap().clone();
+        let mut peers = Vec::with_capacity(configs.len());
+
+        for config in configs {
+            let public_key = *config.public_key.as_bytes();
+            let psk = config.psk.clone();
+            let keepalive = config.persistent_keepalive;
+            let allowed_ips = config.allowed_ips.clone();
+            let obfuscator = config
+                .obfuscation
+                .as_ref()
+                .map(Obfuscator::from_config)
+                .map(Arc::new);
+            let configured_endpoint = config.endpoint_addr().map(|addr| Endpoint {
+                addr,
+                transport: self.transport_for(addr),
+                obfuscator: obfuscator.clone(),
+            });
+
+            let existing = current
+                .iter()
+                .find(|p| p.public_key == public_key && p.psk == psk && p.keepalive == keepalive);
+
+            let peer = match existing {
+                Some(old) => {
+                    if configured_endpoint.is_some() {
+                        *old.endpoint.lock().unwrap() = configured_endpoint;
+                    }
+                    PeerTunnel {
+                        public_key,
+                        psk,
+                        keepalive,
+                        allowed_ips,
+                        obfuscator,
+                        sessions: old.sessions.clone(),
+                        endpoint: old.endpoint.clone(),
+                    }
+                }
+                None => PeerTunnel {
+                    public_key,
+                    psk: psk.clone(),
+                    keepalive,
+                    allowed_ips,
+                    obfuscator,
+                    sessions: Arc::new(Mutex::new(Sessions::new(self.session(
+                        public_key,
+                        psk,
+                        keepalive,
+                        self.keys.read().unwrap()[0].clone(),
+                    )?))),
+                    endpoint: Arc::new(Mutex::new(configured_endpoint)),
+                },
+            };
+            peers.push(Arc::new(peer));
+        }
+
+        let mut candidates: Vec<Arc<Obfuscator>> = Vec::new();
+        for obfuscator in self
+            .obfuscator
+            .iter()
+            .chain(peers.iter().filter_map(|p| p.obfuscator.as_ref()))
+        {
+            if !candidates.iter().any(|c| c == obfuscator) {
+                candidates.push(obfuscator.clone());
+            }
+        }
+
+        *self.candidates.write().unwrap() = candidates;
+        *self.peers.write().unwrap() = peers;
+        Ok(())
+    }
+
+    /// 为对等体创建新的 boringtun 隧道
+    fn session(
+        &self,
+        public_key: [u8; 32],
+        psk: Option<SecretKey>,
+        keepalive: Option<u16>,
+        local_key: Arc<LocalKey>,
+    ) -> Result<Session> {
+        let index = self.next_index.fetch_add(1, Ordering::Relaxed);
+        let tunn = Tunn::new(
+            local_key.secret.clone(),
+            PublicKey::from(public_key),
+            psk.as_ref().map(|psk| *psk.as_bytes()),
+            keepalive,
+            index,
+            Some(local_key.limiter.clone()),
+        )
+        .map_err(|e| Error::CryptoError(format!("Failed to create tunnel: {}", e)))?;
+        Ok(Session {
+            index,
+            local_key,
+            psk,
+            tunn,
+        })
+    }
+
+    /// 为对等体安装新的预共享密钥，已建立的会话在新握手完成前继续使用
+    ///
+    /// `initiate` 为真时立即用新密钥发起握手，否则等待对端发起。
+    pub async fn set_preshared_key(
+        &self,
+        public_key: &[u8; 32],
+        psk: SecretKey,
+        initiate: bool,
+    ) -> Result<()> {
+        let peer = self
+            .peers
+            .read()
+            .unwrap()
+            .iter()
+            .find(|p| &p.public_key == public_key)
+            .cloned()
+            .ok_or_else(|| Error::Other("Peer not found".to_string()))?;
+
+        let mut out = vec![0u8; MAX_PACKET];
+        let packet = {
+            let mut sessions = peer.sessions.lock().unwrap();
+            let local_key = sessions.current.local_key.clone();
+            sessions.replace(self.session(
+                peer.public_key,
+                Some(psk),
+                peer.keepalive,
+                local_key,
+            )?);
+            if !initiate {
+                return Ok(());
+            }
+            match sessions
+                .current
+                .tunn
+                .format_handshake_initiation(&mut out, false)
+            {
+                TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+                _ => return Ok(()),
+            }
+        };
+
+        if let Some(endpoint) = peer.endpoint() {
+            self.send_to(&packet, &endpoint).await;
+        }
+        Ok(())
+    }
+
+    /// 启动网络、设备和计时器任务，每个传输各有一个网络任务
+    pub fn spawn(self: &Arc<Self>) -> Vec<JoinHandle<()>> {
+        let mut tasks = Vec::with_capacity(self.transports.len() + 2);
+        for (index, transport) in self.transports.iter().enumerate() {
+            info!(
+                "Starting userspace data plane on {}",
+                transport
+                    .local_addr()
+                    .map(|a| a.to_string())
+                    .unwrap_or_default()
+            );
+            let network = self.clone();
+            tasks.push(tokio::spawn(
+                async move { network.network_loop(index).await },
+            ));
+        }
+
+        let device = self.clone();
+        let timers = self.clone();
+        tasks.push(tokio::spawn(async move { device.device_loop().await }));
+        tasks.push(tokio::spawn(async move { timers.timer_loop().await }));
+        tasks
+    }
+
+    /// 用会话计数器更新对等体状态
+    pub fn update_peers(&self, peers: &mut [Peer]) {
+        let tunnels = self.peers.read().unwrap();
+        for peer in peers.iter_mut() {
+            let Some(tunnel) = tunnels
+                .iter()
+                .find(|t| &t.public_key == peer.public_key.as_bytes())
+            else {
+                continue;
+            };
+
+            let (since_handshake, tx_bytes, rx_bytes) = tunnel.sessions.lock().unwrap().stats();
+            peer.bytes_sent = tx_bytes as u64;
+            peer.bytes_received = rx_bytes as u64;
+            if let Some(endpoint) = tunnel.endpoint() {
+                peer.endpoint = Some(endpoint.addr);
+            }
+            match since_handshake {
+                Some(age) if age < HANDSHAKE_TIMEOUT => {
+                    peer.status = PeerStatus::Connected;
+                    peer.last_handshake = current_timestamp().saturating_sub(age.as_secs());
+                }
+                _ => peer.status = PeerStatus::Disconnected,
+            }
+        }
+    }
+
+    /// 为配置的端点选择传输：优先地址族相同的，否则使用第一个
+    fn transport_for(&self, addr: SocketAddr) -> usize {
+        self.transports
+            .iter()
+            .position(|t| {
+                t.local_addr()
+                    .map(|local| local.is_ipv4() == addr.is_ipv4())
+                    .unwrap_or(false)
+            })
+            .unwrap_or(0)
+    }
+
+    /// 通过端点对应的传输发送报文
+    async fn send_to(&self, packet: &[u8], endpoint: &Endpoint) {
+        let transport = self.transports[endpoint.transport].as_ref();
+        send(
+            transport,
+            packet,
+            endpoint.addr,
+            endpoint.obfuscator.as_deref(),
+        )
+        .await;
+    }
+
+    /// 识别明文或混淆报文，返回还原后的报文和对端使用的混淆器
+    ///
+    /// 先尝试 `from` 上次使用的混淆；来源未知时才逐个尝试全部候选密钥，并受 `trials` 预算限制。
+    fn reveal<'a>(&self, datagram: &'a [u8], from: SocketAddr) -> Option<Revealed<'a>> {
+        if obfuscation::looks_plain(datagram) {
+            return Some((Cow::Borrowed(datagram), None));
+        }
+        let decode = |obfuscator: &Arc<Obfuscator>| {
+            obfuscator
+                .decode(datagram)
+                .map(|packet| (Cow::Owned(packet), Some(obfuscator.clone())))
+        };
+
+        let known = self
+            .peers
+            .read()
+            .unwrap()
+            .iter()
+            .filter_map(|p| p.endpoint())
+            .find(|endpoint| endpoint.addr == from)
+            .and_then(|endpoint| endpoint.obfuscator);
+        if let Some(revealed) = known.as_ref().and_then(decode) {
+            return Some(revealed);
+        }
+
+        let candidates = self.candidates.read().unwrap();
+        if candidates.is_empty() {
+            return None;
+        }
+        if !self
+            .trials
+            .lock()
+            .unwrap()
+            .take(candidates.len(), Instant::now())
+        {
+            debug!(
+                "Obfuscation trial budget exhausted, dropping datagram from {}",
+                from
+            );
+            return None;
+        }
+        candidates.iter().find_map(decode)
+    }
+
+    /// 根据报文找到对应的对等体及会话，`out` 用于生成 cookie 回复
+    ///
+    /// 握手发起报文的 MAC1 以本端公钥为密钥，先用它选出对端使用的本端密钥并限速，
+    /// 只有通过校验的报文才进行 DH 运算。
+    fn peer_for_datagram(&self, datagram: &[u8], from: IpAddr, out: &mut [u8]) -> Lookup {
+        let peers = self.peers.read().unwrap();
+        let by_index = |receiver_idx: u32| {
+            let index = receiver_idx >> 8;
+            peers
+                .iter()
+                .find(|p| p.sessions.lock().unwrap().owns(index))
+                .map_or(Lookup::Drop, |p| {
+                    Lookup::Peer(p.clone(), Target::Session(index))
+                })
+        };
+
+        let Ok(packet) = Tunn::parse_incoming_packet(datagram) else {
+            return Lookup::Drop;
+        };
+        match packet {
+            Packet::HandshakeInit(init) => {
+                let keys = self.keys.read().unwrap();
+                for key in keys.iter().filter(|key| key.accepts()) {
+                    match key.limiter.verify_packet(Some(from), datagram, out) {
+                        Ok(_) => {}
+                        Err(TunnResult::Err(WireGuardError::InvalidMac)) => continue,
+                        Err(TunnResult::WriteToNetwork(cookie)) => {
+                            return Lookup::Cookie(cookie.to_vec())
+                        }
+                        Err(_) => return Lookup::Drop,
+                    }
+                    let Ok(half) = parse_handshake_anon(&key.secret, &key.public, &init) else {
+                        return Lookup::Drop;
+                    };
+                    return peers
+                        .iter()
+                        .find(|p| p.public_key == half.peer_static_public)
+                        .map_or(Lookup::Drop, |p| {
+                            Lookup::Peer(p.clone(), Target::Handshake(key.clone()))
+                        });
+                }
+                Lookup::Drop
+            }
+            Packet::HandshakeResponse(response) => by_index(response.receiver_idx),
+            Packet::PacketCookieReply(cookie) => by_index(cookie.receiver_idx),
+            Packet::PacketData(data) => by_index(data.receiver_idx),
+        }
+    }
+
+    /// 按目标地址选择对等体（最长前缀匹配）
+    fn peer_for_destination(&self, dst: IpAddr) -> Option<Arc<PeerTunnel>> {
+        let peers = self.peers.read().unwrap();
+        peers
+            .iter()
+            .filter_map(|p| {
+                p.allowed_ips
+                    .iter()
+                    .filter(|net| net.contains(&dst))
+                    .map(|net| net.prefix_len())
+                    .max()
+                    .map(|len| (len, p))
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, p)| p.clone())
+    }
+
+    /// 网络侧：解密并写入设备
+    async fn network_loop(&self, index: usize) {
+        let transport = self.transports[index].clone();
+        let mut buf = vec![0u8; MAX_PACKET];
+        let mut out = vec![0u8; MAX_PACKET];
+
+        loop {
+            let (len, from) = match transport.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return,
+                Err(e) => {
+                    warn!("Failed to receive datagram: {}", e);
+                    continue;
+                }
+            };
+
+            let Some((datagram, obfuscator)) = self.reveal(&buf[..len], from) else {
+                debug!("Dropping unrecognised datagram from {}", from);
+                continue;
+            };
+            let (peer, target) = match self.peer_for_datagram(&datagram, from.ip(), &mut out) {
+                Lookup::Peer(peer, target) => (peer, target),
+                Lookup::Cookie(reply) => {
+                    debug!("Under load, sending cookie reply to {}", from);
+                    send(transport.as_ref(), &reply, from, obfuscator.as_deref()).await;
+                    continue;
+                }
+                Lookup::Drop => {
+                    debug!("Dropping unrecognised datagram from {}", from);
+                    continue;
+                }
+            };
+
+            let mut to_network = Vec::new();
+            let mut to_device = None;
+            {
+                let mut sessions = peer.sessions.lock().unwrap();
+                let tunn = match target {
+                    Target::Handshake(key) => {
+                        // 对端改用另一个本端密钥时换用新会话，旧会话保留到新会话建立
+                        if sessions.current.local_key.public != key.public {
+                            let psk = sessions.current.psk.clone();
+                            match self.session(peer.public_key, psk, peer.keepalive, key) {
+                                Ok(session) => sessions.replace(session),
+                                Err(e) => {
+                                    warn!("Failed to create session: {}", e);
+                                    continue;
+                                }
+                            }
+                        }
+                        &mut sessions.current.tunn
+                    }
+                    Target::Session(index) => sessions.tunn(index),
+                };
+                let mut result = tunn.decapsulate(Some(from.ip()), &datagram, &mut out);
+                if !matches!(result, TunnResult::Err(_)) {
+                    *peer.endpoint.lock().unwrap() = Some(Endpoint {
+                        addr: from,
+                        transport: index,
+                        obfuscator: obfuscator.clone(),
+                    });
+                }
+                loop {
+                    match result {
+                        TunnResult::Done => break,
+                        TunnResult::Err(e) => {
+                            debug!("Failed to decapsulate datagram from {}: {:?}", from, e);
+                            break;
+                        }
+                        TunnResult::WriteToNetwork(packet) => {
+                            to_network.push(packet.to_vec());
+                            // 继续取出握手完成前排队的报文
+                            result = tunn.decapsulate(None, &[], &mut out);
+                        }
+                        TunnResult::WriteToTunnelV4(packet, src) => {
+                            if peer.allows(src.into()) {
+                                to_device = Some(packet.to_vec());
+                            } else {
+                                debug!("Dropping packet from {} outside allowed IPs", src);
+                            }
+                            break;
+                        }
+                        TunnResult::WriteToTunnelV6(packet, src) => {
+                            if peer.allows(src.into()) {
+                                to_device = Some(packet.to_vec());
+                            } else {
+                                debug!("Dropping packet from {} outside allowed IPs", src);
+                            }
+                            break;
+                        }
+                    }
+                }
+            }
+
+            for packet in to_network {
+                send(transport.as_ref(), &packet, from, obfuscator.as_deref()).await;
+            }
+            if let Some(packet) = to_device {
+                if let Err(e) = self.device.send(&packet).await {
+                    warn!("Failed to write packet to {}: {}", self.device.name(), e);
+                }
+            }
+        }
+    }
+
+    /// 设备侧：加密并发往对等体
+    async fn device_loop(&self) {
+        let mut buf = vec![0u8; MAX_PACKET];
+        let mut out = vec![0u8; MAX_PACKET + 148];
+
+        loop {
+            let len = match self.device.recv(&mut buf).await {
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return,
+                Err(e) => {
+                    warn!("Failed to read from {}: {}", self.device.name(), e);
+                    continue;
+                }
+            };
+
+            let Some(dst) = Tunn::dst_address(&buf[..len]) else {
+                continue;
+            };
+            let Some(peer) = self.peer_for_destination(dst) else {
+                debug!("No peer for destination {}", dst);
+                continue;
+            };
+
+            let result = peer
+                .sessions
+                .lock()
+                .unwrap()
+                .outbound()
+                .encapsulate(&buf[..len], &mut out);
+            let packet = match result {
+                TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+                TunnResult::Err(e) => {
+                    debug!("Failed to encapsulate packet for {}: {:?}", dst, e);
+                    continue;
+                }
+                _ => continue,
+            };
+
+            match peer.endpoint() {
+                Some(endpoint) => self.send_to(&packet, &endpoint).await,
+                None => debug!("Peer for {} has no known endpoint", dst),
+            }
+        }
+    }
+
+    /// 驱动握手重传、密钥轮换和保活
+    async fn timer_loop(&self) {
+        let mut out = vec![0u8; MAX_PACKET];
+        let mut interval = tokio::time::interval(TIMER_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let peers = self.peers.read().unwrap().clone();
+            let keys = self.keys.read().unwrap().clone();
+            // 限速器由所有会话共用，boringtun 不再自行清零计数
+            for key in &keys {
+                key.limiter.reset_count();
+            }
+            // 重载会创建新的 LocalKey，按公钥判断会话使用的密钥是否仍被接受
+            let accepted = |local: &LocalKey| {
+                keys.iter()
+                    .any(|key| key.public == local.public && key.accepts())
+            };
+            for peer in peers {
+                let mut packets = Vec::new();
+                {
+                    let mut sessions = peer.sessions.lock().unwrap();
+                    sessions.expire_previous();
+                    if !sessions.retire_rejected(accepted) {
+                        // 旧私钥失效后不再为使用它的会话更新密钥，对端需要用新公钥重新握手
+                        info!(
+                            "Closing session of peer {} that uses an expired private key",
+                            crate::types::PublicKey::from(peer.public_key).short()
+                        );
+                        let psk = sessions.current.psk.clone();
+                        match self.session(peer.public_key, psk, peer.keepalive, keys[0].clone()) {
+                            Ok(session) => sessions.reset(session),
+                            Err(e) => warn!("Failed to create session: {}", e),
+                        }
+                    }
+                    let Sessions {
+                        current, previous, ..
+                    } = &mut *sessions;
+                    for session in std::iter::once(current).chain(previous.as_mut()) {
+                        match session.tunn.update_timers(&mut out) {
+                            TunnResult::WriteToNetwork(packet) => packets.push(packet.to_vec()),
+                            TunnResult::Err(WireGuardError::ConnectionExpired) => {}
+                            TunnResult::Err(e) => debug!("Timer error: {:?}", e),
+                            _ => {}
+                        }
+                    }
+                }
+
+                if let Some(endpoint) = peer.endpoint() {
+                    for packet in packets {
+                        self.send_to(&packet, &endpoint).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// 发送报文，对端使用混淆时按混淆格式发出
+async fn send(
+    transport: &dyn Transport,
+    packet: &[u8],
+    addr: SocketAddr,
+    obfuscator: Option<&Obfuscator>,
+) {
+    let datagrams = match obfuscator {
+        Some(obfuscator) => obfuscator.datagrams(packet),
+        None => vec![packet.to_vec()],
+    };
+    for datagram in datagrams {
+        if let Err(e) = transport.send_to(&datagram, addr).await {
+            warn!("Failed to send datagram to {}: {}", addr, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto;
+    use crate::device::MemoryDevice;
+    use crate::migrate::SERVER_VERSION;
+    use crate::transport::MemoryNetwork;
+    use crate::types;
+
+    fn test_tunnel(
+        private_key: SecretKey,
+        next_private_key: Option<SecretKey>,
+        peers: Vec<PeerConfig>,
+    ) -> Tunnel {
+        let config = ServerConfig {
+            version: SERVER_VERSION,
+            interface: InterfaceConfig {
+                name: "wg0".to_string(),
+                private_key: Some(private_key),
+                next_private_key,
+                address: "10.8.0.1/24".parse().unwrap(),
+                listen_port: 51820,
+                ..Default::default()
+            },
+            nat: None,
+            stream: None,
+            security: None,
+            pq: None,
+            ipam: None,
+            peers_dir: None,
+            peers,
+        };
+        let (device, _) = MemoryDevice::new("wg0");
+        let transport = MemoryNetwork::new()
+            .bind("192.0.2.1:51820".parse().unwrap())
+            .unwrap();
+        Tunnel::new(&config, Arc::new(device), vec![Arc::new(transport)]).unwrap()
+    }
+
+    fn peer(public_key: types::PublicKey, allowed_ips: &[&str]) -> PeerConfig {
+        PeerConfig {
+            public_key,
+            allowed_ips: allowed_ips.iter().map(|ip| ip.parse().unwrap()).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// 客户端发往 `server` 公钥的握手发起报文
+    fn initiation(client: &SecretKey, server: &types::PublicKey) -> Vec<u8> {
+        let mut tunn = Tunn::new(
+            StaticSecret::from(*client.as_bytes()),
+            PublicKey::from(*server.as_bytes()),
+            None,
+            None,
+            0,
+            None,
+        )
+        .unwrap();
+        let mut out = vec![0u8; MAX_PACKET];
+        match tunn.format_handshake_initiation(&mut out, false) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("expected a handshake initiation"),
+        }
+    }
+
+    #[test]
+    fn test_handshake_lookup() {
+        let (server_key, server_pub) = crypto::generate_keypair().unwrap();
+        let (next_key, next_pub) = crypto::generate_keypair().unwrap();
+        let (client_key, client_pub) = crypto::generate_keypair().unwrap();
+        let (stranger_key, stranger_pub) = crypto::generate_keypair().unwrap();
+        let tunnel = test_tunnel(
+            server_key,
+            Some(next_key),
+            vec![peer(client_pub, &["10.8.0.2/32"])],
+        );
+        let from: IpAddr = "198.51.100.7".parse().unwrap();
+        let mut out = vec![0u8; MAX_PACKET];
+
+        // MAC1 选出对端使用的本端密钥
+        let init = initiation(&client_key, &next_pub);
+        match tunnel.peer_for_datagram(&init, from, &mut out) {
+            Lookup::Peer(peer, Target::Handshake(key)) => {
+                assert_eq!(&peer.public_key, client_pub.as_bytes());
+                assert_eq!(key.public.as_bytes(), next_pub.as_bytes());
+            }
+            _ => panic!("expected a handshake for the client"),
+        }
+
+        // 未配置的对等体和发往其他公钥的握手被丢弃
+        let unknown = initiation(&stranger_key, &server_pub);
+        assert!(matches!(
+            tunnel.peer_for_datagram(&unknown, from, &mut out),
+            Lookup::Drop
+        ));
+        let misdirected = initiation(&client_key, &stranger_pub);
+        assert!(matches!(
+            tunnel.peer_for_datagram(&misdirected, from, &mut out),
+            Lookup::Drop
+        ));
+
+        // 超过限速后不再做 DH，回复 cookie
+        let init = initiation(&client_key, &server_pub);
+        let limiter = tunnel.keys.read().unwrap()[0].limiter.clone();
+        for _ in 0..HANDSHAKE_RATE_LIMIT {
+            let _ = limiter.verify_packet(Some(from), &init, &mut out);
+        }
+        match tunnel.peer_for_datagram(&init, from, &mut out) {
+            Lookup::Cookie(reply) => assert!(matches!(
+                Tunn::parse_incoming_packet(&reply),
+                Ok(Packet::PacketCookieReply(_))
+            )),
+            _ => panic!("expected a cookie reply under load"),
+        }
+    }
+
+    #[test]
+    fn test_peer_for_destination() {
+        let (server_key, _) = crypto::generate_keypair().unwrap();
+        let (_, subnet_pub) = crypto::generate_keypair().unwrap();
+        let (_, host_pub) = crypto::generate_keypair().unwrap();
+        let tunnel = test_tunnel(
+            server_key,
+            None,
+            vec![
+                peer(subnet_pub, &["10.8.0.0/24"]),
+                peer(host_pub, &["10.8.0.5/32", "fd00::/64"]),
+            ],
+        );
+
+        let lookup = |dst: &str| {
+            tunnel
+                .peer_for_destination(dst.parse().unwrap())
+                .map(|p| p.public_key)
+        };
+        assert_eq!(lookup("10.8.0.5"), Some(*host_pub.as_bytes()));
+        assert_eq!(lookup("10.8.0.6"), Some(*subnet_pub.as_bytes()));
+        assert_eq!(lookup("fd00::1"), Some(*host_pub.as_bytes()));
+        assert_eq!(lookup("10.9.0.1"), None);
+    }
+
+    #[test]
+    fn test_sessions_follow_accepted_keys() {
+        let (server_key, _) = crypto::generate_keypair().unwrap();
+        let (next_key, _) = crypto::generate_keypair().unwrap();
+        let (_, client_pub) = crypto::generate_keypair().unwrap();
+        let tunnel = test_tunnel(
+            server_key,
+            Some(next_key),
+            vec![peer(client_pub, &["10.8.0.2/32"])],
+        );
+        let keys = tunnel.keys.read().unwrap().clone();
+        let peer = tunnel.peers.read().unwrap()[0].clone();
+        let mut sessions = peer.sessions.lock().unwrap();
+        let session = |key: &Arc<LocalKey>| {
+            tunnel
+                .session(peer.public_key, None, None, key.clone())
+                .unwrap()
+        };
+
+        // 换用新密钥的会话后旧会话保留，直到计时器发现其密钥不再被接受
+        let first = sessions.current.index;
+        sessions.replace(session(&keys[1]));
+        let second = sessions.current.index;
+        assert!(sessions.owns(first) && sessions.owns(second));
+        assert!(sessions.retire_rejected(|key| key.public == keys[1].public));
+        assert!(!sessions.owns(first));
+
+        // 当前会话的密钥失效时换成使用当前私钥的新会话
+        assert!(!sessions.retire_rejected(|key| key.public == keys[0].public));
+        sessions.reset(session(&keys[0]));
+        assert!(!sessions.owns(second));
+        assert_eq!(sessions.current.local_key.public, keys[0].public);
+    }
+}
@@ -0,0 +1,344 @@
+use crate::crypto;
+use crate::error::{Error, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ipnet::IpNet;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// 配置值的解析错误，只说明原因，位置由调用方补充
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError(String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<Error> for ParseError {
+    fn from(error: Error) -> Self {
+        ParseError(error.detail())
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(error: ParseError) -> Self {
+        Error::ConfigError(error.0)
+    }
+}
+
+/// 以字符串形式序列化
+fn serialize_display<T: fmt::Display, S: Serializer>(
+    value: &T,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+/// 从字符串反序列化，解析失败时报告原因
+fn deserialize_parsed<'de, T, D>(deserializer: D) -> std::result::Result<T, D::Error>
+where
+    T: FromStr<Err = ParseError>,
+    D: Deserializer<'de>,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(de::Error::custom)
+}
+
+/// 解析逗号分隔的 CIDR 列表，忽略空项
+pub fn parse_networks(value: &str) -> std::result::Result<Vec<IpNet>, ParseError> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            entry
+                .parse::<IpNet>()
+                .map_err(|e| ParseError(format!("Invalid CIDR {:?}: {}", entry, e)))
+        })
+        .collect()
+}
+
+/// 将 CIDR 列表格式化为逗号分隔的文本
+pub fn join_networks(networks: &[IpNet]) -> String {
+    networks
+        .iter()
+        .map(IpNet::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// 对等体公钥（32 字节），配置中为 Base64
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PublicKey([u8; 32]);
+
+impl PublicKey {
+    /// 返回公钥字节
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Base64 的前 8 个字符，用于日志
+    pub fn short(&self) -> String {
+        let mut encoded = self.to_string();
+        encoded.truncate(8);
+        encoded
+    }
+}
+
+impl From<[u8; 32]> for PublicKey {
+    fn from(bytes: [u8; 32]) -> Self {
+        PublicKey(bytes)
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(PublicKey(crypto::decode_public_key(s)?))
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&STANDARD.encode(self.0))
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PublicKey({})", self)
+    }
+}
+
+impl Serialize for PublicKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serialize_display(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for PublicKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserialize_parsed(deserializer)
+    }
+}
+
+/// 对等体端点：IP 地址加端口，或主机名加端口（加载配置时解析）
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    /// IP 地址和端口，IPv6 写作 `[addr]:port`
+    Addr(SocketAddr),
+    /// 主机名和端口
+    Host(String, u16),
+}
+
+impl Endpoint {
+    /// 解析为套接字地址，主机名通过异步 DNS 查询，取第一个结果
+    pub async fn resolve(&self) -> Result<SocketAddr> {
+        match self {
+            Endpoint::Addr(addr) => Ok(*addr),
+            Endpoint::Host(host, port) => tokio::net::lookup_host((host.as_str(), *port))
+                .await
+                .map_err(|e| {
+                    Error::NetworkError(format!("Cannot resolve endpoint {}: {}", self, e))
+                })?
+                .next()
+                .ok_or_else(|| Error::NetworkError(format!("Endpoint {} has no addresses", self))),
+        }
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(Endpoint::Addr(addr));
+        }
+        let invalid = |reason: &str| ParseError(format!("Invalid endpoint {:?}: {}", s, reason));
+
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| invalid("expected host:port"))?;
+        let port = port
+            .parse::<u16>()
+            .map_err(|_| invalid("the port must be a number from 0 to 65535"))?;
+        if host.starts_with('[') || host.contains(':') {
+            return Err(invalid("expected [IPv6 address]:port"));
+        }
+        let labels: Vec<&str> = host.split('.').collect();
+        if labels
+            .iter()
+            .all(|l| !l.is_empty() && l.bytes().all(|b| b.is_ascii_digit()))
+        {
+            return Err(invalid("invalid IPv4 address"));
+        }
+        let valid_label = |label: &&str| {
+            (1..=63).contains(&label.len())
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        };
+        if host.len() > 253 || !labels.iter().all(valid_label) {
+            return Err(invalid("invalid host name"));
+        }
+        Ok(Endpoint::Host(host.to_string(), port))
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Addr(addr) => write!(f, "{}", addr),
+            Endpoint::Host(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+impl Serialize for Endpoint {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serialize_display(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Endpoint {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserialize_parsed(deserializer)
+    }
+}
+
+/// 监听地址：IP 地址加端口，或只写 IP 地址（使用接口的 `listen_port`）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenAddr {
+    /// 只指定地址
+    Ip(IpAddr),
+    /// 指定地址和端口
+    Socket(SocketAddr),
+}
+
+impl ListenAddr {
+    /// 未指定端口时使用 `port`
+    pub fn with_default_port(self, port: u16) -> SocketAddr {
+        match self {
+            ListenAddr::Ip(ip) => SocketAddr::new(ip, port),
+            ListenAddr::Socket(addr) => addr,
+        }
+    }
+}
+
+impl FromStr for ListenAddr {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        s.parse::<SocketAddr>()
+            .map(ListenAddr::Socket)
+            .or_else(|_| s.parse::<IpAddr>().map(ListenAddr::Ip))
+            .map_err(|_| {
+                ParseError(format!(
+                    "Invalid listen address {:?}, expected ip or ip:port",
+                    s
+                ))
+            })
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Ip(ip) => write!(f, "{}", ip),
+            ListenAddr::Socket(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+impl Serialize for ListenAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serialize_display(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ListenAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserialize_parsed(deserializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_endpoint_parsing() {
+        let parse = |s: &str| s.parse::<Endpoint>().map_err(|e| e.to_string());
+        assert_eq!(
+            parse("192.0.2.1:51820"),
+            Ok(Endpoint::Addr("192.0.2.1:51820".parse().unwrap()))
+        );
+        assert_eq!(
+            parse("[2001:db8::1]:443").unwrap().to_string(),
+            "[2001:db8::1]:443"
+        );
+        assert_eq!(
+            parse("vpn.example.com:51820"),
+            Ok(Endpoint::Host("vpn.example.com".to_string(), 51820))
+        );
+        assert!(parse("vpn.example.com")
+            .unwrap_err()
+            .contains("expected host:port"));
+        assert!(parse("vpn.example.com:http")
+            .unwrap_err()
+            .contains("port must be"));
+        assert!(parse("2001:db8::1:443")
+            .unwrap_err()
+            .contains("[IPv6 address]:port"));
+        assert!(parse("10.8.0.300:51820")
+            .unwrap_err()
+            .contains("invalid IPv4 address"));
+        assert!(parse("vpn_example.com:1")
+            .unwrap_err()
+            .contains("invalid host name"));
+
+        let localhost = Endpoint::Host("localhost".to_string(), 51820);
+        assert_eq!(localhost.resolve().await.unwrap().port(), 51820);
+    }
+
+    #[test]
+    fn test_key_and_listen_serialization() {
+        #[derive(Debug, Serialize, Deserialize)]
+        struct Wrapper {
+            key: PublicKey,
+            listen: Vec<ListenAddr>,
+        }
+
+        let key = PublicKey::from([9u8; 32]);
+        let content = format!(
+            "key = \"{}\"\nlisten = [\"203.0.113.5\", \"[2001:db8::1]:443\"]",
+            key
+        );
+        let wrapper: Wrapper = toml::from_str(&content).unwrap();
+        assert_eq!(wrapper.key, key);
+        assert_eq!(
+            wrapper.listen[0].with_default_port(51820),
+            "203.0.113.5:51820".parse().unwrap()
+        );
+        assert_eq!(
+            wrapper.listen[1].with_default_port(51820),
+            "[2001:db8::1]:443".parse().unwrap()
+        );
+        assert_eq!(toml::to_string(&wrapper).unwrap().trim(), content);
+
+        let err = toml::from_str::<Wrapper>("key = \"AAAA\"\nlisten = []").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Public key must be 32 bytes, got 3"));
+        assert_eq!(err.span(), Some(6..12));
+    }
+}
@@ -0,0 +1,245 @@
+use crate::error::{Error, Result};
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use log::{debug, info};
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+
+/// 最后一次变更后等待的时间，合并编辑器和部署工具的连续写入
+pub const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// 关心的事件：写入完成、重命名进出和删除
+fn watch_mask() -> WatchMask {
+    WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::MOVED_FROM | WatchMask::DELETE
+}
+
+/// 配置文件和 `peers_dir` 的变更监视器
+///
+/// 监视主配置文件所在的目录而不是文件本身，原子替换（写临时文件后重命名）也能被发现；
+/// 只有主配置文件和 `peers_dir` 中 `*.toml` 文件的变更会触发重载。
+pub struct ConfigWatcher {
+    /// inotify 实例
+    inotify: AsyncFd<Inotify>,
+    /// 主配置文件的文件名
+    config_name: OsString,
+    /// 主配置文件所在目录的监视
+    config_watch: WatchDescriptor,
+    /// 对等体目录及其监视（可选）
+    peers_watch: Option<(PathBuf, WatchDescriptor)>,
+    /// 合并变更的等待时间
+    debounce: Duration,
+}
+
+impl ConfigWatcher {
+    /// 开始监视配置文件和对等体目录
+    pub fn new(config_path: &Path, peers_dir: Option<&Path>, debounce: Duration) -> Result<Self> {
+        let config_name = config_path
+            .file_name()
+            .ok_or_else(|| {
+                Error::ConfigError(format!("Invalid configuration path {:?}", config_path))
+            })?
+            .to_os_string();
+        let config_dir = match config_path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+
+        let inotify = Inotify::init()?;
+        let config_watch = inotify
+            .watches()
+            .add(config_dir, watch_mask())
+            .map_err(|e| Error::ConfigError(format!("Failed to watch {:?}: {}", config_dir, e)))?;
+        info!("Watching {:?} for configuration changes", config_path);
+
+        // SAFETY: inotify 实例由 AsyncFd 独占，描述符在其释放前保持有效。
+        let inotify = unsafe { AsyncFd::register(inotify) }
+            .map_err(|e| Error::ConfigError(format!("Failed to register inotify: {}", e)))?;
+        let mut watcher = ConfigWatcher {
+            inotify,
+            config_name,
+            config_watch,
+            peers_watch: None,
+            debounce,
+        };
+        watcher.watch_peers_dir(peers_dir)?;
+        Ok(watcher)
+    }
+
+    /// 切换监视的对等体目录，重载后 `peers_dir` 可能已改变
+    pub fn watch_peers_dir(&mut self, dir: Option<&Path>) -> Result<()> {
+        if self.peers_watch.as_ref().map(|(path, _)| path.as_path()) == dir {
+            return Ok(());
+        }
+
+        let mut watches = self.inotify.get_ref().watches();
+        if let Some((path, wd)) = self.peers_watch.take() {
+            // 与配置文件同一目录时共用一个监视，不能移除
+            if wd != self.config_watch {
+                if let Err(e) = watches.remove(wd) {
+                    debug!("Failed to remove watch for {:?}: {}", path, e);
+                }
+            }
+        }
+
+        if let Some(dir) = dir {
+            let wd = watches
+                .add(dir, watch_mask())
+                .map_err(|e| Error::ConfigError(format!("Failed to watch {:?}: {}", dir, e)))?;
+            info!("Watching {:?} for peer changes", dir);
+            self.peers_watch = Some((dir.to_path_buf(), wd));
+        }
+        Ok(())
+    }
+
+    /// 等待配置发生变更，并在 `debounce` 时间内没有新的变更后返回
+    pub async fn changed(&mut self) -> Result<()> {
+        while !self.next().await? {}
+
+        loop {
+            match tokio::time::timeout(self.debounce, self.next()).await {
+                Ok(result) => {
+                    result?;
+                }
+                Err(_) => return Ok(()),
+            }
+        }
+    }
+
+    /// 读取一批事件，返回其中是否有需要重载的变更
+    async fn next(&mut self) -> Result<bool> {
+        let mut buffer = [0u8; 4096];
+        loop {
+            let mut guard = self.inotify.readable_mut().await?;
+            let events = match guard.try_io(|inotify| {
+                let events = inotify.get_mut().read_events(&mut buffer)?;
+                Ok(events
+                    .map(|event| (event.wd, event.mask, event.name.map(OsStr::to_os_string)))
+                    .collect::<Vec<_>>())
+            }) {
+                Ok(events) => events?,
+                Err(_) => continue,
+            };
+            drop(guard);
+
+            return Ok(events
+                .iter()
+                .any(|(wd, mask, name)| self.is_relevant(wd, *mask, name.as_deref())));
+        }
+    }
+
+    /// 事件是否涉及主配置文件或对等体文件
+    fn is_relevant(&self, wd: &WatchDescriptor, mask: EventMask, name: Option<&OsStr>) -> bool {
+        // 事件队列溢出时无法知道丢失了什么，按有变更处理
+        if mask.contains(EventMask::Q_OVERFLOW) {
+            return true;
+        }
+        let Some(name) = name else {
+            return false;
+        };
+
+        if *wd == self.config_watch && name == self.config_name {
+            return true;
+        }
+        match &self.peers_watch {
+            Some((_, peers)) if wd == peers => {
+                let path = Path::new(name);
+                !name.to_string_lossy().starts_with('.')
+                    && path.extension().is_some_and(|ext| ext == "toml")
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "rusty-tunnel-watch-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("peers.d")).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_config_changes_debounced() {
+        let dir = temp_dir("config");
+        let config_path = dir.join("server.toml");
+        fs::write(&config_path, "").unwrap();
+        let mut watcher =
+            ConfigWatcher::new(&config_path, None, Duration::from_millis(100)).unwrap();
+
+        // 无关文件不触发重载
+        fs::write(dir.join("notes.txt"), "x").unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(300), watcher.changed())
+                .await
+                .is_err()
+        );
+
+        // 多次写入和原子替换合并为一次变更
+        fs::write(&config_path, "a").unwrap();
+        fs::write(&config_path, "b").unwrap();
+        fs::write(dir.join(".server.toml.tmp"), "c").unwrap();
+        fs::rename(dir.join(".server.toml.tmp"), &config_path).unwrap();
+        tokio::time::timeout(TIMEOUT, watcher.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(300), watcher.changed())
+                .await
+                .is_err()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_peer_files_watched() {
+        let dir = temp_dir("peers");
+        let config_path = dir.join("server.toml");
+        let peers_dir = dir.join("peers.d");
+        let mut watcher =
+            ConfigWatcher::new(&config_path, Some(&peers_dir), Duration::from_millis(50)).unwrap();
+
+        fs::write(peers_dir.join(".hidden.toml"), "").unwrap();
+        fs::write(peers_dir.join("README"), "").unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(300), watcher.changed())
+                .await
+                .is_err()
+        );
+
+        fs::write(peers_dir.join("alice.toml"), "").unwrap();
+        tokio::time::timeout(TIMEOUT, watcher.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        fs::remove_file(peers_dir.join("alice.toml")).unwrap();
+        tokio::time::timeout(TIMEOUT, watcher.changed())
+            .await
+            .unwrap()
+            .unwrap();
+
+        // 不再监视旧目录
+        watcher.watch_peers_dir(None).unwrap();
+        fs::write(peers_dir.join("bob.toml"), "").unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(300), watcher.changed())
+                .await
+                .is_err()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,603 @@
+use crate::config::{Backend, ClientConfig, InterfaceConfig, PeerConfig, ServerConfig};
+use crate::error::{Error, Result};
+use crate::migrate::{CLIENT_VERSION, SERVER_VERSION};
+use crate::secret::SecretKey;
+use crate::types::{self, Endpoint, PublicKey};
+use ipnet::IpNet;
+use std::fmt::{self, Write};
+use std::str::FromStr;
+
+/// wg-quick 配置未指定 ListenPort 时使用的端口
+const DEFAULT_LISTEN_PORT: u16 = 51820;
+
+/// 客户端未限制路由范围时导出的 AllowedIPs
+const ALL_TRAFFIC: &str = "0.0.0.0/0, ::/0";
+
+/// 导入 PostUp/PreDown 时的警告
+const HOOKS_NOT_RUN: &str =
+    "PostUp/PreDown are kept for export only; rusty-tunnel does not run them";
+
+/// wg-quick 配置的 `[Interface]` 段
+#[derive(Debug, Default)]
+struct WgInterface {
+    private_key: Option<SecretKey>,
+    addresses: Vec<IpNet>,
+    listen_port: Option<u16>,
+    dns: Vec<String>,
+    mtu: Option<u16>,
+    post_up: Vec<String>,
+    pre_down: Vec<String>,
+}
+
+/// wg-quick 配置的 `[Peer]` 段
+#[derive(Debug, Default)]
+struct WgPeer {
+    public_key: Option<PublicKey>,
+    preshared_key: Option<SecretKey>,
+    allowed_ips: Vec<IpNet>,
+    endpoint: Option<Endpoint>,
+    persistent_keepalive: Option<u16>,
+}
+
+/// 解析后的 wg-quick 配置
+#[derive(Debug, Default)]
+struct WgQuickConfig {
+    interface: WgInterface,
+    peers: Vec<WgPeer>,
+}
+
+/// 当前所在的段
+enum Section {
+    None,
+    Interface,
+    Peer,
+    Unknown,
+}
+
+/// 解析 wg-quick 配置，不支持的字段记入 `warnings` 后忽略
+fn parse(content: &str, warnings: &mut Vec<String>) -> Result<WgQuickConfig> {
+    let mut config = WgQuickConfig::default();
+    let mut section = Section::None;
+
+    for (number, line) in content.lines().enumerate() {
+        let number = number + 1;
+        // 与 wg-quick 相同，`#` 之后的内容都是注释
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if line.starts_with('[') && line.ends_with(']') {
+            let name = &line[1..line.len() - 1];
+            section = match name.to_ascii_lowercase().as_str() {
+                "interface" => Section::Interface,
+                "peer" => {
+                    config.peers.push(WgPeer::default());
+                    Section::Peer
+                }
+                _ => {
+                    warnings.push(format!(
+                        "Line {}: unsupported section [{}] is ignored",
+                        number, name
+                    ));
+                    Section::Unknown
+                }
+            };
+            continue;
+        }
+
+        let (key, value) = line.split_once('=').ok_or_else(|| {
+            Error::ConfigError(format!("Line {}: expected `Key = Value`", number))
+        })?;
+        let (key, value) = (key.trim(), value.trim());
+        let lower = key.to_ascii_lowercase();
+
+        match section {
+            Section::None => {
+                return Err(Error::ConfigError(format!(
+                    "Line {}: {} appears before any section",
+                    number, key
+                )))
+            }
+            Section::Unknown => {}
+            Section::Interface => {
+                let interface = &mut config.interface;
+                match lower.as_str() {
+                    "privatekey" => interface.private_key = Some(parse_value(number, key, value)?),
+                    "address" => interface.addresses.extend(parse_list(number, key, value)?),
+                    "listenport" => interface.listen_port = Some(parse_value(number, key, value)?),
+                    "dns" => interface.dns.extend(split_list(value)),
+                    "mtu" => interface.mtu = Some(parse_value(number, key, value)?),
+                    "postup" => interface.post_up.push(value.to_string()),
+                    "predown" => interface.pre_down.push(value.to_string()),
+                    _ => warnings.push(format!(
+                        "Line {}: unsupported [Interface] field {} is ignored",
+                        number, key
+                    )),
+                }
+            }
+            Section::Peer => {
+                let peer = config.peers.last_mut().expect("peer section was pushed");
+                match lower.as_str() {
+                    "publickey" => peer.public_key = Some(parse_value(number, key, value)?),
+                    "presharedkey" => peer.preshared_key = Some(parse_value(number, key, value)?),
+                    "allowedips" => peer.allowed_ips.extend(parse_list(number, key, value)?),
+                    "endpoint" => peer.endpoint = Some(parse_value(number, key, value)?),
+                    "persistentkeepalive" => {
+                        peer.persistent_keepalive = match value {
+                            "off" => None,
+                            _ => Some(parse_value(number, key, value)?).filter(|&v: &u16| v != 0),
+                        }
+                    }
+                    _ => warnings.push(format!(
+                        "Line {}: unsupported [Peer] field {} is ignored",
+                        number, key
+                    )),
+                }
+            }
+        }
+    }
+
+    Ok(config)
+}
+
+/// 生成 wg-quick 配置文本
+fn render(config: &WgQuickConfig) -> String {
+    let mut out = String::from("[Interface]\n");
+    let interface = &config.interface;
+    if let Some(key) = &interface.private_key {
+        let _ = writeln!(out, "PrivateKey = {}", key.to_base64().expose());
+    }
+    if !interface.addresses.is_empty() {
+        let _ = writeln!(
+            out,
+            "Address = {}",
+            types::join_networks(&interface.addresses)
+        );
+    }
+    if let Some(port) = interface.listen_port {
+        let _ = writeln!(out, "ListenPort = {}", port);
+    }
+    if !interface.dns.is_empty() {
+        let _ = writeln!(out, "DNS = {}", interface.dns.join(", "));
+    }
+    if let Some(mtu) = interface.mtu {
+        let _ = writeln!(out, "MTU = {}", mtu);
+    }
+    for command in &interface.post_up {
+        let _ = writeln!(out, "PostUp = {}", command);
+    }
+    for command in &interface.pre_down {
+        let _ = writeln!(out, "PreDown = {}", command);
+    }
+
+    for peer in &config.peers {
+        out.push_str("\n[Peer]\n");
+        if let Some(key) = &peer.public_key {
+            let _ = writeln!(out, "PublicKey = {}", key);
+        }
+        if let Some(psk) = &peer.preshared_key {
+            let _ = writeln!(out, "PresharedKey = {}", psk.to_base64().expose());
+        }
+        if !peer.allowed_ips.is_empty() {
+            let _ = writeln!(
+                out,
+                "AllowedIPs = {}",
+                types::join_networks(&peer.allowed_ips)
+            );
+        }
+        if let Some(endpoint) = &peer.endpoint {
+            let _ = writeln!(out, "Endpoint = {}", endpoint);
+        }
+        if let Some(keepalive) = peer.persistent_keepalive {
+            let _ = writeln!(out, "PersistentKeepalive = {}", keepalive);
+        }
+    }
+    out
+}
+
+fn split_list(value: &str) -> impl Iterator<Item = String> + '_ {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+}
+
+/// 解析单个值，错误信息不包含原值，以免泄露密钥
+fn parse_value<T>(number: usize, key: &str, value: &str) -> Result<T>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| Error::ConfigError(format!("Line {}: invalid {}: {}", number, key, e)))
+}
+
+fn parse_list(number: usize, key: &str, value: &str) -> Result<Vec<IpNet>> {
+    types::parse_networks(value)
+        .map_err(|e| Error::ConfigError(format!("Line {}: invalid {}: {}", number, key, e)))
+}
+
+/// 取第一个地址，多余的地址记入警告
+fn single_address(interface: &WgInterface, warnings: &mut Vec<String>) -> Result<IpNet> {
+    let (first, rest) = interface
+        .addresses
+        .split_first()
+        .ok_or_else(|| Error::ConfigError("[Interface] Address is required".to_string()))?;
+    if !rest.is_empty() {
+        warnings.push(format!(
+            "Only one interface address is supported; {} is ignored",
+            types::join_networks(rest)
+        ));
+    }
+    Ok(*first)
+}
+
+fn required_private_key(interface: &WgInterface) -> Result<SecretKey> {
+    interface
+        .private_key
+        .clone()
+        .ok_or_else(|| Error::ConfigError("[Interface] PrivateKey is required".to_string()))
+}
+
+/// 将 wg-quick 配置导入为服务器配置，`name` 为接口名（通常取自文件名）
+pub fn import_server(content: &str, name: &str) -> Result<(ServerConfig, Vec<String>)> {
+    let mut warnings = Vec::new();
+    let wg = parse(content, &mut warnings)?;
+    let interface = &wg.interface;
+
+    let address = single_address(interface, &mut warnings)?;
+    let listen_port = interface.listen_port.unwrap_or_else(|| {
+        warnings.push(format!(
+            "ListenPort is not set; using {}",
+            DEFAULT_LISTEN_PORT
+        ));
+        DEFAULT_LISTEN_PORT
+    });
+    if !interface.dns.is_empty() {
+        warnings.push("DNS is not supported in server configurations and is ignored".to_string());
+    }
+    if !interface.post_up.is_empty() || !interface.pre_down.is_empty() {
+        warnings.push(HOOKS_NOT_RUN.to_string());
+    }
+
+    let mut peers = Vec::with_capacity(wg.peers.len());
+    for (index, peer) in wg.peers.iter().enumerate() {
+        let public_key = peer
+            .public_key
+            .ok_or_else(|| Error::ConfigError(format!("[Peer] #{} has no PublicKey", index + 1)))?;
+        if peer.allowed_ips.is_empty() {
+            return Err(Error::ConfigError(format!(
+                "[Peer] {} has no AllowedIPs",
+                public_key
+            )));
+        }
+        peers.push(PeerConfig {
+            name: None,
+            public_key,
+            allowed_ips: peer.allowed_ips.clone(),
+            endpoint: peer.endpoint.clone(),
+            persistent_keepalive: peer.persistent_keepalive,
+            psk: peer.preshared_key.clone(),
+            ..Default::default()
+        });
+    }
+
+    let config = ServerConfig {
+        version: SERVER_VERSION,
+        interface: InterfaceConfig {
+            name: name.to_string(),
+            private_key: Some(required_private_key(interface)?),
+            private_key_file: None,
+            private_key_env: None,
+            private_key_credential: None,
+            next_private_key: None,
+            next_private_key_file: None,
+            next_private_key_env: None,
+            next_private_key_credential: None,
+            previous_private_key: None,
+            previous_key_expires: None,
+            key_overlap: None,
+            address,
+            listen_port,
+            listen: vec![],
+            backend: Backend::default(),
+            netns: None,
+            obfuscation: None,
+            mtu: interface.mtu,
+            post_up: interface.post_up.clone(),
+            pre_down: interface.pre_down.clone(),
+        },
+        nat: None,
+        stream: None,
+        security: None,
+        pq: None,
+        ipam: None,
+        peers_dir: None,
+        peers,
+    };
+    Ok((config, warnings))
+}
+
+/// 将只有一个 `[Peer]` 的 wg-quick 配置导入为客户端配置
+pub fn import_client(content: &str) -> Result<(ClientConfig, Vec<String>)> {
+    let mut warnings = Vec::new();
+    let wg = parse(content, &mut warnings)?;
+    let interface = &wg.interface;
+
+    let [peer] = wg.peers.as_slice() else {
+        return Err(Error::ConfigError(format!(
+            "A client configuration needs exactly one [Peer], found {}",
+            wg.peers.len()
+        )));
+    };
+    if interface.listen_port.is_some() {
+        warnings.push(
+            "ListenPort is not supported in client configurations and is ignored".to_string(),
+        );
+    }
+    if interface.addresses.is_empty() {
+        return Err(Error::ConfigError(
+            "[Interface] Address is required".to_string(),
+        ));
+    }
+    if !interface.post_up.is_empty() || !interface.pre_down.is_empty() {
+        warnings.push(HOOKS_NOT_RUN.to_string());
+    }
+
+    let config = ClientConfig {
+        version: CLIENT_VERSION,
+        private_key: Some(required_private_key(interface)?),
+        private_key_file: None,
+        private_key_env: None,
+        private_key_credential: None,
+        address: interface.addresses.clone(),
+        server_public_key: peer
+            .public_key
+            .ok_or_else(|| Error::ConfigError("[Peer] has no PublicKey".to_string()))?,
+        server_endpoint: peer
+            .endpoint
+            .clone()
+            .ok_or_else(|| Error::ConfigError("[Peer] has no Endpoint".to_string()))?,
+        allowed_ips: peer.allowed_ips.clone(),
+        persistent_keepalive: peer.persistent_keepalive,
+        psk: peer.preshared_key.clone(),
+        psk_file: None,
+        psk_env: None,
+        psk_credential: None,
+        dns: (!interface.dns.is_empty()).then(|| interface.dns.clone()),
+        mtu: interface.mtu,
+        post_up: interface.post_up.clone(),
+        pre_down: interface.pre_down.clone(),
+    };
+    Ok((config, warnings))
+}
+
+/// 将服务器配置导出为 wg-quick 配置，无法表示的设置记入警告
+pub fn export_server(config: &ServerConfig) -> (String, Vec<String>) {
+    let mut warnings = Vec::new();
+    let interface = &config.interface;
+    let mut unsupported = |set: bool, name: &str| {
+        if set {
+            warnings.push(format!(
+                "{} has no wg-quick equivalent and is not exported",
+                name
+            ));
+        }
+    };
+    unsupported(!interface.listen.is_empty(), "interface.listen");
+    unsupported(interface.netns.is_some(), "interface.netns");
+    unsupported(interface.obfuscation.is_some(), "interface.obfuscation");
+    unsupported(
+        interface.next_private_key.is_some(),
+        "interface.next_private_key",
+    );
+    unsupported(
+        interface.previous_private_key.is_some(),
+        "interface.previous_private_key",
+    );
+    unsupported(config.nat.is_some(), "[nat]");
+    unsupported(config.stream.is_some(), "[stream]");
+    unsupported(config.security.is_some(), "[security]");
+    unsupported(config.pq.is_some(), "[pq]");
+    for peer in &config.peers {
+        unsupported(
+            peer.obfuscation.is_some(),
+            &format!("obfuscation for peer {}", peer.public_key),
+        );
+        unsupported(
+            peer.pq_endpoint.is_some(),
+            &format!("pq_endpoint for peer {}", peer.public_key),
+        );
+    }
+    if interface.has_external_private_key() {
+        warnings
+            .push("private_key comes from an external source and is exported inline".to_string());
+    }
+    for peer in config.peers.iter().filter(|peer| peer.has_external_psk()) {
+        warnings.push(format!(
+            "psk for peer {} comes from an external source and is exported inline",
+            peer.public_key
+        ));
+    }
+
+    let wg = WgQuickConfig {
+        interface: WgInterface {
+            private_key: interface.private_key.clone(),
+            addresses: vec![interface.address],
+            listen_port: Some(interface.listen_port),
+            dns: vec![],
+            mtu: interface.mtu,
+            post_up: interface.post_up.clone(),
+            pre_down: interface.pre_down.clone(),
+        },
+        peers: config
+            .peers
+            .iter()
+            .map(|peer| WgPeer {
+                public_key: Some(peer.public_key),
+                preshared_key: peer.psk.clone(),
+                allowed_ips: peer.allowed_ips.clone(),
+                endpoint: peer.endpoint.clone(),
+                persistent_keepalive: peer.persistent_keepalive,
+            })
+            .collect(),
+    };
+    (render(&wg), warnings)
+}
+
+/// 将客户端配置导出为 wg-quick 配置
+pub fn export_client(config: &ClientConfig) -> (String, Vec<String>) {
+    let mut warnings = Vec::new();
+    if config.private_key_file.is_some()
+        || config.private_key_env.is_some()
+        || config.private_key_credential.is_some()
+    {
+        warnings
+            .push("private_key comes from an external source and is exported inline".to_string());
+    }
+    if config.psk_file.is_some() || config.psk_env.is_some() || config.psk_credential.is_some() {
+        warnings.push("psk comes from an external source and is exported inline".to_string());
+    }
+
+    let wg = WgQuickConfig {
+        interface: WgInterface {
+            private_key: config.private_key.clone(),
+            addresses: config.address.clone(),
+            listen_port: None,
+            dns: config.dns.clone().unwrap_or_default(),
+            mtu: config.mtu,
+            post_up: config.post_up.clone(),
+            pre_down: config.pre_down.clone(),
+        },
+        peers: vec![WgPeer {
+            public_key: Some(config.server_public_key),
+            preshared_key: config.psk.clone(),
+            allowed_ips: if config.allowed_ips.is_empty() {
+                types::parse_networks(ALL_TRAFFIC).expect("ALL_TRAFFIC is a valid list")
+            } else {
+                config.allowed_ips.clone()
+            },
+            endpoint: Some(config.server_endpoint.clone()),
+            persistent_keepalive: config.persistent_keepalive,
+        }],
+    };
+    (render(&wg), warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER: &str = "[Interface]
+PrivateKey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=
+Address = 10.8.0.1/24
+ListenPort = 51820
+MTU = 1420
+PostUp = iptables -A FORWARD -i %i -j ACCEPT
+PostUp = iptables -t nat -A POSTROUTING -o eth0 -j MASQUERADE
+PreDown = iptables -D FORWARD -i %i -j ACCEPT
+
+[Peer]
+PublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=
+PresharedKey = FpCyhws9cxwWoV4xELtfJvjJN+zQVRPISllRWgeopVE=
+AllowedIPs = 10.8.0.2/32, fd00:8::2/128
+Endpoint = 192.0.2.10:51820
+PersistentKeepalive = 25
+
+[Peer]
+PublicKey = TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=
+AllowedIPs = 10.8.0.3/32
+";
+
+    #[test]
+    fn test_server_round_trip() {
+        let (config, warnings) = import_server(SERVER, "wg0").unwrap();
+        assert_eq!(warnings, vec![HOOKS_NOT_RUN.to_string()]);
+        assert_eq!(config.interface.mtu, Some(1420));
+        assert_eq!(config.interface.post_up.len(), 2);
+        assert_eq!(config.peers[0].persistent_keepalive, Some(25));
+        assert_eq!(
+            types::join_networks(&config.peers[0].allowed_ips),
+            "10.8.0.2/32, fd00:8::2/128"
+        );
+        assert!(config.peers[1].psk.is_none());
+
+        // 经 TOML 往返后导出的内容与原文件一致
+        let toml = toml::to_string(&config).unwrap();
+        let config: ServerConfig = toml::from_str(&toml).unwrap();
+        let (exported, warnings) = export_server(&config);
+        assert!(warnings.is_empty());
+        assert_eq!(exported, SERVER);
+
+        // 来自外部来源的预共享密钥内联导出时给出警告
+        let mut external = config.clone();
+        external.peers[0].psk_file = Some("/run/secrets/psk".to_string());
+        let (_, warnings) = export_server(&external);
+        assert_eq!(warnings.len(), 1);
+        assert!(
+            warnings[0].contains("psk for peer xTIBA5"),
+            "{:?}",
+            warnings
+        );
+
+        // 不支持的字段产生警告
+        let content = SERVER.replace("MTU = 1420", "MTU = 1420\nTable = off\nFwMark = 0x1");
+        let (_, warnings) = import_server(&content, "wg0").unwrap();
+        assert_eq!(warnings.len(), 3);
+        assert!(warnings[0].contains("Line 6: unsupported [Interface] field Table"));
+
+        assert!(import_server("PrivateKey = x", "wg0").is_err());
+        assert!(import_server(
+            &SERVER.replace("ListenPort = 51820", "ListenPort = high"),
+            "wg0"
+        )
+        .unwrap_err()
+        .to_string()
+        .contains("Line 4: invalid ListenPort"));
+
+        // 无效的密钥报告行号，但不回显内容
+        let err = import_server(
+            &SERVER.replace("PresharedKey = Fp", "PresharedKey = "),
+            "wg0",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("Line 12: invalid PresharedKey"));
+        assert!(!err.contains("Cyhws9"));
+        assert!(
+            import_server(&SERVER.replace("10.8.0.3/32", "10.8.0.3/33"), "wg0")
+                .unwrap_err()
+                .to_string()
+                .contains("Line 19: invalid AllowedIPs: Invalid CIDR \"10.8.0.3/33\"")
+        );
+    }
+
+    #[test]
+    fn test_client_round_trip() {
+        let content = "[Interface]
+PrivateKey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=
+Address = 10.8.0.2/24
+DNS = 1.1.1.1, 1.0.0.1
+
+[Peer]
+PublicKey = HIgo9xNzJMWLKASShiTqIybxZ0U3wGLiUeJ1PKf8ykw=
+AllowedIPs = 0.0.0.0/0, ::/0
+Endpoint = vpn.example.com:51820
+PersistentKeepalive = 25
+";
+        let (config, warnings) = import_client(content).unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(config.server_endpoint.to_string(), "vpn.example.com:51820");
+        assert_eq!(
+            config.dns,
+            Some(vec!["1.1.1.1".to_string(), "1.0.0.1".to_string()])
+        );
+        assert_eq!(export_client(&config).0, content);
+
+        let two_peers = format!("{}\n[Peer]\nPublicKey = x\n", content);
+        assert!(import_client(&two_peers).is_err());
+    }
+}